@@ -0,0 +1,138 @@
+//! Throughput of the rate limiter under concurrent traffic spread over many keys.
+//!
+//! Run with `cargo bench --bench rate_limiter`. `global_mutex` is the limiter this crate
+//! used to ship, a single `tokio::sync::Mutex` around one map, kept here as the baseline.
+
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rust_http_template::rate_limiter::{FixedWindow, InMemoryRateLimiter, RateLimiter};
+use tokio::sync::Mutex;
+
+const KEYS: usize = 10_000;
+const CHECKS_PER_ITER: u64 = 1_000;
+const WINDOW: Duration = Duration::from_secs(60);
+// High enough that every check updates state rather than short-circuiting on a rejection
+const LIMIT: u64 = u64::MAX;
+
+struct GlobalMutexRateLimiter {
+    requests: Mutex<HashMap<String, (u64, Instant)>>,
+    max_requests: u64,
+    window: Duration,
+}
+
+impl GlobalMutexRateLimiter {
+    async fn check(&self, key: &str) -> (u64, bool) {
+        let mut requests = self.requests.lock().await;
+        let now = Instant::now();
+
+        let (current_count, current_time) = requests
+            .get(key)
+            .map(|(count, time)| (*count, *time))
+            .unwrap_or((0, now));
+
+        if now.duration_since(current_time) > self.window {
+            requests.insert(key.to_string(), (1, now));
+            (1, true)
+        } else if current_count >= self.max_requests {
+            (current_count, false)
+        } else {
+            let new_count = current_count + 1;
+            requests.insert(key.to_string(), (new_count, current_time));
+            (new_count, true)
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Limiter {
+    GlobalMutex(Arc<GlobalMutexRateLimiter>),
+    Sharded(Arc<dyn RateLimiter>),
+}
+
+impl Limiter {
+    async fn check(&self, key: &str) -> bool {
+        match self {
+            Limiter::GlobalMutex(limiter) => limiter.check(key).await.1,
+            Limiter::Sharded(limiter) => limiter.check(key).await.allowed,
+        }
+    }
+}
+
+fn limiters() -> Vec<(&'static str, Limiter)> {
+    let sharded = |shards: Option<usize>| {
+        let limiter = InMemoryRateLimiter::new(FixedWindow::new(LIMIT, WINDOW));
+        let limiter = match shards.and_then(NonZeroUsize::new) {
+            Some(shards) => limiter.with_shards(shards),
+            None => limiter,
+        };
+        Limiter::Sharded(Arc::new(limiter))
+    };
+
+    vec![
+        (
+            "global_mutex",
+            Limiter::GlobalMutex(Arc::new(GlobalMutexRateLimiter {
+                requests: Mutex::new(HashMap::new()),
+                max_requests: LIMIT,
+                window: WINDOW,
+            })),
+        ),
+        ("sharded_1", sharded(Some(1))),
+        ("sharded_default", sharded(None)),
+    ]
+}
+
+/// Splits `iters * CHECKS_PER_ITER` checks over `tasks` concurrent tasks, each walking the
+/// keys from a different offset, and returns the wall time they took.
+async fn run(limiter: Limiter, keys: Arc<Vec<String>>, tasks: usize, iters: u64) -> Duration {
+    let checks_per_task = (iters * CHECKS_PER_ITER).div_ceil(tasks as u64);
+    let start = Instant::now();
+    let handles: Vec<_> = (0..tasks)
+        .map(|task| {
+            let limiter = limiter.clone();
+            let keys = keys.clone();
+            tokio::spawn(async move {
+                let offset = task * KEYS / tasks;
+                for i in 0..checks_per_task as usize {
+                    let allowed = limiter.check(&keys[(offset + i) % KEYS]).await;
+                    std::hint::black_box(allowed);
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    start.elapsed()
+}
+
+fn concurrent_keys(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let keys: Arc<Vec<String>> = Arc::new(
+        (0..KEYS)
+            .map(|i| format!("10.{}.{}.{}", i >> 16, (i >> 8) & 0xff, i & 0xff))
+            .collect(),
+    );
+
+    let mut group = c.benchmark_group("rate_limiter_check");
+    group.throughput(Throughput::Elements(CHECKS_PER_ITER));
+    for tasks in [1, 8, 64] {
+        for (name, limiter) in limiters() {
+            group.bench_with_input(BenchmarkId::new(name, tasks), &tasks, |b, &tasks| {
+                b.to_async(&runtime)
+                    .iter_custom(|iters| run(limiter.clone(), keys.clone(), tasks, iters));
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_keys);
+criterion_main!(benches);
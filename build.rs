@@ -1,4 +1,11 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::compile_protos("proto/helloworld.proto")?;
+    tonic_build::configure().compile_protos(
+        &[
+            "proto/google/rpc/status.proto",
+            "proto/google/rpc/error_details.proto",
+        ],
+        &["proto"],
+    )?;
     Ok(())
 }
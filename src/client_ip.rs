@@ -0,0 +1,149 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use axum::extract::{ConnectInfo, FromRequestParts, OptionalFromRequestParts, Request, State};
+use axum::http::{request::Parts, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use ipnet::IpNet;
+
+/// The address of the client that sent the request, after stripping trusted proxy hops.
+/// Resolved once per request by the `resolve_client_ip` middleware, so handlers, gRPC
+/// services (through `request.extensions()`) and the rate limiter all agree on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientIp(pub IpAddr);
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<ClientIp>().copied().ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Client IP is not available",
+        ))
+    }
+}
+
+impl<S: Send + Sync> OptionalFromRequestParts<S> for ClientIp {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        Ok(parts.extensions.get::<ClientIp>().copied())
+    }
+}
+
+/// Proxies whose forwarding headers we believe, as CIDRs or bare addresses.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    pub fn parse(proxies: &[String]) -> Result<Self, String> {
+        proxies
+            .iter()
+            .map(|p| {
+                p.parse::<IpNet>()
+                    .or_else(|_| p.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("invalid proxy address or CIDR: {}", p))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(ip))
+    }
+
+    /// Resolves the client address for a connection from `peer`.
+    ///
+    /// Forwarding headers are only read when `peer` is trusted. `Forwarded` wins over
+    /// `X-Forwarded-For`, which wins over `X-Real-IP`. The hop list is walked from the right
+    /// and the first untrusted hop is the client, since everything left of it could have
+    /// been written by the client itself.
+    pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.contains(&peer) {
+            return peer;
+        }
+
+        let hops = forwarded_hops(headers)
+            .or_else(|| x_forwarded_for_hops(headers))
+            .or_else(|| {
+                headers
+                    .get("x-real-ip")
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| vec![parse_hop(v)])
+            })
+            .unwrap_or_default();
+
+        let mut client = peer;
+        for hop in hops.into_iter().rev() {
+            match hop {
+                Some(ip) if self.contains(&ip) => client = ip,
+                Some(ip) => return ip,
+                // An obfuscated or garbled hop, the last proxy we trust is as far as we can see
+                None => return client,
+            }
+        }
+        client
+    }
+}
+
+/// This middleware resolves the `ClientIp` of every request and stores it in the extensions.
+/// Requests that didn't come through `ServerBuilder::serve` have no `ConnectInfo` and
+/// therefore no `ClientIp`.
+pub(crate) async fn resolve_client_ip(
+    State(trusted): State<Arc<TrustedProxies>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if let Some(ConnectInfo(peer)) = req.extensions().get::<ConnectInfo<SocketAddr>>() {
+        let ip = trusted.resolve(peer.ip(), req.headers());
+        req.extensions_mut().insert(ClientIp(ip));
+    }
+    next.run(req).await
+}
+
+/// `for=` values of every `Forwarded` header (RFC 7239), in order.
+fn forwarded_hops(headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let mut hops = Vec::new();
+    for value in headers.get_all("forwarded") {
+        let value = value.to_str().ok()?;
+        for element in value.split(',') {
+            let hop = element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_hop(value))
+            });
+            // Elements without a `for=` say nothing about the client
+            if let Some(hop) = hop {
+                hops.push(hop);
+            }
+        }
+    }
+    (!hops.is_empty()).then_some(hops)
+}
+
+fn x_forwarded_for_hops(headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let mut hops = Vec::new();
+    for value in headers.get_all("x-forwarded-for") {
+        let value = value.to_str().ok()?;
+        hops.extend(value.split(',').map(parse_hop));
+    }
+    (!hops.is_empty()).then_some(hops)
+}
+
+/// Parses a single hop, which may be quoted and may carry a port (`"[2001:db8::1]:4711"`).
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    if let Ok(ip) = hop.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = hop.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    // Bracketed IPv6 without a port
+    hop.strip_prefix('[')?.strip_suffix(']')?.parse().ok()
+}
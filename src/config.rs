@@ -0,0 +1,506 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::Deserialize;
+use validator::{Validate, ValidationError};
+
+use crate::client_ip::TrustedProxies;
+
+/// Environment variables with this prefix override values from the config file.
+/// Nested keys are separated by a double underscore, e.g. `APP_RATE_LIMIT__MAX_REQUESTS=100`.
+pub const ENV_PREFIX: &str = "APP";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to load config: {0}")]
+    Load(#[from] ::config::ConfigError),
+    #[error("invalid config: {0}")]
+    Invalid(#[from] validator::ValidationErrors),
+}
+
+/// Everything `start` needs to know about a deployment.
+/// Missing keys fall back to the defaults below, which match what used to be hard-coded.
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(default)]
+pub struct ServerConfig {
+    #[validate(custom(function = "validate_socket_addr"))]
+    pub http_addr: String,
+
+    /// Max size of a single decoded gRPC message, in bytes.
+    #[validate(range(min = 1))]
+    pub grpc_max_decoding_message_size: usize,
+
+    /// Number of requests the `BufferLayer` will queue before applying backpressure.
+    #[validate(range(min = 1))]
+    pub buffer_capacity: usize,
+
+    /// Max request body size, in bytes.
+    #[validate(range(min = 1))]
+    pub body_limit: usize,
+
+    #[validate(range(min = 1))]
+    pub request_timeout_secs: u64,
+
+    /// Proxies (CIDRs or addresses) allowed to set `Forwarded`, `X-Forwarded-For` and
+    /// `X-Real-IP`. From the environment this is a comma separated list.
+    #[validate(custom(function = "validate_trusted_proxies"))]
+    pub trusted_proxies: Vec<String>,
+
+    #[validate(nested)]
+    pub rate_limit: RateLimitConfig,
+
+    #[validate(nested)]
+    pub shutdown: ShutdownConfig,
+
+    /// Terminate TLS on the listener when set, otherwise serve plain HTTP.
+    #[validate(nested)]
+    pub tls: Option<TlsConfig>,
+
+    #[validate(nested)]
+    pub json_rpc: JsonRpcConfig,
+
+    /// Send clients the full error chain of internal errors instead of just an error ID.
+    /// For local development only, it can leak anything the errors mention.
+    pub dev_mode: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            http_addr: "0.0.0.0:8080".to_string(),
+            grpc_max_decoding_message_size: 1024 * 1024, // 1MB
+            buffer_capacity: 1024,
+            body_limit: 1_000_000,
+            request_timeout_secs: 60,
+            trusted_proxies: Vec::new(),
+            rate_limit: RateLimitConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            tls: None,
+            json_rpc: JsonRpcConfig::default(),
+            dev_mode: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitAlgorithm {
+    #[default]
+    FixedWindow,
+    SlidingWindowLog,
+    SlidingWindowCounter,
+    TokenBucket,
+    Gcra,
+}
+
+/// The top-level `algorithm`, `max_requests` and `window_secs` make up the `default` policy,
+/// which every request is charged against unless its route or gRPC method is attached to
+/// another policy. JSON-RPC calls are additionally charged against their method's policy.
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(default)]
+#[validate(schema(function = "validate_policy_refs"))]
+pub struct RateLimitConfig {
+    pub algorithm: RateLimitAlgorithm,
+
+    #[validate(range(min = 1))]
+    pub max_requests: u64,
+
+    #[validate(range(min = 1))]
+    pub window_secs: u64,
+
+    /// Most keys to keep state for, the least recently used ones are evicted beyond that.
+    #[validate(range(min = 1))]
+    pub max_keys: usize,
+
+    /// How often to drop state for keys whose limits have fully reset, 0 disables sweeping.
+    pub sweep_interval_secs: u64,
+
+    /// Named policies, each with a budget of its own that is shared by everything attached
+    /// to it.
+    #[validate(nested)]
+    pub policies: HashMap<String, RateLimitPolicy>,
+
+    /// Policies for axum routes, keyed by exact request path (`/sse`).
+    #[validate(nested)]
+    pub routes: HashMap<String, PolicyRef>,
+
+    /// Policies for gRPC methods, keyed by path (`/helloworld.Greeter/SayHello`).
+    #[validate(nested)]
+    pub grpc_methods: HashMap<String, PolicyRef>,
+
+    /// Policies for JSON-RPC methods, keyed by method name (`greeting_rpc`).
+    #[validate(nested)]
+    pub rpc_methods: HashMap<String, PolicyRef>,
+
+    /// Keep counters in a Redis-compatible store shared by every replica, instead of in each
+    /// process. Shared counters are always fixed windows, `algorithm` only applies to the
+    /// local limits used while the store is unreachable.
+    #[validate(nested)]
+    pub store: Option<RateLimitStoreConfig>,
+
+    /// Quotas per API key or tenant, applied on top of the per-IP limits.
+    #[validate(nested)]
+    pub quota: Option<QuotaConfig>,
+}
+
+/// Quotas are counted per calendar day and month in UTC. They are kept in the shared store when
+/// one is configured, so every replica sees the same usage.
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// Header carrying the API key or tenant ID that quotas are keyed by.
+    #[validate(length(min = 1))]
+    pub header: String,
+
+    /// Key by the common name of the mTLS client certificate when the header is missing.
+    pub client_cert: bool,
+
+    /// Short-term limit per key on top of the quotas, e.g. 10 requests per second.
+    #[validate(nested)]
+    pub burst: Option<RateLimitPolicy>,
+
+    #[validate(nested)]
+    #[serde(flatten)]
+    pub limits: QuotaLimits,
+
+    /// Quotas for specific keys, replacing the ones above.
+    #[validate(nested)]
+    pub keys: HashMap<String, QuotaLimits>,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            header: "x-api-key".to_string(),
+            client_cert: false,
+            burst: None,
+            limits: QuotaLimits::default(),
+            keys: HashMap::new(),
+        }
+    }
+}
+
+/// Requests allowed per period, no limit when unset.
+#[derive(Debug, Clone, Default, Deserialize, Validate)]
+pub struct QuotaLimits {
+    #[validate(range(min = 1))]
+    pub daily: Option<u64>,
+
+    #[validate(range(min = 1))]
+    pub monthly: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(default)]
+pub struct RateLimitStoreConfig {
+    /// `redis://[user:password@]host[:port][/db]`, or `rediss://` for TLS, with the server
+    /// certificate verified against the system's root certificates.
+    #[validate(custom(function = "validate_redis_url"))]
+    pub url: String,
+
+    /// Prepended to every key, so several deployments can share one store.
+    pub key_prefix: String,
+
+    /// How long to wait for the store before falling back to local limits, in milliseconds.
+    #[validate(range(min = 1))]
+    pub timeout_ms: u64,
+
+    /// How long to stick with local limits after the store failed before trying it again.
+    pub retry_interval_secs: u64,
+}
+
+impl Default for RateLimitStoreConfig {
+    fn default() -> Self {
+        Self {
+            url: "redis://127.0.0.1:6379".to_string(),
+            key_prefix: "rate_limit".to_string(),
+            timeout_ms: 100,
+            retry_interval_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(default)]
+pub struct RateLimitPolicy {
+    pub algorithm: RateLimitAlgorithm,
+
+    #[validate(range(min = 1))]
+    pub max_requests: u64,
+
+    #[validate(range(min = 1))]
+    pub window_secs: u64,
+}
+
+impl Default for RateLimitPolicy {
+    fn default() -> Self {
+        Self {
+            algorithm: RateLimitAlgorithm::default(),
+            max_requests: 10,
+            window_secs: 60,
+        }
+    }
+}
+
+/// Attaches a route or method to a policy.
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(default)]
+pub struct PolicyRef {
+    pub policy: String,
+
+    /// How much of the policy's budget one call uses up, so expensive calls can count as
+    /// several requests.
+    #[validate(range(min = 1))]
+    pub cost: u64,
+}
+
+impl Default for PolicyRef {
+    fn default() -> Self {
+        Self {
+            policy: DEFAULT_POLICY.to_string(),
+            cost: 1,
+        }
+    }
+}
+
+/// The name of the policy made up of the top-level `rate_limit` settings.
+pub const DEFAULT_POLICY: &str = "default";
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: RateLimitAlgorithm::default(),
+            max_requests: 10, // 10 requests per minute
+            window_secs: 60,
+            max_keys: 100_000,
+            sweep_interval_secs: 60,
+            policies: HashMap::new(),
+            routes: HashMap::new(),
+            grpc_methods: HashMap::new(),
+            rpc_methods: HashMap::new(),
+            store: None,
+            quota: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    /// How long to keep accepting connections after flipping `/readyz` to 503,
+    /// so load balancers have time to notice before we stop listening.
+    pub drain_delay_secs: u64,
+
+    /// How long in-flight requests and streams get to finish before they are cut off.
+    #[validate(range(min = 1))]
+    pub deadline_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_delay_secs: 0,
+            deadline_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(default)]
+pub struct JsonRpcConfig {
+    /// Max number of requests in a batch, larger batches are rejected as a whole.
+    #[validate(range(min = 1))]
+    pub max_batch_size: usize,
+
+    /// How many requests of a batch run at the same time.
+    #[validate(range(min = 1))]
+    pub batch_concurrency: usize,
+
+    /// How many messages of a WebSocket connection are handled at the same time, further
+    /// ones wait for a slot.
+    #[validate(range(min = 1))]
+    pub ws_max_in_flight: usize,
+
+    /// How many events a WebSocket client can fall behind by before it misses the oldest.
+    #[validate(range(min = 1))]
+    pub ws_event_buffer: usize,
+
+    /// How many topics a WebSocket connection can be subscribed to at the same time.
+    #[validate(range(min = 1))]
+    pub ws_max_subscriptions: usize,
+
+    /// How many messages of a stdio or Unix socket connection are handled at the same time,
+    /// further ones wait for a slot.
+    #[validate(range(min = 1))]
+    pub local_max_in_flight: usize,
+}
+
+impl Default for JsonRpcConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 100,
+            batch_concurrency: 8,
+            ws_max_in_flight: 32,
+            ws_event_buffer: 64,
+            ws_max_subscriptions: 32,
+            local_max_in_flight: 32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain, leaf first.
+    #[validate(length(min = 1))]
+    pub cert_path: String,
+
+    /// PEM file with the private key (PKCS#8, PKCS#1 or SEC1).
+    #[validate(length(min = 1))]
+    pub key_path: String,
+
+    /// PEM bundle of CAs used to verify client certificates. Enables mTLS when set.
+    pub client_ca_path: Option<String>,
+
+    /// Let clients without a certificate connect even when `client_ca_path` is set.
+    /// Certificates that are presented must still verify.
+    #[serde(default)]
+    pub client_auth_optional: bool,
+
+    /// How often to check the certificate files for changes, 0 disables hot reloading.
+    #[serde(default = "default_tls_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+}
+
+fn default_tls_reload_interval_secs() -> u64 {
+    10
+}
+
+impl ServerConfig {
+    /// Loads the config from an optional TOML/YAML file (format picked by extension),
+    /// applies `APP_*` environment overrides, then validates the result.
+    pub fn load(path: Option<&str>) -> Result<Self, ConfigError> {
+        let mut builder = ::config::Config::builder();
+        if let Some(path) = path {
+            builder = builder.add_source(::config::File::with_name(path));
+        }
+
+        let config: Self = builder
+            .add_source(
+                ::config::Environment::with_prefix(ENV_PREFIX)
+                    .prefix_separator("_")
+                    .separator("__")
+                    .try_parsing(true)
+                    .list_separator(",")
+                    .with_list_parse_key("trusted_proxies"),
+            )
+            .build()?
+            .try_deserialize()?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+}
+
+impl RateLimitConfig {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs)
+    }
+
+    /// The policy made up of the top-level settings.
+    pub fn default_policy(&self) -> RateLimitPolicy {
+        RateLimitPolicy {
+            algorithm: self.algorithm,
+            max_requests: self.max_requests,
+            window_secs: self.window_secs,
+        }
+    }
+
+    /// Looks up a policy by name, including `default`.
+    pub fn policy(&self, name: &str) -> Option<RateLimitPolicy> {
+        match name {
+            DEFAULT_POLICY => Some(self.default_policy()),
+            name => self.policies.get(name).cloned(),
+        }
+    }
+
+    pub fn sweep_interval(&self) -> Option<Duration> {
+        (self.sweep_interval_secs > 0).then(|| Duration::from_secs(self.sweep_interval_secs))
+    }
+}
+
+impl RateLimitStoreConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn retry_interval(&self) -> Duration {
+        Duration::from_secs(self.retry_interval_secs)
+    }
+}
+
+impl RateLimitPolicy {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs)
+    }
+}
+
+impl ShutdownConfig {
+    pub fn drain_delay(&self) -> Duration {
+        Duration::from_secs(self.drain_delay_secs)
+    }
+
+    pub fn deadline(&self) -> Duration {
+        Duration::from_secs(self.deadline_secs)
+    }
+}
+
+impl TlsConfig {
+    pub fn reload_interval(&self) -> Option<Duration> {
+        (self.reload_interval_secs > 0).then(|| Duration::from_secs(self.reload_interval_secs))
+    }
+}
+
+fn validate_socket_addr(addr: &str) -> Result<(), ValidationError> {
+    addr.parse::<SocketAddr>()
+        .map(|_| ())
+        .map_err(|_| ValidationError::new("socket_addr").with_message("must be host:port".into()))
+}
+
+fn validate_redis_url(url: &str) -> Result<(), ValidationError> {
+    redis::Client::open(url)
+        .map(|_| ())
+        .map_err(|e| ValidationError::new("redis_url").with_message(e.to_string().into()))
+}
+
+fn validate_trusted_proxies(proxies: &[String]) -> Result<(), ValidationError> {
+    TrustedProxies::parse(proxies)
+        .map(|_| ())
+        .map_err(|e| ValidationError::new("trusted_proxies").with_message(e.into()))
+}
+
+/// Every attachment must name an existing policy and cost no more than that policy's limit,
+/// otherwise its calls could never be allowed.
+fn validate_policy_refs(config: &RateLimitConfig) -> Result<(), ValidationError> {
+    let refs = config
+        .routes
+        .iter()
+        .chain(&config.grpc_methods)
+        .chain(&config.rpc_methods);
+    for (target, policy_ref) in refs {
+        let message = match config.policy(&policy_ref.policy) {
+            None => format!("{} uses unknown policy {}", target, policy_ref.policy),
+            Some(policy) if policy_ref.cost > policy.max_requests => format!(
+                "{} costs {} but policy {} only allows {}",
+                target, policy_ref.cost, policy_ref.policy, policy.max_requests
+            ),
+            Some(_) => continue,
+        };
+        return Err(ValidationError::new("policy_ref").with_message(message.into()));
+    }
+    Ok(())
+}
@@ -0,0 +1,202 @@
+//! Stable, machine-readable error codes. Every `AppError` carries one, and each code says in
+//! one place how it is reported over HTTP, gRPC and JSON-RPC. Clients match on the code rather
+//! than the human-readable text, which is free to change.
+//!
+//! Domain errors are declared with `error_codes!` and registered with
+//! `ServerBuilder::error_codes` so they show up in the catalog served at `/errors`.
+
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use tonic::Code;
+
+use crate::json_rpc;
+
+/// Where the catalog of every registered code is served.
+pub const ERRORS_PATH: &str = "/errors";
+
+#[doc(hidden)]
+pub mod __private {
+    pub use axum::http::StatusCode;
+    pub use tonic::Code;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorCode {
+    /// The code clients match on, in `SCREAMING_SNAKE_CASE`. Never change it once published.
+    pub code: &'static str,
+    pub status: StatusCode,
+    pub grpc_code: Code,
+    pub json_rpc_code: i64,
+    pub description: &'static str,
+}
+
+impl ErrorCode {
+    /// The built-in code closest to `status`, for errors that only come with a status.
+    pub fn for_status(status: StatusCode) -> ErrorCode {
+        match status {
+            StatusCode::BAD_REQUEST => ErrorCodes::BAD_REQUEST,
+            StatusCode::UNAUTHORIZED => ErrorCodes::UNAUTHORIZED,
+            StatusCode::FORBIDDEN => ErrorCodes::FORBIDDEN,
+            StatusCode::NOT_FOUND => ErrorCodes::NOT_FOUND,
+            StatusCode::TOO_MANY_REQUESTS => ErrorCodes::RATE_LIMITED,
+            StatusCode::REQUEST_TIMEOUT | StatusCode::GATEWAY_TIMEOUT => ErrorCodes::TIMEOUT,
+            status if status.is_client_error() => ErrorCodes::BAD_REQUEST,
+            _ => ErrorCodes::INTERNAL,
+        }
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut entry = serializer.serialize_struct("ErrorCode", 5)?;
+        entry.serialize_field("code", self.code)?;
+        entry.serialize_field("status", &self.status.as_u16())?;
+        entry.serialize_field("grpc_code", grpc_code_name(self.grpc_code))?;
+        entry.serialize_field("json_rpc_code", &self.json_rpc_code)?;
+        entry.serialize_field("description", self.description)?;
+        entry.end()
+    }
+}
+
+/// A set of error codes, usually declared with `error_codes!`.
+pub trait ErrorCatalog {
+    const CODES: &'static [ErrorCode];
+}
+
+/// Declares a set of error codes as associated consts of a unit struct, along with its
+/// `ErrorCatalog` impl.
+///
+/// ```
+/// use rust_http_template::{error_codes, AppError};
+///
+/// error_codes! {
+///     /// Errors of the orders domain.
+///     pub struct OrderErrors {
+///         ORDER_NOT_FOUND => (NOT_FOUND, NotFound, -32004, "The order does not exist"),
+///         ORDER_CLOSED => (CONFLICT, FailedPrecondition, -32009, "The order can't be changed"),
+///     }
+/// }
+///
+/// let error = AppError::coded(OrderErrors::ORDER_CLOSED, anyhow::anyhow!("order 7 shipped"));
+/// assert_eq!(error.code().code, "ORDER_CLOSED");
+/// ```
+///
+/// Each entry is the HTTP status (a `StatusCode` const), the `tonic::Code` variant, the
+/// JSON-RPC error code and a description for the catalog.
+#[macro_export]
+macro_rules! error_codes {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$code_meta:meta])*
+                $code:ident => ($status:ident, $grpc:ident, $json_rpc:expr, $description:expr $(,)?)
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy)]
+        $vis struct $name;
+
+        impl $name {
+            $(
+                $(#[$code_meta])*
+                pub const $code: $crate::error_code::ErrorCode = $crate::error_code::ErrorCode {
+                    code: stringify!($code),
+                    status: $crate::error_code::__private::StatusCode::$status,
+                    grpc_code: $crate::error_code::__private::Code::$grpc,
+                    json_rpc_code: $json_rpc,
+                    description: $description,
+                };
+            )*
+        }
+
+        impl $crate::error_code::ErrorCatalog for $name {
+            const CODES: &'static [$crate::error_code::ErrorCode] = &[$($name::$code),*];
+        }
+    };
+}
+
+error_codes! {
+    /// The codes the server itself reports.
+    pub struct ErrorCodes {
+        INTERNAL => (
+            INTERNAL_SERVER_ERROR, Internal, json_rpc::INTERNAL_ERROR,
+            "Something went wrong on the server"
+        ),
+        PARSE_ERROR => (
+            BAD_REQUEST, InvalidArgument, json_rpc::PARSE_ERROR,
+            "The request body is not valid JSON"
+        ),
+        BAD_REQUEST => (
+            BAD_REQUEST, InvalidArgument, json_rpc::INVALID_REQUEST,
+            "The request is malformed"
+        ),
+        VALIDATION_FAILED => (
+            BAD_REQUEST, InvalidArgument, json_rpc::INVALID_PARAMS,
+            "Some fields of the request are invalid, see `errors` for which"
+        ),
+        UNAUTHORIZED => (
+            UNAUTHORIZED, Unauthenticated, -32001,
+            "The request lacks valid credentials"
+        ),
+        FORBIDDEN => (
+            FORBIDDEN, PermissionDenied, -32003,
+            "The credentials don't allow this request"
+        ),
+        NOT_FOUND => (
+            NOT_FOUND, NotFound, -32004,
+            "The requested resource does not exist"
+        ),
+        METHOD_NOT_FOUND => (
+            NOT_FOUND, Unimplemented, json_rpc::METHOD_NOT_FOUND,
+            "The JSON-RPC method does not exist"
+        ),
+        RATE_LIMITED => (
+            TOO_MANY_REQUESTS, ResourceExhausted, -32029,
+            "A rate limit or quota was exceeded, retry after the time in `Retry-After`"
+        ),
+        TIMEOUT => (
+            GATEWAY_TIMEOUT, DeadlineExceeded, -32008,
+            "The request took longer than the server allows"
+        ),
+        LIMIT_REACHED => (
+            CONFLICT, ResourceExhausted, -32030,
+            "A connection holds as much as it may, release something before asking for more"
+        ),
+    }
+}
+
+/// Serves every registered code, the built-in ones first.
+pub(crate) async fn catalog(State(codes): State<Arc<Vec<ErrorCode>>>) -> impl IntoResponse {
+    Json(serde_json::json!({ "errors": *codes }))
+}
+
+/// The canonical name of a gRPC code, as used in the gRPC spec and `google.rpc.Code`.
+fn grpc_code_name(code: Code) -> &'static str {
+    match code {
+        Code::Ok => "OK",
+        Code::Cancelled => "CANCELLED",
+        Code::Unknown => "UNKNOWN",
+        Code::InvalidArgument => "INVALID_ARGUMENT",
+        Code::DeadlineExceeded => "DEADLINE_EXCEEDED",
+        Code::NotFound => "NOT_FOUND",
+        Code::AlreadyExists => "ALREADY_EXISTS",
+        Code::PermissionDenied => "PERMISSION_DENIED",
+        Code::ResourceExhausted => "RESOURCE_EXHAUSTED",
+        Code::FailedPrecondition => "FAILED_PRECONDITION",
+        Code::Aborted => "ABORTED",
+        Code::OutOfRange => "OUT_OF_RANGE",
+        Code::Unimplemented => "UNIMPLEMENTED",
+        Code::Internal => "INTERNAL",
+        Code::Unavailable => "UNAVAILABLE",
+        Code::DataLoss => "DATA_LOSS",
+        Code::Unauthenticated => "UNAUTHENTICATED",
+    }
+}
@@ -1 +1,2 @@
 pub mod hello_world;
+pub mod status;
@@ -0,0 +1,164 @@
+//! gRPC statuses with `google.rpc` error details, for `AppError`s returned by services and for
+//! calls that are turned away before they reach one, e.g. by the rate limiter or the request
+//! timeout. tonic clients only understand a status in the `grpc-status` header or trailer, an
+//! HTTP error code shows up as a transport error.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::{Body, Bytes};
+use axum::http::{header, HeaderMap};
+use axum::response::Response;
+use prost::Message;
+use prost_types::Any;
+use tonic::{Code, Status};
+
+use crate::error_code::{ErrorCatalog, ErrorCode, ErrorCodes};
+use crate::AppError;
+
+/// The `google.rpc` error model, sent in the `grpc-status-details-bin` header.
+pub mod rpc {
+    tonic::include_proto!("google.rpc");
+}
+
+/// The `ErrorInfo.domain` of every error this server reports.
+pub const ERROR_DOMAIN: &str = env!("CARGO_PKG_NAME");
+
+const TYPE_URL_PREFIX: &str = "type.googleapis.com/google.rpc.";
+
+/// A `google.rpc` message that can be sent as a status detail.
+pub trait Detail: Message + Default + Sized {
+    const NAME: &'static str;
+
+    fn to_any(&self) -> Any {
+        Any {
+            type_url: format!("{}{}", TYPE_URL_PREFIX, Self::NAME),
+            value: self.encode_to_vec(),
+        }
+    }
+}
+
+impl Detail for rpc::RetryInfo {
+    const NAME: &'static str = "RetryInfo";
+}
+
+impl Detail for rpc::ErrorInfo {
+    const NAME: &'static str = "ErrorInfo";
+}
+
+impl Detail for rpc::BadRequest {
+    const NAME: &'static str = "BadRequest";
+}
+
+/// Whether a request is a gRPC call, going by its `content-type`.
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/grpc"))
+}
+
+/// A status carrying `details` as a `google.rpc.Status`.
+pub fn with_details(code: Code, message: impl Into<String>, details: Vec<Any>) -> Status {
+    let message = message.into();
+    let status = rpc::Status {
+        code: code as i32,
+        message: message.clone(),
+        details,
+    };
+    Status::with_details(code, message, Bytes::from(status.encode_to_vec()))
+}
+
+/// A `RetryInfo` detail telling the client to wait `retry_delay` before retrying.
+pub fn retry_info(retry_delay: Duration) -> Any {
+    rpc::RetryInfo {
+        retry_delay: retry_delay.try_into().ok(),
+    }
+    .to_any()
+}
+
+/// An `ErrorInfo` detail naming `code` from the catalog.
+pub fn error_info(code: &ErrorCode, metadata: HashMap<String, String>) -> Any {
+    rpc::ErrorInfo {
+        reason: code.code.to_string(),
+        domain: ERROR_DOMAIN.to_string(),
+        metadata,
+    }
+    .to_any()
+}
+
+/// Reads a detail of type `D` back out of a status, for clients and tests.
+pub fn detail<D: Detail>(status: &Status) -> Option<D> {
+    let type_url = format!("{}{}", TYPE_URL_PREFIX, D::NAME);
+    rpc::Status::decode(status.details())
+        .ok()?
+        .details
+        .iter()
+        .find(|any| any.type_url == type_url)
+        .and_then(|any| D::decode(any.value.as_slice()).ok())
+}
+
+/// Reads the `RetryInfo` detail back out of a status.
+pub fn retry_delay(status: &Status) -> Option<Duration> {
+    detail::<rpc::RetryInfo>(status)?
+        .retry_delay?
+        .try_into()
+        .ok()
+}
+
+/// A trailers-only response carrying `status`.
+pub fn into_response(status: Status) -> Response {
+    status.into_http::<Body>()
+}
+
+/// Reports `error` as a status with the catalog code as an `ErrorInfo` detail, plus a
+/// `BadRequest` detail listing the invalid fields of validation errors. For internal errors the
+/// message is redacted and `trace_http` logs the error, found through the status' source.
+impl From<AppError> for Status {
+    fn from(error: AppError) -> Self {
+        let report = error.report();
+        let mut metadata = HashMap::new();
+        if let Some(internal) = &report.internal {
+            metadata.insert("error_id".to_string(), internal.id.clone());
+        }
+        let mut details = vec![error_info(&report.code, metadata)];
+        if !report.errors.is_empty() {
+            let field_violations = report
+                .errors
+                .iter()
+                .map(|error| rpc::bad_request::FieldViolation {
+                    field: error.pointer.clone(),
+                    description: error.detail.clone(),
+                    reason: error.code.to_uppercase(),
+                })
+                .collect();
+            details.push(rpc::BadRequest { field_violations }.to_any());
+        }
+
+        let mut status = with_details(report.code.grpc_code, report.message, details);
+        if let Some(internal) = report.internal {
+            status.set_source(Arc::new(internal));
+        }
+        status
+    }
+}
+
+impl AppError {
+    /// Turns a status from a gRPC call into an error for this server's own clients, keeping
+    /// its code when it was reported by a server like this one.
+    pub fn from_status(status: &Status) -> Self {
+        let reason = detail::<rpc::ErrorInfo>(status).map(|info| info.reason);
+        let code = ErrorCodes::CODES
+            .iter()
+            .find(|code| Some(code.code) == reason.as_deref())
+            .or_else(|| {
+                ErrorCodes::CODES
+                    .iter()
+                    .find(|code| code.grpc_code == status.code())
+            })
+            .copied()
+            .unwrap_or(ErrorCodes::INTERNAL);
+        AppError::coded(code, anyhow::anyhow!(status.message().to_string()))
+    }
+}
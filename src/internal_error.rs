@@ -0,0 +1,113 @@
+//! Internal errors are never shown to clients outside of dev mode. They get a generic message
+//! and an error ID instead, and `trace_http` logs the full error chain under that ID in the
+//! request's span, so a report quoting the ID leads straight to the cause.
+//!
+//! Backtraces are captured as usual for `anyhow`, when `RUST_BACKTRACE` or
+//! `RUST_LIB_BACKTRACE` is set.
+
+use std::backtrace::BacktraceStatus;
+use std::sync::Arc;
+
+use axum::response::Response;
+use tracing::error;
+
+use crate::problem::Problem;
+
+/// Whether clients get the full error chain of internal errors, see `ServerConfig::dev_mode`.
+/// `ServerBuilder` adds it to every request for `trace_http` to read.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ErrorVerbosity {
+    pub verbose: bool,
+}
+
+/// An internal error, kept in the response extensions for `trace_http` to log.
+#[derive(Debug, Clone)]
+pub struct InternalError {
+    pub id: String,
+    pub error: Arc<anyhow::Error>,
+}
+
+impl InternalError {
+    pub fn new(error: anyhow::Error) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            error: Arc::new(error),
+        }
+    }
+
+    /// What clients get told outside of dev mode.
+    pub fn redacted_message(&self) -> String {
+        format!("Internal server error, reference {}", self.id)
+    }
+
+    /// The whole chain on one line, e.g. `loading user: connection refused`.
+    fn verbose_message(&self) -> String {
+        format!("{:#}", self.error)
+    }
+}
+
+impl std::fmt::Display for InternalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.redacted_message())
+    }
+}
+
+// Lets it ride along as the source of a `tonic::Status`
+impl std::error::Error for InternalError {}
+
+/// Logs the internal errors `response` carries, if any, and in dev mode puts its full chain
+/// in the response.
+pub(crate) fn report(response: &mut Response, verbosity: ErrorVerbosity) {
+    // JSON-RPC batches can carry one per request
+    let mut errors = response
+        .extensions()
+        .get::<Vec<InternalError>>()
+        .cloned()
+        .unwrap_or_default();
+    errors.extend(
+        response
+            .extensions()
+            .get::<InternalError>()
+            .cloned()
+            .or_else(|| {
+                // gRPC services return it as the source of their status
+                let status = response.extensions().get::<tonic::Status>()?;
+                std::error::Error::source(status)?
+                    .downcast_ref::<InternalError>()
+                    .cloned()
+            }),
+    );
+    for internal in &errors {
+        log(internal);
+    }
+    let [internal] = errors.as_slice() else {
+        return;
+    };
+    if !verbosity.verbose {
+        return;
+    }
+
+    if let Some(problem) = response.extensions_mut().get_mut::<Problem>() {
+        problem.detail = Some(internal.verbose_message());
+    } else if let Some(status) = tonic::Status::from_header_map(response.headers()) {
+        // A gRPC call, the status is all the client sees
+        let _ = tonic::Status::new(status.code(), internal.verbose_message())
+            .add_header(response.headers_mut());
+    }
+}
+
+/// Logs the full chain of `internal` under its error ID, for transports that don't go through
+/// `trace_http`.
+pub(crate) fn log(internal: &InternalError) {
+    let backtrace = internal.error.backtrace();
+    if backtrace.status() == BacktraceStatus::Captured {
+        error!(
+            error_id = %internal.id,
+            error = %internal.verbose_message(),
+            %backtrace,
+            "Internal error"
+        );
+    } else {
+        error!(error_id = %internal.id, error = %internal.verbose_message(), "Internal error");
+    }
+}
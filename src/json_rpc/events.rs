@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::broadcast;
+use tracing::error;
+
+use super::{JsonRpcRequest, JSONRPC_VERSION};
+
+/// Pushes events to JSON-RPC WebSocket clients: to those subscribed to a topic with
+/// `subscribe`, or as a notification to all of them. Cloning is cheap, every clone publishes
+/// to the same clients. Get one with `ServerBuilder::events`.
+#[derive(Clone)]
+pub struct Events {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    topics: Mutex<HashMap<String, broadcast::Sender<Value>>>,
+    notifications: broadcast::Sender<Arc<JsonRpcRequest>>,
+    capacity: usize,
+}
+
+impl Events {
+    /// Clients that fall more than `capacity` events behind miss the oldest ones.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                topics: Mutex::new(HashMap::new()),
+                notifications: broadcast::Sender::new(capacity),
+                capacity,
+            }),
+        }
+    }
+
+    /// Sends `event` to every client subscribed to `topic`, returning how many there were.
+    pub fn publish(&self, topic: &str, event: impl Serialize) -> usize {
+        let topics = self.inner.topics.lock().unwrap();
+        let Some(sender) = topics.get(topic) else {
+            return 0;
+        };
+        match serde_json::to_value(event) {
+            Ok(event) => sender.send(event).unwrap_or(0),
+            Err(e) => {
+                error!("Failed to serialize event for topic {}: {}", topic, e);
+                0
+            }
+        }
+    }
+
+    /// Sends every connected client a notification calling `method`, returning how many
+    /// there were.
+    pub fn notify(&self, method: &str, params: impl Serialize) -> usize {
+        let params = match serde_json::to_value(params) {
+            Ok(params) => params,
+            Err(e) => {
+                error!("Failed to serialize notification {}: {}", method, e);
+                return 0;
+            }
+        };
+        let notification = JsonRpcRequest {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            method: method.to_string(),
+            params,
+            id: None,
+        };
+        self.inner
+            .notifications
+            .send(Arc::new(notification))
+            .unwrap_or(0)
+    }
+
+    /// The topics clients can subscribe to, sorted.
+    pub fn topics(&self) -> Vec<String> {
+        let mut topics: Vec<_> = self.inner.topics.lock().unwrap().keys().cloned().collect();
+        topics.sort();
+        topics
+    }
+
+    /// Lets clients subscribe to `topic`. Adding it again is a no-op.
+    pub(crate) fn add_topic(&self, topic: &str) {
+        self.inner
+            .topics
+            .lock()
+            .unwrap()
+            .entry(topic.to_string())
+            .or_insert_with(|| broadcast::Sender::new(self.inner.capacity));
+    }
+
+    /// `None` if there is no such topic.
+    pub(crate) fn subscribe(&self, topic: &str) -> Option<broadcast::Receiver<Value>> {
+        Some(self.inner.topics.lock().unwrap().get(topic)?.subscribe())
+    }
+
+    pub(crate) fn notifications(&self) -> broadcast::Receiver<Arc<JsonRpcRequest>> {
+        self.inner.notifications.subscribe()
+    }
+}
@@ -1,7 +1,32 @@
+use axum::body::Bytes;
+use axum::extract::{FromRequest, Request};
 use axum::response::{IntoResponse, Response};
-use serde::{Deserialize, Serialize};
+use axum::Json;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::error_code::ErrorCodes;
+use crate::internal_error::InternalError;
+use crate::problem::FieldError;
+use crate::AppError;
+
+mod events;
+pub mod openrpc;
+mod router;
+pub use events::*;
+pub use router::*;
+
+/// Where `ServerBuilder` serves the methods registered with `rpc_method`.
+pub const JSON_RPC_PATH: &str = "/json_rpc";
+
+/// Where the methods served from `path` are also reachable over WebSocket.
+pub fn ws_path(path: &str) -> String {
+    format!("{}/ws", path.trim_end_matches('/'))
+}
+
+/// The only protocol version there is, sent in every response.
+pub const JSONRPC_VERSION: &str = "2.0";
+
 // JSON-RPC Error Codes
 pub const PARSE_ERROR: i64 = -32700;
 pub const INVALID_REQUEST: i64 = -32600;
@@ -9,44 +34,154 @@ pub const METHOD_NOT_FOUND: i64 = -32601;
 pub const INVALID_PARAMS: i64 = -32602;
 pub const INTERNAL_ERROR: i64 = -32603;
 
+/// A request id, echoed back exactly as the client sent it. `Null` is what responses carry when
+/// the request's id couldn't be read.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Id {
+    Number(serde_json::Number),
+    String(String),
+    #[default]
+    Null,
+}
+
+impl From<i64> for Id {
+    fn from(id: i64) -> Self {
+        Id::Number(id.into())
+    }
+}
+
+impl From<String> for Id {
+    fn from(id: String) -> Self {
+        Id::String(id)
+    }
+}
+
+impl From<&str> for Id {
+    fn from(id: &str) -> Self {
+        Id::String(id.to_string())
+    }
+}
+
 // Basic JSON-RPC structures (same as before)
 #[derive(Debug, Deserialize, Serialize)]
 pub struct JsonRpcRequest {
     pub jsonrpc: String,
     pub method: String,
+    /// May be left out, handlers then get `null`.
+    #[serde(default)]
     pub params: Value,
-    pub id: Option<i64>,
+    /// `None` for notifications, which get no response. An explicit `null` is `Some(Id::Null)`.
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub id: Option<Id>,
+}
+
+impl JsonRpcRequest {
+    pub fn is_notification(&self) -> bool {
+        self.id.is_none()
+    }
+}
+
+/// The body of a JSON-RPC call: one request or a batch of them, not yet checked to be valid
+/// requests so that each can be answered with its own error.
+#[derive(Debug)]
+pub enum JsonRpcPayload {
+    Single(Value),
+    Batch(Vec<Value>),
+}
+
+impl JsonRpcPayload {
+    /// Reads a message, or answers it with a `PARSE_ERROR` response if it isn't JSON.
+    pub fn from_slice(message: &[u8]) -> Result<Self, Value> {
+        match serde_json::from_slice(message) {
+            Ok(Value::Array(batch)) => Ok(Self::Batch(batch)),
+            Ok(request) => Ok(Self::Single(request)),
+            Err(e) => {
+                let error = JsonRpcError::from(AppError::coded(ErrorCodes::PARSE_ERROR, e));
+                Err(error.into_response(Id::Null).into())
+            }
+        }
+    }
+}
+
+/// Bodies that aren't JSON are answered with a `PARSE_ERROR` response rather than a plain 4xx,
+/// whatever their content type says.
+impl<S: Send + Sync> FromRequest<S> for JsonRpcPayload {
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        Self::from_slice(&body).map_err(|response| Json(response).into_response())
+    }
+}
+
+/// Deserializes the params of a call, reporting params that don't fit as `VALIDATION_FAILED`
+/// with a pointer to the offending one, e.g. `/params/items/0`.
+pub fn params<P: DeserializeOwned>(params: Value) -> Result<P, AppError> {
+    serde_path_to_error::deserialize(params).map_err(|e| {
+        let mut pointer = "/params".to_string();
+        for segment in e.path().iter() {
+            match segment {
+                serde_path_to_error::Segment::Seq { index } => {
+                    pointer.push_str(&format!("/{}", index))
+                }
+                serde_path_to_error::Segment::Map { key } => {
+                    pointer.push_str(&format!("/{}", key.replace('~', "~0").replace('/', "~1")))
+                }
+                serde_path_to_error::Segment::Enum { .. }
+                | serde_path_to_error::Segment::Unknown => {}
+            }
+        }
+        AppError::InvalidFields(vec![FieldError {
+            pointer,
+            code: "parse".to_string(),
+            detail: e.into_inner().to_string(),
+            params: Default::default(),
+        }])
+    })
+}
+
+/// Tells a missing `id` apart from `"id": null`, which `Option` alone maps to `None` either way.
+fn present<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Id>, D::Error> {
+    Id::deserialize(deserializer).map(Some)
 }
 
 #[derive(Debug, Serialize)]
 pub struct JsonRpcResponseSuccess<T: Serialize> {
     pub jsonrpc: String,
     pub result: T,
-    pub id: Option<i64>,
+    pub id: Id,
 }
 
 impl<T: Serialize> JsonRpcResponseSuccess<T> {
-    pub fn with_id(mut self, id: Option<i64>) -> Self {
+    pub fn with_id(mut self, id: Id) -> Self {
         self.id = id;
         self
     }
 }
 
 #[derive(Debug, Serialize)]
-pub struct JsonRpcResponseError<T: Serialize> {
+pub struct JsonRpcResponseError {
     pub jsonrpc: String,
-    pub id: Option<i64>,
-    pub data: Option<T>,
-    pub code: i64,
+    pub error: JsonRpcError,
+    pub id: Id,
 }
 
-impl<T: Serialize> JsonRpcResponseError<T> {
-    pub fn with_id(mut self, id: Option<i64>) -> Self {
+impl JsonRpcResponseError {
+    pub fn with_id(mut self, id: Id) -> Self {
         self.id = id;
         self
     }
 }
 
+/// A response as sent on the wire: `result` for calls that succeeded, `error` for those
+/// that failed.
 #[derive(Debug, Serialize)]
 pub struct JsonRpcResponse {
     pub jsonrpc: String,
@@ -55,17 +190,13 @@ pub struct JsonRpcResponse {
     pub result: Option<Value>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub id: Option<i64>,
+    pub error: Option<JsonRpcError>,
 
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub data: Option<Value>,
-
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub code: Option<i64>,
+    pub id: Id,
 }
 
 impl JsonRpcResponse {
-    pub fn with_id(mut self, id: Option<i64>) -> Self {
+    pub fn with_id(mut self, id: Id) -> Self {
         self.id = id;
         self
     }
@@ -91,16 +222,48 @@ impl IntoResponse for JsonRpcResponse {
     }
 }
 
-#[derive(Debug, Serialize)]
-pub struct InternalError {
+/// The `error` object of a response, which is how every `AppError` is reported. `data` carries
+/// the catalog code, the invalid fields of validation errors and the ID of internal errors.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonRpcError {
+    pub code: i64,
     pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    /// Only logged, by `trace_http`, once the handler puts it in the response extensions.
+    #[serde(skip)]
+    pub internal: Option<InternalError>,
 }
 
-impl From<anyhow::Error> for InternalError {
-    fn from(e: anyhow::Error) -> Self {
+impl From<AppError> for JsonRpcError {
+    fn from(error: AppError) -> Self {
+        let report = error.report();
+        let mut data = serde_json::Map::new();
+        data.insert("code".to_string(), report.code.code.into());
+        if !report.errors.is_empty() {
+            data.insert("errors".to_string(), serde_json::json!(report.errors));
+        }
+        if let Some(internal) = &report.internal {
+            data.insert("error_id".to_string(), internal.id.clone().into());
+        }
         Self {
-            message: e.to_string(),
+            code: report.code.json_rpc_code,
+            message: report.message,
+            data: Some(data.into()),
+            internal: report.internal,
+        }
+    }
+}
+
+impl JsonRpcError {
+    /// The error as a response to the request with `id`.
+    pub fn into_response(self, id: Id) -> JsonRpcResponse {
+        JsonRpcResponseError {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            error: self,
+            id,
         }
+        .into()
     }
 }
 
@@ -108,9 +271,9 @@ impl From<anyhow::Error> for InternalError {
 impl<T: Serialize> From<T> for JsonRpcResponseSuccess<T> {
     fn from(e: T) -> Self {
         Self {
-            jsonrpc: "2.0".to_string(),
+            jsonrpc: JSONRPC_VERSION.to_string(),
             result: e,
-            id: None,
+            id: Id::Null,
         }
     }
 }
@@ -119,11 +282,10 @@ impl<T: Serialize> From<T> for JsonRpcResponseSuccess<T> {
 impl<T: Serialize> From<JsonRpcResponseSuccess<T>> for JsonRpcResponse {
     fn from(e: JsonRpcResponseSuccess<T>) -> Self {
         Self {
-            jsonrpc: "2.0".to_string(),
+            jsonrpc: JSONRPC_VERSION.to_string(),
             result: Some(serde_json::to_value(e.result).unwrap()),
+            error: None,
             id: e.id,
-            data: None,
-            code: None,
         }
     }
 }
@@ -136,34 +298,32 @@ impl<T: Serialize> From<JsonRpcResponseSuccess<T>> for Value {
     }
 }
 
-// Convert a value to a JsonRpcResponseError
-impl<T: Serialize> From<T> for JsonRpcResponseError<T> {
-    fn from(e: T) -> Self {
+// Convert a JsonRpcError to a JsonRpcResponseError
+impl From<JsonRpcError> for JsonRpcResponseError {
+    fn from(e: JsonRpcError) -> Self {
         Self {
-            jsonrpc: "2.0".to_string(),
-            id: None,
-            data: Some(e),
-            code: INTERNAL_ERROR,
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            error: e,
+            id: Id::Null,
         }
     }
 }
 
-// Convert a JsonRpcResponseError to a JsonRpcResponse via JsonRpcResponse
-impl<T: Serialize> From<JsonRpcResponseError<T>> for JsonRpcResponse {
-    fn from(e: JsonRpcResponseError<T>) -> Self {
+// Convert a JsonRpcResponseError to a JsonRpcResponse
+impl From<JsonRpcResponseError> for JsonRpcResponse {
+    fn from(e: JsonRpcResponseError) -> Self {
         Self {
-            jsonrpc: "2.0".to_string(),
+            jsonrpc: JSONRPC_VERSION.to_string(),
             result: None,
+            error: Some(e.error),
             id: e.id,
-            data: e.data.map(|d| serde_json::to_value(d).unwrap()),
-            code: Some(e.code),
         }
     }
 }
 
 // Convert a JsonRpcResponseError to a Value via JsonRpcResponse
-impl<T: Serialize> From<JsonRpcResponseError<T>> for Value {
-    fn from(e: JsonRpcResponseError<T>) -> Self {
+impl From<JsonRpcResponseError> for Value {
+    fn from(e: JsonRpcResponseError) -> Self {
         let response: JsonRpcResponse = e.into();
         serde_json::to_value(response).unwrap()
     }
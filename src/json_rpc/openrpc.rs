@@ -0,0 +1,174 @@
+//! OpenRPC documents describing the methods of a `JsonRpcRouter`, served by the `rpc.discover`
+//! method and by `GET` on the path the router is mounted on. Methods are described from the
+//! `JsonSchema` of their params and result, see `JsonRpcRouter::method_with_schema`.
+
+use std::future::Future;
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::{Extension, Json};
+use schemars::generate::{SchemaGenerator, SchemaSettings};
+use schemars::{JsonSchema, Schema};
+use serde_json::{json, Value};
+
+use super::{JsonRpcRouter, Params};
+
+/// The method every router answers with its OpenRPC document.
+pub const DISCOVER_METHOD: &str = "rpc.discover";
+
+/// The version of the OpenRPC specification documents follow.
+pub const OPENRPC_VERSION: &str = "1.3.2";
+
+/// The schemas of a method's params, if it takes any, and of its result.
+#[derive(Debug, Clone)]
+pub struct MethodSchema {
+    pub params: Option<Schema>,
+    pub result: Schema,
+}
+
+/// Generates the schemas of a method, with the types they refer to added to the generator's
+/// definitions.
+pub type Describe = fn(&mut SchemaGenerator) -> MethodSchema;
+
+/// A handler whose params and result implement `JsonSchema`, so that it can be described in the
+/// OpenRPC document. Implemented for the same functions as `RpcHandler`.
+pub trait RpcSchema<T> {
+    fn describe(generator: &mut SchemaGenerator) -> MethodSchema;
+}
+
+impl<F, Fut, O, E> RpcSchema<()> for F
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<O, E>>,
+    O: JsonSchema,
+{
+    fn describe(generator: &mut SchemaGenerator) -> MethodSchema {
+        MethodSchema {
+            params: None,
+            result: generator.subschema_for::<O>(),
+        }
+    }
+}
+
+impl<F, Fut, O, E, P> RpcSchema<(Params<P>,)> for F
+where
+    F: Fn(Params<P>) -> Fut,
+    Fut: Future<Output = Result<O, E>>,
+    O: JsonSchema,
+    P: JsonSchema,
+{
+    fn describe(generator: &mut SchemaGenerator) -> MethodSchema {
+        MethodSchema {
+            // Inline, so that the fields of structs can be listed as params of their own
+            params: Some(P::json_schema(generator)),
+            result: generator.subschema_for::<O>(),
+        }
+    }
+}
+
+impl<F, Fut, O, E, S> RpcSchema<(State<S>,)> for F
+where
+    F: Fn(State<S>) -> Fut,
+    Fut: Future<Output = Result<O, E>>,
+    O: JsonSchema,
+{
+    fn describe(generator: &mut SchemaGenerator) -> MethodSchema {
+        MethodSchema {
+            params: None,
+            result: generator.subschema_for::<O>(),
+        }
+    }
+}
+
+impl<F, Fut, O, E, S, P> RpcSchema<(State<S>, Params<P>)> for F
+where
+    F: Fn(State<S>, Params<P>) -> Fut,
+    Fut: Future<Output = Result<O, E>>,
+    O: JsonSchema,
+    P: JsonSchema,
+{
+    fn describe(generator: &mut SchemaGenerator) -> MethodSchema {
+        MethodSchema {
+            params: Some(P::json_schema(generator)),
+            result: generator.subschema_for::<O>(),
+        }
+    }
+}
+
+/// Serves the OpenRPC document of the router mounted on the requested path.
+pub async fn document(Extension(router): Extension<Arc<JsonRpcRouter>>) -> Json<Value> {
+    Json(router.openrpc())
+}
+
+/// The OpenRPC document for `methods`, which are described by their `Describe` if they have
+/// one. Methods without one are still listed, taking and returning anything.
+pub(super) fn openrpc<'a>(methods: impl Iterator<Item = (&'a str, Option<Describe>)>) -> Value {
+    let mut generator = SchemaSettings::draft2020_12()
+        .with(|settings| {
+            settings.definitions_path = "/components/schemas".into();
+            settings.meta_schema = None;
+        })
+        .into_generator();
+
+    let methods: Vec<Value> = methods
+        .map(|(name, describe)| {
+            let Some(describe) = describe else {
+                return json!({
+                    "name": name,
+                    "params": [],
+                    "result": { "name": "result", "schema": {} },
+                });
+            };
+            let schema = describe(&mut generator);
+            let mut method = json!({
+                "name": name,
+                "params": [],
+                "result": { "name": "result", "schema": schema.result },
+            });
+            if let Some(params) = schema.params {
+                method["params"] = content_descriptors(params).into();
+                // `Params` takes an object or an array in the order of the fields
+                method["paramStructure"] = "either".into();
+            }
+            method
+        })
+        .collect();
+
+    json!({
+        "openrpc": OPENRPC_VERSION,
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "methods": methods,
+        "components": { "schemas": generator.take_definitions(true) },
+    })
+}
+
+/// The params of a method as OpenRPC content descriptors: one per field for structs, or a
+/// single `params` one for anything else.
+fn content_descriptors(params: Schema) -> Vec<Value> {
+    let properties = params.get("properties").and_then(Value::as_object);
+    let Some(properties) = properties else {
+        return vec![json!({ "name": "params", "required": true, "schema": params })];
+    };
+    let required = params
+        .get("required")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+    properties
+        .iter()
+        .map(|(name, schema)| {
+            let mut descriptor = json!({
+                "name": name,
+                "required": required.contains(&Value::from(name.as_str())),
+                "schema": schema,
+            });
+            if let Some(description) = schema.get("description") {
+                descriptor["description"] = description.clone();
+            }
+            descriptor
+        })
+        .collect()
+}
@@ -0,0 +1,291 @@
+//! Typed JSON-RPC methods, registered by name on a `JsonRpcRouter`. Handlers take their
+//! params and state as arguments, much like axum handlers take extractors:
+//!
+//! ```
+//! use axum::extract::State;
+//! use rust_http_template::json_rpc::{JsonRpcRouter, Params};
+//! use rust_http_template::AppError;
+//! use serde::Deserialize;
+//! use validator::Validate;
+//!
+//! #[derive(Deserialize, Validate)]
+//! struct AddParams {
+//!     #[validate(range(max = 1000))]
+//!     a: i64,
+//!     b: i64,
+//! }
+//!
+//! async fn add(State(offset): State<i64>, Params(p): Params<AddParams>) -> Result<i64, AppError> {
+//!     Ok(p.a + p.b + offset)
+//! }
+//!
+//! async fn ping() -> Result<&'static str, AppError> {
+//!     Ok("pong")
+//! }
+//!
+//! let math = JsonRpcRouter::new().method("add", add).with_state(1);
+//! let router: JsonRpcRouter = JsonRpcRouter::new().method("ping", ping).merge(math);
+//! ```
+//!
+//! Mount it with `ServerBuilder::json_rpc_router`. Methods registered with
+//! `method_with_schema` instead of `method` are described in the router's OpenRPC document,
+//! see `openrpc`.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+
+use axum::extract::State;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use validator::Validate;
+
+use super::openrpc::{self, Describe, RpcSchema, DISCOVER_METHOD};
+use super::params;
+use crate::{problem, AppError};
+
+/// The params of a call, given by name (an object) or by position (an array, in field order).
+/// They are checked against their `validator` rules before the handler is called, invalid ones
+/// are reported as `VALIDATION_FAILED` like params that don't deserialize.
+#[derive(Debug, Clone)]
+pub struct Params<P>(pub P);
+
+impl<P: DeserializeOwned + Validate> Params<P> {
+    fn from_value(value: Value) -> Result<Self, AppError> {
+        // Left out params are no params, structs with optional fields can still take them
+        let value = match value {
+            Value::Null => Value::Object(Default::default()),
+            value => value,
+        };
+        let params: P = params(value)?;
+        params.validate().map_err(|e| {
+            let errors = problem::field_errors(&e)
+                .into_iter()
+                .map(|mut error| {
+                    error.pointer = format!("/params{}", error.pointer);
+                    error
+                })
+                .collect();
+            AppError::InvalidFields(errors)
+        })?;
+        Ok(Self(params))
+    }
+}
+
+/// An async function that can serve a method of a `JsonRpcRouter<S>`: one taking no arguments,
+/// `State<S>`, `Params<P>`, or both in that order, and returning `Result<O, E>` where `O`
+/// serializes to the call's result and `E` converts into an `AppError`.
+pub trait RpcHandler<T, S>: Clone + Send + Sync + Sized + 'static {
+    fn call(self, state: S, params: Value) -> BoxFuture<'static, Result<Value, AppError>>;
+}
+
+fn into_result<O: Serialize, E: Into<AppError>>(result: Result<O, E>) -> Result<Value, AppError> {
+    let output = result.map_err(Into::into)?;
+    serde_json::to_value(output).map_err(|e| AppError::Anyhow(e.into()))
+}
+
+impl<F, Fut, O, E, S> RpcHandler<(), S> for F
+where
+    F: Fn() -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<O, E>> + Send + 'static,
+    O: Serialize,
+    E: Into<AppError>,
+{
+    fn call(self, _state: S, _params: Value) -> BoxFuture<'static, Result<Value, AppError>> {
+        Box::pin(async move { into_result(self().await) })
+    }
+}
+
+impl<F, Fut, O, E, S, P> RpcHandler<(Params<P>,), S> for F
+where
+    F: Fn(Params<P>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<O, E>> + Send + 'static,
+    O: Serialize,
+    E: Into<AppError>,
+    P: DeserializeOwned + Validate + Send + 'static,
+{
+    fn call(self, _state: S, params: Value) -> BoxFuture<'static, Result<Value, AppError>> {
+        Box::pin(async move { into_result(self(Params::from_value(params)?).await) })
+    }
+}
+
+impl<F, Fut, O, E, S> RpcHandler<(State<S>,), S> for F
+where
+    F: Fn(State<S>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<O, E>> + Send + 'static,
+    O: Serialize,
+    E: Into<AppError>,
+    S: Send + 'static,
+{
+    fn call(self, state: S, _params: Value) -> BoxFuture<'static, Result<Value, AppError>> {
+        Box::pin(async move { into_result(self(State(state)).await) })
+    }
+}
+
+impl<F, Fut, O, E, S, P> RpcHandler<(State<S>, Params<P>), S> for F
+where
+    F: Fn(State<S>, Params<P>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<O, E>> + Send + 'static,
+    O: Serialize,
+    E: Into<AppError>,
+    S: Send + 'static,
+    P: DeserializeOwned + Validate + Send + 'static,
+{
+    fn call(self, state: S, params: Value) -> BoxFuture<'static, Result<Value, AppError>> {
+        Box::pin(async move { into_result(self(State(state), Params::from_value(params)?).await) })
+    }
+}
+
+type Method<S> = Arc<dyn Fn(S, Value) -> BoxFuture<'static, Result<Value, AppError>> + Send + Sync>;
+
+/// JSON-RPC methods keyed by name. Routers for different parts of an app can be built in
+/// their own modules, each with its own state, and merged into one to be served.
+pub struct JsonRpcRouter<S = ()> {
+    methods: BTreeMap<String, Method<S>>,
+    /// How to describe the methods registered with `method_with_schema`.
+    schemas: BTreeMap<String, Describe>,
+}
+
+impl<S> Clone for JsonRpcRouter<S> {
+    fn clone(&self) -> Self {
+        Self {
+            methods: self.methods.clone(),
+            schemas: self.schemas.clone(),
+        }
+    }
+}
+
+impl<S> Default for JsonRpcRouter<S> {
+    fn default() -> Self {
+        Self {
+            methods: BTreeMap::new(),
+            schemas: BTreeMap::new(),
+        }
+    }
+}
+
+impl<S: Clone + Send + Sync + 'static> JsonRpcRouter<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves the method `name` with `handler`.
+    ///
+    /// # Panics
+    ///
+    /// If a method called `name` is already registered.
+    pub fn method<H, T>(mut self, name: &str, handler: H) -> Self
+    where
+        H: RpcHandler<T, S>,
+    {
+        self.insert(
+            name.to_string(),
+            Arc::new(move |state, params| handler.clone().call(state, params)),
+        );
+        self
+    }
+
+    /// Serves the method `name` with `handler`, like `method`, and describes it in the OpenRPC
+    /// document with the schemas of its params and result.
+    ///
+    /// # Panics
+    ///
+    /// If a method called `name` is already registered.
+    pub fn method_with_schema<H, T>(mut self, name: &str, handler: H) -> Self
+    where
+        H: RpcHandler<T, S> + RpcSchema<T>,
+    {
+        self = self.method(name, handler);
+        self.schemas.insert(name.to_string(), H::describe);
+        self
+    }
+
+    /// Adds every method of `other`.
+    ///
+    /// # Panics
+    ///
+    /// If both routers have a method of the same name.
+    pub fn merge(mut self, other: JsonRpcRouter<S>) -> Self {
+        for (name, method) in other.methods {
+            self.insert(name, method);
+        }
+        self.schemas.extend(other.schemas);
+        self
+    }
+
+    /// Provides the state the handlers take, turning this into a router that needs a state of
+    /// type `S2`, usually `()`, which its handlers ignore.
+    pub fn with_state<S2>(self, state: S) -> JsonRpcRouter<S2> {
+        let methods = self
+            .methods
+            .into_iter()
+            .map(|(name, method)| {
+                let state = state.clone();
+                let method: Method<S2> =
+                    Arc::new(move |_: S2, params| method(state.clone(), params));
+                (name, method)
+            })
+            .collect();
+        JsonRpcRouter {
+            methods,
+            schemas: self.schemas,
+        }
+    }
+
+    /// The names of every method, sorted.
+    pub fn method_names(&self) -> impl Iterator<Item = &str> {
+        self.methods.keys().map(String::as_str)
+    }
+
+    /// The names of the methods registered without a schema, which the OpenRPC document can
+    /// only say take and return anything.
+    pub fn undocumented_methods(&self) -> impl Iterator<Item = &str> {
+        self.method_names()
+            .filter(|name| !self.schemas.contains_key(*name))
+    }
+
+    /// The OpenRPC document describing every method, also served by `rpc.discover`.
+    pub fn openrpc(&self) -> Value {
+        openrpc::openrpc(
+            self.method_names()
+                .map(|name| (name, self.schemas.get(name).copied())),
+        )
+    }
+
+    fn insert(&mut self, name: String, method: Method<S>) {
+        if name == DISCOVER_METHOD {
+            panic!("JSON-RPC method {} is reserved", name);
+        }
+        if self.methods.insert(name.clone(), method).is_some() {
+            panic!("JSON-RPC method {} is registered twice", name);
+        }
+    }
+}
+
+impl JsonRpcRouter {
+    /// Calls the method `name`, or returns `None` if there isn't one. A method that panics
+    /// fails with an internal error, the other requests of its batch still get their
+    /// responses. `rpc.discover` returns the OpenRPC document.
+    pub async fn call(&self, name: &str, params: Value) -> Option<Result<Value, AppError>> {
+        if name == DISCOVER_METHOD {
+            return Some(Ok(self.openrpc()));
+        }
+        let method = self.methods.get(name)?;
+        let result = AssertUnwindSafe(method((), params))
+            .catch_unwind()
+            .await
+            .unwrap_or_else(|panic| {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                Err(anyhow::anyhow!("JSON-RPC method {} panicked: {}", name, message).into())
+            });
+        Some(result)
+    }
+}
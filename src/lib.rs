@@ -1,95 +1,58 @@
-use axum::routing::post;
-use axum::{middleware, routing::get};
-use grpc::hello_world::helloworld::greeter_server;
 use hyper::body::Body;
 
 use std::sync::Arc;
 use std::time::Duration;
-use tonic::service::Routes;
 
 use axum::{
-    error_handling::HandleErrorLayer,
-    extract::DefaultBodyLimit,
     http::StatusCode,
     response::{IntoResponse, Response},
 };
-use tower::{buffer::BufferLayer, BoxError, ServiceBuilder};
-use tracing::{error, info, info_span, Instrument};
+use tracing::{info_span, Instrument};
 
+pub mod client_ip;
+pub mod config;
+pub mod error_code;
 pub mod grpc;
+pub mod internal_error;
 pub mod json_rpc;
-mod rate_limiter;
+pub mod local;
+pub mod problem;
+pub mod rate_limiter;
 mod routes;
-use rate_limiter::{ip_rate_limiter, RateLimiter};
+pub mod server;
+pub mod shutdown;
+pub mod tls;
+use config::{JsonRpcConfig, ServerConfig};
+use error_code::{ErrorCode, ErrorCodes};
+use internal_error::{ErrorVerbosity, InternalError};
+use json_rpc::Events;
+use problem::{FieldError, Problem};
+use rate_limiter::{Quotas, RateLimitPolicies};
+pub use server::ServerBuilder;
+use shutdown::Shutdown;
 
 #[derive(Clone)]
 struct AppState {
-    rate_limiter: Arc<RateLimiter>,
+    rate_limits: Arc<RateLimitPolicies>,
+    quotas: Option<Arc<Quotas>>,
+    json_rpc: JsonRpcConfig,
+    /// Largest request body, and WebSocket message, accepted.
+    body_limit: usize,
+    events: Events,
+    shutdown: Shutdown,
+    shutdown_deadline: Duration,
 }
 
-pub async fn start(http_addr: &str) {
-    let greeter_service = grpc::hello_world::MyGreeter::default();
-    let grpc_svc = Routes::new(
-        greeter_server::GreeterServer::new(greeter_service)
-            .max_decoding_message_size(1024 * 1024 * 1), // 1MB
-    )
-    .prepare()
-    .into_axum_router()
-    .with_state(());
-
-    let state = AppState {
-        rate_limiter: Arc::new(RateLimiter::new(10, Duration::from_secs(60))), // 10 requests per minute
-    };
-
-    let app = axum::Router::new()
-        .route("/echo/json", post(routes::echo_json))
-        .route("/echo/json_extractor", post(routes::echo_json_extractor))
-        .route(
-            "/echo/nested_function_tracing",
-            post(routes::echo_nested_function_tracing),
-        )
-        .route("/sse", get(routes::sse_res))
-        .route("/stream", get(routes::stream_res))
-        .route("/stream_handler", post(routes::stream_handler))
-        .route("/json_rpc", post(routes::json_rpc))
-        // .route(
-        //     "/{key}",
-        //     get(routes::get::get_key).post(routes::post::write_key),
-        // )
-        .merge(grpc_svc)
-        .layer(
-            ServiceBuilder::new()
-                .layer(middleware::from_fn(trace_http))
-                // https://github.com/tokio-rs/axum/discussions/987
-                .layer(HandleErrorLayer::new(|err: BoxError| async move {
-                    // turns layer errors into HTTP errors
-                    error!("Unhandled error: {}", err);
-                    (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("Unhandled error: {}", err),
-                    )
-                }))
-                .layer(BufferLayer::new(1024))
-                .layer(DefaultBodyLimit::max(1_000_000))
-                // also see https://docs.rs/tower-http/0.6.1/tower_http/request_id/index.html#example
-                .layer(tower::timeout::TimeoutLayer::new(Duration::from_secs(60))) // 30 second timeout
-                .layer(middleware::from_fn_with_state(
-                    state.clone(),
-                    ip_rate_limiter,
-                )),
-        )
-        .with_state(state);
-
-    info!("Starting on {}", http_addr);
-    let axum_listener = tokio::net::TcpListener::bind(http_addr).await.unwrap();
-
-    let axum_server = axum::serve(axum_listener, app).with_graceful_shutdown(async {
-        tokio::signal::ctrl_c()
-            .await
-            .expect("Failed to install Ctrl+C handler");
-        info!("Received shutdown signal");
-    });
-    axum_server.await.unwrap();
+/// Serves the template's default services on `config.http_addr` until SIGINT or SIGTERM.
+pub async fn start(config: ServerConfig) {
+    let axum_listener = tokio::net::TcpListener::bind(&config.http_addr)
+        .await
+        .unwrap();
+    ServerBuilder::new(config)
+        .with_default_services()
+        .serve(axum_listener)
+        .await
+        .unwrap();
 }
 
 // Make our own error that wraps `anyhow::Error`.
@@ -98,26 +61,42 @@ pub enum AppError {
     CustomCode(anyhow::Error, axum::http::StatusCode),
     RateLimited(anyhow::Error),
     ValidationError(validator::ValidationErrors),
+    /// Fields that failed before `validator` got to see them, e.g. because they didn't
+    /// deserialize.
+    InvalidFields(Vec<FieldError>),
+    /// An error with a code from the catalog, which also decides its status.
+    Coded(ErrorCode, anyhow::Error),
+}
+
+/// What every transport reports for an `AppError`: HTTP as a problem, gRPC as a status with
+/// `google.rpc` details and JSON-RPC as an error object, see `AppError::report`.
+#[derive(Debug, Clone)]
+pub struct ErrorReport {
+    pub code: ErrorCode,
+    pub status: StatusCode,
+    /// Safe to show clients, internal errors only get a reference to their error ID.
+    pub message: String,
+    pub errors: Vec<FieldError>,
+    /// Set for internal errors, to be logged by `trace_http`.
+    pub internal: Option<InternalError>,
 }
 
-// Tell axum how to convert `AppError` into a response.
+// Tell axum how to convert `AppError` into a response. Every variant renders as an RFC 9457
+// problem, see `problem`. Internal errors only show an error ID, see `internal_error`.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        match self {
-            AppError::Anyhow(e) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Something went wrong: {}", e),
-            ),
-            AppError::CustomCode(e, code) => (code, format!("{}", e)),
-            AppError::RateLimited(e) => (
-                StatusCode::TOO_MANY_REQUESTS,
-                format!("Rate limit exceeded: {}", e),
-            ),
-            AppError::ValidationError(e) => {
-                (StatusCode::BAD_REQUEST, format!("Validation error: {}", e))
-            }
-        }
-        .into_response()
+        let report = self.report();
+        let mut problem = Problem::new(report.status)
+            .with_code(&report.code)
+            .with_detail(report.message)
+            .with_errors(report.errors);
+        let Some(internal) = report.internal else {
+            return problem.into_response();
+        };
+        problem = problem.with_error_id(&internal.id);
+        let mut response = problem.into_response();
+        response.extensions_mut().insert(internal);
+        response
     }
 }
 
@@ -133,6 +112,70 @@ where
 }
 
 impl AppError {
+    pub fn coded(code: ErrorCode, e: impl Into<anyhow::Error>) -> Self {
+        Self::Coded(code, e.into())
+    }
+
+    /// Describes this error the same way for every transport. Any error with a 5xx status is
+    /// internal, whatever its variant, so its message is swapped for a reference to its ID.
+    pub fn report(self) -> ErrorReport {
+        let code = self.code();
+        let (status, e) = match self {
+            AppError::Anyhow(e) => (StatusCode::INTERNAL_SERVER_ERROR, e),
+            AppError::CustomCode(e, status) => (status, e),
+            AppError::RateLimited(e) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                anyhow::anyhow!("Rate limit exceeded: {}", e),
+            ),
+            AppError::ValidationError(e) => {
+                return ErrorReport {
+                    code,
+                    status: StatusCode::BAD_REQUEST,
+                    message: "Request validation failed".to_string(),
+                    errors: problem::field_errors(&e),
+                    internal: None,
+                };
+            }
+            AppError::InvalidFields(errors) => {
+                return ErrorReport {
+                    code,
+                    status: StatusCode::BAD_REQUEST,
+                    message: "Request validation failed".to_string(),
+                    errors,
+                    internal: None,
+                };
+            }
+            AppError::Coded(code, e) => (code.status, e),
+        };
+
+        let (message, internal) = if status.is_server_error() {
+            let internal = InternalError::new(e);
+            (internal.redacted_message(), Some(internal))
+        } else {
+            (e.to_string(), None)
+        };
+        ErrorReport {
+            code,
+            status,
+            message,
+            errors: Vec::new(),
+            internal,
+        }
+    }
+
+    /// The catalog entry clients see for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            AppError::Anyhow(_) => ErrorCodes::INTERNAL,
+            AppError::CustomCode(_, status) => ErrorCode::for_status(*status),
+            AppError::RateLimited(_) => ErrorCodes::RATE_LIMITED,
+            AppError::ValidationError(_) | AppError::InvalidFields(_) => {
+                ErrorCodes::VALIDATION_FAILED
+            }
+            AppError::Coded(code, _) => *code,
+        }
+    }
+
     pub fn rate_limited() -> Self {
         Self::CustomCode(
             anyhow::anyhow!("Rate limit exceeded"),
@@ -163,6 +206,18 @@ pub async fn trace_http(req: axum::extract::Request, next: axum::middleware::Nex
         .map(|s| s.to_string())
         .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
 
+    let verbosity = req
+        .extensions()
+        .get::<ErrorVerbosity>()
+        .copied()
+        .unwrap_or_default();
+
+    let client_ip = req
+        .extensions()
+        .get::<client_ip::ClientIp>()
+        .map(|ip| ip.0.to_string())
+        .unwrap_or("?".to_string());
+
     // Create a tracing span that includes our custom fields.
     let span = info_span!(
         target: "req_handler",
@@ -170,20 +225,23 @@ pub async fn trace_http(req: axum::extract::Request, next: axum::middleware::Nex
         req_id = %req_id, // % means display formatting
         method = %method,
         path = %path,
+        client_ip = %client_ip,
         req_size = %req_body_size,
         res_size = tracing::field::Empty,
     );
 
     // wrap it so we can record the response body size in the span
     let response = async {
-        let response = next.run(req).await;
+        let mut response = next.run(req).await;
+        internal_error::report(&mut response, verbosity);
+        let response = problem::with_request(response, &path, &req_id);
         // Try extracting the response body size from the "Content-Length" header.
         let res_body_size: String = response
             .size_hint()
             .upper()
-            .and_then(|s| Some(s.to_string()))
+            .map(|s| s.to_string())
             .unwrap_or("?".to_string());
-        span.record("res_size", &format_args!("{}", res_body_size)); // prevent debug formatting
+        span.record("res_size", format_args!("{}", res_body_size)); // prevent debug formatting
         response
     }
     .instrument(span.clone())
@@ -0,0 +1,256 @@
+//! JSON-RPC for local tools and editors, over stdin and stdout or a Unix domain socket, see
+//! `ServerBuilder::serve_stdio` and `ServerBuilder::serve_unix`. Messages go through the same
+//! dispatch as `POST /json_rpc`: every call is charged to the policy of `/json_rpc` and to its
+//! method's, and each message is traced like a request. There is no client address to tell
+//! local clients apart, so the clients of a transport share one rate limiting key,
+//! `local:stdio` or `local:unix`, which is kept apart from those of HTTP clients. Calls also
+//! count against the quotas of that key, which can be given limits of its own under
+//! `quota.keys`.
+
+use std::io;
+use std::sync::Arc;
+
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+use tracing::{info_span, warn, Instrument};
+
+use crate::json_rpc::{JsonRpcPayload, JsonRpcRouter, JSON_RPC_PATH};
+use crate::{internal_error, routes, AppState};
+
+/// Longest header line accepted with `Framing::ContentLength`.
+const MAX_HEADER_LINE: u64 = 1024;
+
+/// How messages are delimited on the stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// One message per line, blank lines are skipped.
+    #[default]
+    Lines,
+    /// Each message is preceded by a `Content-Length: <bytes>` header and a blank line, as in
+    /// the Language Server Protocol. Other headers are ignored.
+    ContentLength,
+}
+
+impl Framing {
+    /// Reads the next message, or `None` once the stream has ended. Messages over `max_size`
+    /// bytes are an error, as the stream can't be read any further.
+    async fn read<R>(&self, reader: &mut R, max_size: usize) -> io::Result<Option<Vec<u8>>>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        match self {
+            Framing::Lines => loop {
+                let mut line = Vec::new();
+                let read = reader
+                    .take(max_size as u64 + 1)
+                    .read_until(b'\n', &mut line)
+                    .await?;
+                if read == 0 {
+                    return Ok(None);
+                }
+                if line.len() > max_size {
+                    return Err(too_large(max_size));
+                }
+                if !line.trim_ascii().is_empty() {
+                    return Ok(Some(line));
+                }
+            },
+            Framing::ContentLength => {
+                let mut content_length = None;
+                loop {
+                    let mut line = String::new();
+                    let read = reader.take(MAX_HEADER_LINE).read_line(&mut line).await?;
+                    if read == 0 {
+                        return match content_length {
+                            None => Ok(None),
+                            Some(_) => Err(io::ErrorKind::UnexpectedEof.into()),
+                        };
+                    }
+                    let line = line.trim();
+                    if line.is_empty() {
+                        // Tolerate blank lines between messages
+                        if content_length.is_some() {
+                            break;
+                        }
+                        continue;
+                    }
+                    let Some((name, value)) = line.split_once(':') else {
+                        return Err(invalid(format!("Malformed header {:?}", line)));
+                    };
+                    if name.trim().eq_ignore_ascii_case("content-length") {
+                        let length = value.trim().parse::<usize>().map_err(|_| {
+                            invalid(format!("Invalid Content-Length {:?}", value.trim()))
+                        })?;
+                        content_length = Some(length);
+                    }
+                }
+                let length = content_length.unwrap_or_default();
+                if length > max_size {
+                    return Err(too_large(max_size));
+                }
+                let mut message = vec![0; length];
+                reader.read_exact(&mut message).await?;
+                Ok(Some(message))
+            }
+        }
+    }
+
+    async fn write<W>(&self, writer: &mut W, message: &[u8]) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        match self {
+            Framing::Lines => {
+                writer.write_all(message).await?;
+                writer.write_all(b"\n").await?;
+            }
+            Framing::ContentLength => {
+                let header = format!("Content-Length: {}\r\n\r\n", message.len());
+                writer.write_all(header.as_bytes()).await?;
+                writer.write_all(message).await?;
+            }
+        }
+        writer.flush().await
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn too_large(max_size: usize) -> io::Error {
+    invalid(format!("Message exceeds the limit of {} bytes", max_size))
+}
+
+/// Where and how a stream is served.
+#[derive(Clone)]
+pub(crate) struct Transport {
+    /// `stdio` or `unix`, for the logs and the rate limiting key.
+    pub name: &'static str,
+    pub framing: Framing,
+    /// Longest message accepted, the server's `body_limit`.
+    pub max_size: usize,
+    pub state: AppState,
+    pub router: Arc<JsonRpcRouter>,
+}
+
+/// Serves the methods of the transport's router on one stream until it ends or shutdown is
+/// triggered. Up to `local_max_in_flight` messages are handled at the same time, so responses
+/// come back in whatever order they finish, matched up by `id`. Calls still in flight when
+/// reading stops get to finish, within the shutdown deadline if shutdown was triggered.
+pub(crate) async fn serve_stream<R, W>(
+    reader: R,
+    mut writer: W,
+    transport: Transport,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let Transport {
+        name,
+        framing,
+        max_size,
+        state,
+        router,
+    } = transport;
+    let mut reader = BufReader::new(reader);
+    let (outgoing, mut outgoing_rx) = mpsc::channel::<Vec<u8>>(state.json_rpc.local_max_in_flight);
+    let writer = tokio::spawn(async move {
+        while let Some(response) = outgoing_rx.recv().await {
+            framing.write(&mut writer, &response).await?;
+        }
+        Ok::<_, io::Error>(())
+    });
+
+    let in_flight = Arc::new(Semaphore::new(state.json_rpc.local_max_in_flight));
+    let mut calls = JoinSet::new();
+    let read = loop {
+        // Reading isn't cancel safe, so nothing but shutdown may interrupt it
+        let message = tokio::select! {
+            message = framing.read(&mut reader, max_size) => message,
+            _ = state.shutdown.triggered() => break Ok(()),
+        };
+        let message = match message {
+            Ok(Some(message)) => message,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        let Ok(permit) = in_flight.clone().acquire_owned().await else {
+            break Ok(());
+        };
+        let state = state.clone();
+        let router = router.clone();
+        let outgoing = outgoing.clone();
+        calls.spawn(async move {
+            if let Some(response) = handle_message(&state, &router, &message, name).await {
+                let _ = outgoing.send(response).await;
+            }
+            drop(permit);
+        });
+        // Reap finished calls so the set doesn't grow forever
+        while calls.try_join_next().is_some() {}
+    };
+
+    // Calls left when the stream ends get to finish, unless shutdown cuts them off
+    let deadline = state.shutdown_deadline;
+    let drained = tokio::select! {
+        _ = async { while calls.join_next().await.is_some() {} } => true,
+        _ = async {
+            state.shutdown.triggered().await;
+            tokio::time::sleep(deadline).await;
+        } => false,
+    };
+    if !drained {
+        warn!(
+            "Shutdown deadline of {:?} exceeded, aborting {} {} calls",
+            deadline,
+            calls.len(),
+            name
+        );
+        calls.shutdown().await;
+    }
+    drop(outgoing);
+    let written = writer.await.map_err(io::Error::other)?;
+    read.and(written)
+}
+
+/// The response to a message, if it needs one, traced like `trace_http` traces requests.
+async fn handle_message(
+    state: &AppState,
+    router: &JsonRpcRouter,
+    message: &[u8],
+    transport: &'static str,
+) -> Option<Vec<u8>> {
+    let span = info_span!(
+        target: "req_handler",
+        "req_handler",
+        req_id = %uuid::Uuid::new_v4(),
+        transport = transport,
+        req_size = message.len(),
+        res_size = tracing::field::Empty,
+    );
+    async {
+        let response = match JsonRpcPayload::from_slice(message) {
+            Ok(payload) => {
+                let key = format!("local:{}", transport);
+                let path = Some(JSON_RPC_PATH);
+                let (response, internal) =
+                    routes::dispatch(state, router, &key, Some(&key), path, 0, payload).await;
+                for internal in &internal {
+                    internal_error::log(internal);
+                }
+                response?
+            }
+            Err(response) => response,
+        };
+        let response = serde_json::to_vec(&response).ok()?;
+        tracing::Span::current().record("res_size", response.len());
+        Some(response)
+    }
+    .instrument(span)
+    .await
+}
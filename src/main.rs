@@ -1,15 +1,85 @@
-use rust_http_template::start;
-use tokio::signal;
-use tracing::{debug, warn, Level};
-use tracing_subscriber::{fmt::format::FmtSpan, layer::SubscriberExt, Layer};
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+
+use rust_http_template::{config::ServerConfig, local::Framing, start, ServerBuilder};
+use tracing::{error, Level};
+use tracing_subscriber::{
+    fmt::{format::FmtSpan, writer::BoxMakeWriter},
+    layer::SubscriberExt,
+    Layer,
+};
+
+const USAGE: &str = "\
+Usage:
+    rust_http_template [serve] [CONFIG]
+    rust_http_template stdio [--content-length] [CONFIG]
+    rust_http_template unix SOCKET [--content-length] [CONFIG]";
+
+/// What to serve, picked by the first argument. Without one the HTTP server is started.
+enum Command {
+    Serve,
+    /// JSON-RPC over stdin and stdout.
+    Stdio(Framing),
+    /// JSON-RPC over a Unix domain socket at the given path.
+    Unix(PathBuf, Framing),
+}
+
+/// Parses `[COMMAND] [ARGS] [--content-length] [CONFIG]`, returning the command and the
+/// optional path to a TOML/YAML config file.
+fn parse_args(args: impl Iterator<Item = String>) -> Result<(Command, Option<String>), String> {
+    let mut positional = Vec::new();
+    let mut framing = Framing::Lines;
+    for arg in args {
+        match arg.as_str() {
+            "--content-length" => framing = Framing::ContentLength,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            flag if flag.starts_with("--") => {
+                return Err(format!("Unknown option {}\n\n{}", flag, USAGE))
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    let (command, config) = match positional.next() {
+        Some(command) if command == "serve" => (Command::Serve, positional.next()),
+        Some(command) if command == "stdio" => (Command::Stdio(framing), positional.next()),
+        Some(command) if command == "unix" => match positional.next() {
+            Some(socket) => (Command::Unix(socket.into(), framing), positional.next()),
+            None => return Err(format!("Missing socket path\n\n{}", USAGE)),
+        },
+        // Just a config file, as before there were commands
+        Some(config) => (Command::Serve, Some(config)),
+        None => (Command::Serve, None),
+    };
+    if let Some(extra) = positional.next() {
+        return Err(format!("Unexpected argument {}\n\n{}", extra, USAGE));
+    }
+    Ok((command, config))
+}
 
 #[tokio::main]
 async fn main() {
+    let (command, config_path) = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    // stdout carries the JSON-RPC messages when serving on stdio
+    let writer = match command {
+        Command::Stdio(_) => BoxMakeWriter::new(std::io::stderr),
+        _ => BoxMakeWriter::new(std::io::stdout),
+    };
+
     // tracing_subscriber::fmt::init();
     let subscriber = tracing_subscriber::registry().with(
         tracing_subscriber::fmt::layer()
             .compact()
             // .json()
+            .with_writer(writer)
             .with_file(true)
             .with_line_number(true)
             .with_span_events(FmtSpan::CLOSE)
@@ -24,16 +94,66 @@ async fn main() {
 
     tracing::subscriber::set_global_default(subscriber).unwrap();
 
-    tokio::select! {
-        _ = start("0.0.0.0:8080") => {},
-        _ = shutdown_signal() => {
-            warn!("Shutdown timer completed, terminating...");
+    // APP_* env vars override the config file
+    let config = match ServerConfig::load(config_path.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let result = match command {
+        Command::Serve => {
+            start(config).await;
+            Ok(())
+        }
+        Command::Stdio(framing) => {
+            ServerBuilder::new(config)
+                .with_default_services()
+                .serve_stdio(framing)
+                .await
         }
+        Command::Unix(socket, framing) => serve_unix(config, &socket, framing).await,
+    };
+    if let Err(e) = &result {
+        error!("{}", e);
     }
+    // A read of stdin may still be keeping the runtime from shutting down
+    std::process::exit(if result.is_ok() { 0 } else { 1 });
 }
 
-async fn shutdown_signal() {
-    signal::ctrl_c().await.expect("Failed to listen for ctrl-c");
-    debug!("Received Ctrl-C, starting 30s shutdown timer...");
-    tokio::time::sleep(std::time::Duration::from_secs(30)).await;
+async fn serve_unix(config: ServerConfig, socket: &Path, framing: Framing) -> io::Result<()> {
+    remove_stale_socket(socket).await?;
+    let listener = tokio::net::UnixListener::bind(socket)?;
+    let result = ServerBuilder::new(config)
+        .with_default_services()
+        .serve_unix(listener, framing)
+        .await;
+    let _ = std::fs::remove_file(socket);
+    result
+}
+
+/// Removes a socket left behind by a previous run that didn't get to clean up. Anything else
+/// at `socket`, like a regular file or the socket of a server that is still running, is left
+/// alone and reported as an error.
+async fn remove_stale_socket(socket: &Path) -> io::Result<()> {
+    let metadata = match std::fs::symlink_metadata(socket) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", socket.display()),
+        ));
+    }
+    if tokio::net::UnixStream::connect(socket).await.is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use by another server", socket.display()),
+        ));
+    }
+    std::fs::remove_file(socket)
 }
@@ -0,0 +1,201 @@
+//! RFC 9457 problem details, the body of every error response.
+//!
+//! `AppError` renders the parts it knows about and stashes the `Problem` in the response
+//! extensions. `trace_http`, which knows the request, then fills in `instance` and
+//! `request_id` and writes the final body, since handlers behind the buffer layer run on a
+//! different task and can't see the request themselves.
+
+use std::collections::BTreeMap;
+
+use crate::error_code::ErrorCode;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use validator::{ValidationError, ValidationErrors, ValidationErrorsKind};
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Validation errors on the struct itself rather than one of its fields are reported under
+/// this key by `validator`.
+const STRUCT_ERRORS: &str = "__all__";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Problem {
+    /// A URI identifying the kind of problem, `about:blank` when the status says it all.
+    #[serde(rename = "type")]
+    pub type_uri: String,
+    pub title: String,
+    pub status: u16,
+    /// The stable error code from the catalog, see `error_code`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// The path of the request that failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Set for internal errors, whose details are only logged, under this ID.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+/// One failed validation rule, so clients can point at the offending field.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldError {
+    /// JSON Pointer to the field in the request body, e.g. `/items/0/name`.
+    pub pointer: String,
+    /// The rule that failed, e.g. `length` or `range`.
+    pub code: String,
+    pub detail: String,
+    /// The rule's parameters, e.g. `min` and `max`. The rejected value is left out so
+    /// secrets don't get echoed back.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub params: BTreeMap<String, serde_json::Value>,
+}
+
+impl Problem {
+    /// A problem described by nothing but its status, as `about:blank` problems are.
+    pub fn new(status: StatusCode) -> Self {
+        Self {
+            type_uri: "about:blank".to_string(),
+            title: status.canonical_reason().unwrap_or("Unknown").to_string(),
+            status: status.as_u16(),
+            code: None,
+            detail: None,
+            instance: None,
+            request_id: None,
+            error_id: None,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn with_code(mut self, code: &ErrorCode) -> Self {
+        self.code = Some(code.code.to_string());
+        self
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    pub fn with_error_id(mut self, error_id: &str) -> Self {
+        self.error_id = Some(error_id.to_string());
+        self
+    }
+
+    pub fn with_errors(mut self, errors: Vec<FieldError>) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    fn to_response(&self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let body = serde_json::to_vec(self).expect("problems always serialize");
+        let mut response = (status, body).into_response();
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+        response
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let mut response = self.to_response();
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+/// Flattens nested `validator` errors into one entry per failed rule, sorted by field.
+pub fn field_errors(errors: &ValidationErrors) -> Vec<FieldError> {
+    let mut fields = Vec::new();
+    collect(errors, "", &mut fields);
+    fields.sort_by(|a, b| (&a.pointer, &a.code).cmp(&(&b.pointer, &b.code)));
+    fields
+}
+
+fn collect(errors: &ValidationErrors, prefix: &str, out: &mut Vec<FieldError>) {
+    for (field, kind) in errors.errors() {
+        let pointer = if *field == STRUCT_ERRORS {
+            prefix.to_string()
+        } else {
+            format!("{}/{}", prefix, escape(field))
+        };
+        match kind {
+            ValidationErrorsKind::Field(errors) => {
+                out.extend(errors.iter().map(|error| field_error(&pointer, error)))
+            }
+            ValidationErrorsKind::Struct(errors) => collect(errors, &pointer, out),
+            ValidationErrorsKind::List(items) => {
+                for (index, errors) in items {
+                    collect(errors, &format!("{}/{}", pointer, index), out);
+                }
+            }
+        }
+    }
+}
+
+fn field_error(pointer: &str, error: &ValidationError) -> FieldError {
+    let params: BTreeMap<_, _> = error
+        .params
+        .iter()
+        .filter(|(name, _)| *name != "value")
+        .map(|(name, value)| (name.to_string(), value.clone()))
+        .collect();
+    let detail = match &error.message {
+        Some(message) => message.to_string(),
+        None => describe(&error.code, &params),
+    };
+    FieldError {
+        pointer: pointer.to_string(),
+        code: error.code.to_string(),
+        detail,
+        params,
+    }
+}
+
+/// A readable message for the built-in rules, for errors declared without one.
+fn describe(code: &str, params: &BTreeMap<String, serde_json::Value>) -> String {
+    let (min, max) = (params.get("min"), params.get("max"));
+    let bounds = |unit: &str| match (min, max) {
+        (Some(min), Some(max)) => format!("must be between {} and {}{}", min, max, unit),
+        (Some(min), None) => format!("must be at least {}{}", min, unit),
+        (None, Some(max)) => format!("must be at most {}{}", max, unit),
+        (None, None) => "is out of range".to_string(),
+    };
+    match code {
+        "length" => bounds(" characters long"),
+        "range" => bounds(""),
+        "required" => "is required".to_string(),
+        "email" => "must be an email address".to_string(),
+        "url" => "must be a URL".to_string(),
+        other => format!("failed the {} check", other),
+    }
+}
+
+/// Escapes a field name for use in a JSON Pointer (RFC 6901).
+fn escape(field: &str) -> String {
+    field.replace('~', "~0").replace('/', "~1")
+}
+
+/// Completes the problem in `response`, if it carries one, with what is known about the
+/// request it answers.
+pub(crate) fn with_request(response: Response, instance: &str, request_id: &str) -> Response {
+    let Some(mut problem) = response.extensions().get::<Problem>().cloned() else {
+        return response;
+    };
+    problem.instance = Some(instance.to_string());
+    problem.request_id = Some(request_id.to_string());
+
+    let (mut parts, _) = response.into_parts();
+    let completed = problem.to_response();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.extensions.insert(problem);
+    Response::from_parts(parts, completed.into_body())
+}
@@ -0,0 +1,324 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::Decision;
+
+/// A rate limiting algorithm, written as a pure function over the state it keeps per key.
+/// Every algorithm is configured with the same "`limit` requests per `window`" budget.
+pub trait Algorithm: Send + Sync + 'static {
+    type State: Send + 'static;
+
+    fn new_state(&self, now: Instant) -> Self::State;
+
+    /// Counts a request that uses up `cost` of the budget. A cost above the limit is charged
+    /// as the whole limit, so it only gets through once the key has fully reset.
+    fn check(&self, state: &mut Self::State, now: Instant, cost: u64) -> Decision;
+
+    /// Whether `state` has fully reset, so dropping it is the same as keeping it.
+    fn is_expired(&self, state: &Self::State, now: Instant) -> bool;
+}
+
+/// Counts requests in windows that start with the first request after the previous one ended.
+/// Cheap, but a client can fit up to twice the limit around a window boundary.
+#[derive(Debug, Clone)]
+pub struct FixedWindow {
+    limit: u64,
+    window: Duration,
+}
+
+#[derive(Debug)]
+pub struct FixedWindowState {
+    start: Instant,
+    count: u64,
+}
+
+impl FixedWindow {
+    pub fn new(limit: u64, window: Duration) -> Self {
+        Self { limit, window }
+    }
+}
+
+impl Algorithm for FixedWindow {
+    type State = FixedWindowState;
+
+    fn new_state(&self, now: Instant) -> Self::State {
+        FixedWindowState {
+            start: now,
+            count: 0,
+        }
+    }
+
+    fn check(&self, state: &mut Self::State, now: Instant, cost: u64) -> Decision {
+        let cost = cost.min(self.limit);
+        if now.duration_since(state.start) >= self.window {
+            state.start = now;
+            state.count = 0;
+        }
+
+        let reset_after = self.window - now.duration_since(state.start);
+        if state.count + cost <= self.limit {
+            state.count += cost;
+            Decision::allow(self.limit, self.limit - state.count, reset_after)
+        } else {
+            Decision::reject(self.limit, reset_after, reset_after)
+        }
+    }
+
+    fn is_expired(&self, state: &Self::State, now: Instant) -> bool {
+        now.duration_since(state.start) >= self.window
+    }
+}
+
+/// Keeps the timestamp of every allowed request in the last window. Exact, but memory grows
+/// with the limit.
+#[derive(Debug, Clone)]
+pub struct SlidingWindowLog {
+    limit: u64,
+    window: Duration,
+}
+
+impl SlidingWindowLog {
+    pub fn new(limit: u64, window: Duration) -> Self {
+        Self { limit, window }
+    }
+}
+
+impl Algorithm for SlidingWindowLog {
+    type State = VecDeque<Instant>;
+
+    fn new_state(&self, _now: Instant) -> Self::State {
+        VecDeque::new()
+    }
+
+    fn check(&self, log: &mut Self::State, now: Instant, cost: u64) -> Decision {
+        let cost = cost.min(self.limit);
+        while log
+            .front()
+            .is_some_and(|oldest| now.duration_since(*oldest) >= self.window)
+        {
+            log.pop_front();
+        }
+
+        // A request costing n is logged n times
+        let used = log.len() as u64;
+        if used + cost <= self.limit {
+            log.extend(std::iter::repeat_n(now, cost as usize));
+            return Decision::allow(self.limit, self.limit - used - cost, self.window);
+        }
+
+        // Enough of the oldest entries have to expire to make room for `cost` more
+        let expires = |at: &Instant| self.window - now.duration_since(*at);
+        let must_expire = (used + cost - self.limit) as usize;
+        Decision::reject(
+            self.limit,
+            log.back().map(expires).unwrap_or_default(),
+            log.get(must_expire - 1).map(expires).unwrap_or_default(),
+        )
+    }
+
+    fn is_expired(&self, log: &Self::State, now: Instant) -> bool {
+        log.back()
+            .is_none_or(|newest| now.duration_since(*newest) >= self.window)
+    }
+}
+
+/// Approximates a sliding window by weighting the previous fixed window's count by how much
+/// of it still overlaps the sliding window. Constant memory, smooths out boundary bursts.
+#[derive(Debug, Clone)]
+pub struct SlidingWindowCounter {
+    limit: u64,
+    window: Duration,
+}
+
+#[derive(Debug)]
+pub struct SlidingWindowCounterState {
+    start: Instant,
+    current: u64,
+    previous: u64,
+}
+
+impl SlidingWindowCounter {
+    pub fn new(limit: u64, window: Duration) -> Self {
+        Self { limit, window }
+    }
+}
+
+impl Algorithm for SlidingWindowCounter {
+    type State = SlidingWindowCounterState;
+
+    fn new_state(&self, now: Instant) -> Self::State {
+        SlidingWindowCounterState {
+            start: now,
+            current: 0,
+            previous: 0,
+        }
+    }
+
+    fn check(&self, state: &mut Self::State, now: Instant, cost: u64) -> Decision {
+        let cost = cost.min(self.limit);
+        // All the math is in integer nanoseconds so results don't depend on float rounding
+        let window = self.window.as_nanos();
+        let elapsed = now.duration_since(state.start).as_nanos();
+        if elapsed >= window {
+            // Only the window right before the current one still counts
+            state.previous = if elapsed < 2 * window {
+                state.current
+            } else {
+                0
+            };
+            state.current = 0;
+            state.start = now - nanos(elapsed % window);
+        }
+
+        let elapsed = now.duration_since(state.start).as_nanos();
+        let (limit, current, previous, cost) = (
+            self.limit as u128,
+            state.current as u128,
+            state.previous as u128,
+            cost as u128,
+        );
+        // The estimated request count, scaled by the window length
+        let weighted = previous * (window - elapsed) + current * window;
+
+        if weighted + cost * window <= limit * window {
+            state.current += cost as u64;
+            return Decision::allow(
+                self.limit,
+                ((limit * window - weighted - cost * window) / window) as u64,
+                nanos(2 * window - elapsed),
+            );
+        }
+
+        // Solve for the point where the estimate leaves room for `cost` more
+        let retry_after = if current + cost <= limit {
+            let ready_at = window - (limit - current - cost) * window / previous;
+            ready_at - elapsed
+        } else {
+            // Not until the next window, where the current count becomes the previous one
+            (window - elapsed) + (window - (limit - cost) * window / current)
+        };
+        Decision::reject(self.limit, nanos(2 * window - elapsed), nanos(retry_after))
+    }
+
+    fn is_expired(&self, state: &Self::State, now: Instant) -> bool {
+        let elapsed = now.duration_since(state.start);
+        // Once a window with no requests has passed, nothing is left to weigh
+        elapsed >= 2 * self.window || (elapsed >= self.window && state.current == 0)
+    }
+}
+
+/// Starts full with `limit` tokens and refills continuously at `limit / window`.
+/// Allows bursts up to the limit while enforcing the average rate.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    limit: u64,
+    window: Duration,
+}
+
+#[derive(Debug)]
+pub struct TokenBucketState {
+    /// One token is `window` units, so refilling adds exactly `limit` units per nanosecond.
+    units: u128,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: u64, window: Duration) -> Self {
+        Self { limit, window }
+    }
+}
+
+impl Algorithm for TokenBucket {
+    type State = TokenBucketState;
+
+    fn new_state(&self, now: Instant) -> Self::State {
+        TokenBucketState {
+            units: self.limit as u128 * self.window.as_nanos(),
+            last_refill: now,
+        }
+    }
+
+    fn check(&self, state: &mut Self::State, now: Instant, cost: u64) -> Decision {
+        let (limit, token) = (self.limit as u128, self.window.as_nanos());
+        let capacity = limit * token;
+        let refill = now.duration_since(state.last_refill).as_nanos() * limit;
+        state.units = (state.units + refill).min(capacity);
+        state.last_refill = now;
+
+        let needed = cost.min(self.limit) as u128 * token;
+        if state.units >= needed {
+            state.units -= needed;
+            return Decision::allow(
+                self.limit,
+                (state.units / token) as u64,
+                nanos((capacity - state.units).div_ceil(limit)),
+            );
+        }
+
+        Decision::reject(
+            self.limit,
+            nanos((capacity - state.units).div_ceil(limit)),
+            nanos((needed - state.units).div_ceil(limit)),
+        )
+    }
+
+    fn is_expired(&self, state: &Self::State, now: Instant) -> bool {
+        let refill = now.duration_since(state.last_refill).as_nanos() * self.limit as u128;
+        state.units + refill >= self.limit as u128 * self.window.as_nanos()
+    }
+}
+
+/// Generic Cell Rate Algorithm: tracks a single "theoretical arrival time" per key.
+/// Behaves like a token bucket but needs only one timestamp of state.
+#[derive(Debug, Clone)]
+pub struct Gcra {
+    limit: u64,
+    window: Duration,
+}
+
+#[derive(Debug)]
+pub struct GcraState {
+    tat: Instant,
+}
+
+impl Gcra {
+    pub fn new(limit: u64, window: Duration) -> Self {
+        Self { limit, window }
+    }
+
+    fn emission_interval(&self) -> Duration {
+        let nanos = self.window.as_nanos() / self.limit as u128;
+        Duration::from_nanos(nanos.max(1) as u64)
+    }
+}
+
+impl Algorithm for Gcra {
+    type State = GcraState;
+
+    fn new_state(&self, now: Instant) -> Self::State {
+        GcraState { tat: now }
+    }
+
+    fn check(&self, state: &mut Self::State, now: Instant, cost: u64) -> Decision {
+        let interval = self.emission_interval();
+        let tat = state.tat.max(now);
+        let new_tat = tat + nanos(interval.as_nanos() * cost.min(self.limit) as u128);
+        let ahead = new_tat - now;
+
+        if ahead <= self.window {
+            state.tat = new_tat;
+            let remaining = ((self.window - ahead).as_nanos() / interval.as_nanos()) as u64;
+            return Decision::allow(self.limit, remaining, ahead);
+        }
+
+        Decision::reject(self.limit, tat - now, ahead - self.window)
+    }
+
+    fn is_expired(&self, state: &Self::State, now: Instant) -> bool {
+        state.tat <= now
+    }
+}
+
+fn nanos(n: u128) -> Duration {
+    Duration::from_nanos(n as u64)
+}
@@ -0,0 +1,60 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Source of time for the rate limiters, so tests can drive them deterministically.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Wall clock time, for limits tied to the calendar like daily quotas.
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to.
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    system_start: SystemTime,
+    elapsed: Mutex<Duration>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::at(SystemTime::now())
+    }
+}
+
+impl MockClock {
+    /// A clock whose wall time starts at `system_start`.
+    pub fn at(system_start: SystemTime) -> Self {
+        Self {
+            start: Instant::now(),
+            system_start,
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.system_start + *self.elapsed.lock().unwrap()
+    }
+}
@@ -0,0 +1,415 @@
+use axum::extract::Request;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use lru::LruCache;
+use std::fmt::Write;
+use std::hash::{BuildHasher, RandomState};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tracing::debug;
+
+mod algorithms;
+mod clock;
+mod policy;
+mod quota;
+mod store;
+pub use algorithms::*;
+pub use clock::*;
+pub use policy::*;
+pub use quota::*;
+pub use store::*;
+
+use crate::client_ip::ClientIp;
+use crate::config::{RateLimitAlgorithm, RateLimitConfig, RateLimitPolicy};
+use crate::error_code::ErrorCodes;
+use crate::{grpc, AppError, AppState};
+
+pub const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+pub const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+pub const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+/// The outcome of counting one request against a key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u64,
+    pub remaining: u64,
+    /// How long until the full limit is available again.
+    pub reset_after: Duration,
+    /// For rejected requests, how long until a retry can succeed.
+    pub retry_after: Option<Duration>,
+}
+
+impl Decision {
+    pub(crate) fn allow(limit: u64, remaining: u64, reset_after: Duration) -> Self {
+        Self {
+            allowed: true,
+            limit,
+            remaining,
+            reset_after,
+            retry_after: None,
+        }
+    }
+
+    pub(crate) fn reject(limit: u64, reset_after: Duration, retry_after: Duration) -> Self {
+        Self {
+            allowed: false,
+            limit,
+            remaining: 0,
+            reset_after,
+            retry_after: Some(retry_after),
+        }
+    }
+
+    /// Sets the `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` headers from
+    /// the IETF RateLimit header fields draft, plus `Retry-After` when the request was
+    /// rejected. Durations are whole seconds, rounded up so clients never retry too early.
+    pub fn write_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(RATELIMIT_LIMIT, HeaderValue::from(self.limit));
+        headers.insert(RATELIMIT_REMAINING, HeaderValue::from(self.remaining));
+        headers.insert(
+            RATELIMIT_RESET,
+            HeaderValue::from(ceil_secs(self.reset_after)),
+        );
+        if let Some(retry_after) = self.retry_after {
+            headers.insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(ceil_secs(retry_after)),
+            );
+        }
+    }
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// Point-in-time counters describing how much state a limiter holds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateLimiterMetrics {
+    pub tracked_keys: usize,
+    /// Keys dropped by `sweep` because their limits had fully reset.
+    pub expired: u64,
+    /// Keys dropped to stay under the key cap while still holding state.
+    pub evicted: u64,
+}
+
+#[async_trait::async_trait]
+pub trait RateLimiter: Send + Sync {
+    /// Counts a request against `key` and decides whether it may proceed.
+    async fn check(&self, key: &str) -> Decision {
+        self.check_cost(key, 1).await
+    }
+
+    /// Like `check`, for a request that uses up `cost` of the budget.
+    async fn check_cost(&self, key: &str, cost: u64) -> Decision;
+
+    /// Drops the state of keys whose limits have fully reset and returns how many there were.
+    async fn sweep(&self) -> usize {
+        0
+    }
+
+    fn metrics(&self) -> RateLimiterMetrics {
+        RateLimiterMetrics::default()
+    }
+}
+
+type Shard<A> = LruCache<String, <A as Algorithm>::State>;
+
+/// Runs an `Algorithm` over per-key state kept in this process.
+///
+/// Keys are spread over shards that each have their own lock, so requests for different keys
+/// rarely wait on each other. The locks are only held while running the algorithm, never
+/// across an await.
+///
+/// At most `max_keys` keys are tracked, beyond that the least recently used key of a shard is
+/// evicted. That key may still have been limited, which is the price of bounded memory under
+/// a scan from many addresses. Keys whose limits reset are only dropped by `sweep`.
+pub struct InMemoryRateLimiter<A: Algorithm> {
+    algorithm: A,
+    clock: Arc<dyn Clock>,
+    shards: Box<[Mutex<Shard<A>>]>,
+    hasher: RandomState,
+    max_keys: Option<NonZeroUsize>,
+    expired: AtomicU64,
+    evicted: AtomicU64,
+}
+
+impl<A: Algorithm> InMemoryRateLimiter<A> {
+    pub fn new(algorithm: A) -> Self {
+        Self::with_clock(algorithm, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(algorithm: A, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            algorithm,
+            clock,
+            shards: Box::new([]),
+            hasher: RandomState::new(),
+            max_keys: None,
+            expired: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+        }
+        .with_shards(default_shards())
+    }
+
+    /// Caps the number of tracked keys, which is unbounded by default. The cap is split evenly
+    /// over the shards, and there are never more shards than keys.
+    pub fn with_max_keys(mut self, max_keys: NonZeroUsize) -> Self {
+        self.max_keys = Some(max_keys);
+        let shards = NonZeroUsize::new(self.shards.len()).unwrap();
+        self.with_shards(shards)
+    }
+
+    /// Sets the number of shards, which defaults to a few per CPU. A single shard makes the
+    /// key cap an exact LRU over all keys.
+    pub fn with_shards(mut self, shards: NonZeroUsize) -> Self {
+        self.shards = match self.max_keys {
+            Some(max_keys) => {
+                // Every shard holds at least one key, so more shards would exceed the cap
+                let shards = shards.min(max_keys).get();
+                let (per_shard, rest) = (max_keys.get() / shards, max_keys.get() % shards);
+                (0..shards)
+                    .map(|i| {
+                        let capacity = NonZeroUsize::new(per_shard + usize::from(i < rest));
+                        Mutex::new(LruCache::new(capacity.unwrap()))
+                    })
+                    .collect()
+            }
+            None => (0..shards.get())
+                .map(|_| Mutex::new(LruCache::unbounded()))
+                .collect(),
+        };
+        self
+    }
+
+    fn shard(&self, key: &str) -> MutexGuard<'_, Shard<A>> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        // The algorithms don't panic, but a poisoned shard is still better than none
+        self.shards[index]
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// A few shards per CPU keeps contention low without spreading the key cap too thin.
+fn default_shards() -> NonZeroUsize {
+    let cpus = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    NonZeroUsize::new((cpus * 4).next_power_of_two()).unwrap()
+}
+
+#[async_trait::async_trait]
+impl<A: Algorithm> RateLimiter for InMemoryRateLimiter<A> {
+    async fn check_cost(&self, key: &str, cost: u64) -> Decision {
+        let now = self.clock.now();
+        let mut states = self.shard(key);
+
+        if let Some(state) = states.get_mut(key) {
+            return self.algorithm.check(state, now, cost);
+        }
+        let mut state = self.algorithm.new_state(now);
+        let decision = self.algorithm.check(&mut state, now, cost);
+        if let Some((evicted, state)) = states.push(key.to_string(), state) {
+            if !self.algorithm.is_expired(&state, now) {
+                debug!("Rate limiter is full, evicted {}", evicted);
+                self.evicted.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.expired.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        decision
+    }
+
+    async fn sweep(&self) -> usize {
+        let now = self.clock.now();
+        let mut swept = 0;
+
+        // One shard at a time, so traffic on the others carries on
+        for shard in self.shards.iter() {
+            let mut states = shard
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let expired: Vec<String> = states
+                .iter()
+                .filter(|(_, state)| self.algorithm.is_expired(state, now))
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in &expired {
+                states.pop(key);
+            }
+            swept += expired.len();
+        }
+
+        self.expired.fetch_add(swept as u64, Ordering::Relaxed);
+        swept
+    }
+
+    fn metrics(&self) -> RateLimiterMetrics {
+        let tracked_keys = self
+            .shards
+            .iter()
+            .map(|shard| {
+                shard
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .len()
+            })
+            .sum();
+        RateLimiterMetrics {
+            tracked_keys,
+            expired: self.expired.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Builds the limiter for the default policy in `config`.
+pub fn from_config(config: &RateLimitConfig, clock: Arc<dyn Clock>) -> Arc<dyn RateLimiter> {
+    from_policy(&config.default_policy(), config, clock)
+}
+
+/// Builds the limiter for `policy`, with the key cap from `config`.
+pub fn from_policy(
+    policy: &RateLimitPolicy,
+    config: &RateLimitConfig,
+    clock: Arc<dyn Clock>,
+) -> Arc<dyn RateLimiter> {
+    fn build<A: Algorithm>(
+        algorithm: A,
+        config: &RateLimitConfig,
+        clock: Arc<dyn Clock>,
+    ) -> Arc<dyn RateLimiter> {
+        // Already checked when the config was validated
+        let max_keys = NonZeroUsize::new(config.max_keys).expect("max_keys must be positive");
+        Arc::new(InMemoryRateLimiter::with_clock(algorithm, clock).with_max_keys(max_keys))
+    }
+
+    let (limit, window) = (policy.max_requests, policy.window());
+    match policy.algorithm {
+        RateLimitAlgorithm::FixedWindow => build(FixedWindow::new(limit, window), config, clock),
+        RateLimitAlgorithm::SlidingWindowLog => {
+            build(SlidingWindowLog::new(limit, window), config, clock)
+        }
+        RateLimitAlgorithm::SlidingWindowCounter => {
+            build(SlidingWindowCounter::new(limit, window), config, clock)
+        }
+        RateLimitAlgorithm::TokenBucket => build(TokenBucket::new(limit, window), config, clock),
+        RateLimitAlgorithm::Gcra => build(Gcra::new(limit, window), config, clock),
+    }
+}
+
+/// Sweeps every policy's limiter every `interval`, forever.
+pub(crate) async fn sweep_every(policies: Arc<RateLimitPolicies>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let expired = policies.sweep().await;
+        if expired > 0 {
+            debug!("Rate limiter swept {} expired keys", expired);
+        }
+    }
+}
+
+/// Serves the metrics of every policy's limiter in the Prometheus text format.
+pub(crate) async fn metrics(State(policies): State<Arc<RateLimitPolicies>>) -> impl IntoResponse {
+    let metrics = policies.metrics();
+    let mut body = String::new();
+    let _ = writeln!(
+        body,
+        "# HELP rate_limiter_tracked_keys Keys the rate limiter currently holds state for.\n\
+         # TYPE rate_limiter_tracked_keys gauge"
+    );
+    for (policy, metrics) in &metrics {
+        let _ = writeln!(
+            body,
+            "rate_limiter_tracked_keys{{policy=\"{}\"}} {}",
+            policy, metrics.tracked_keys
+        );
+    }
+    let _ = writeln!(
+        body,
+        "# HELP rate_limiter_evictions_total Keys the rate limiter dropped, by reason.\n\
+         # TYPE rate_limiter_evictions_total counter"
+    );
+    for (policy, metrics) in &metrics {
+        let _ = writeln!(
+            body,
+            "rate_limiter_evictions_total{{policy=\"{}\",reason=\"expired\"}} {}\n\
+             rate_limiter_evictions_total{{policy=\"{}\",reason=\"capacity\"}} {}",
+            policy, metrics.expired, policy, metrics.evicted
+        );
+    }
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+/// The key requests are limited by: the client IP resolved by `resolve_client_ip`.
+pub(crate) fn client_key(client_ip: Option<ClientIp>) -> String {
+    client_ip
+        .map(|ip| ip.0.to_string())
+        .unwrap_or("unknown".to_string())
+}
+
+/// The 429 response for a rejected request, with the rate limit headers set.
+pub(crate) fn rejection(decision: &Decision) -> Response {
+    let mut response = rate_limited(decision).into_response();
+    decision.write_headers(response.headers_mut());
+    response
+}
+
+/// The error a rejected request gets, for transports that can't carry the headers.
+pub(crate) fn rate_limited(decision: &Decision) -> AppError {
+    let retry_after = ceil_secs(decision.retry_after.unwrap_or_default());
+    AppError::RateLimited(anyhow::anyhow!("try again in {} seconds", retry_after))
+}
+
+/// Rejects `request`: gRPC calls get a `RESOURCE_EXHAUSTED` status with a `RetryInfo` detail
+/// that clients can act on, everything else the 429 from `rejection`.
+pub(crate) fn reject(request: &Request, decision: &Decision) -> Response {
+    if !grpc::status::is_grpc(request.headers()) {
+        return rejection(decision);
+    }
+    let retry_after = ceil_secs(decision.retry_after.unwrap_or_default());
+    let code = ErrorCodes::RATE_LIMITED;
+    let status = grpc::status::with_details(
+        code.grpc_code,
+        format!("Rate limit exceeded: try again in {} seconds", retry_after),
+        vec![
+            grpc::status::error_info(&code, Default::default()),
+            grpc::status::retry_info(Duration::from_secs(retry_after)),
+        ],
+    );
+    let mut response = grpc::status::into_response(status);
+    decision.write_headers(response.headers_mut());
+    response
+}
+
+pub(crate) async fn ip_rate_limiter(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let key = client_key(request.extensions().get::<ClientIp>().copied());
+
+    // Check if the request is allowed under its route's policy
+    let decision = state
+        .rate_limits
+        .check_path(request.uri().path(), &key)
+        .await;
+
+    if !decision.allowed {
+        // Return 429 Too Many Requests (or RESOURCE_EXHAUSTED) if rate limit exceeded
+        return reject(&request, &decision);
+    }
+    // Continue to next handler if allowed
+    let mut response = next.run(request).await;
+    // A stricter check further in, like a JSON-RPC method's policy, already described itself
+    if !response.headers().contains_key(RATELIMIT_LIMIT) {
+        decision.write_headers(response.headers_mut());
+    }
+    response
+}
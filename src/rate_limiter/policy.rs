@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::{
+    from_policy, Clock, Decision, RateLimitStore, RateLimiter, RateLimiterMetrics, RedisStore,
+    StoreRateLimiter,
+};
+use crate::config::{PolicyRef, RateLimitConfig, RateLimitPolicy, DEFAULT_POLICY};
+
+/// A limiter for every configured policy, and which routes and methods are charged to which.
+pub struct RateLimitPolicies {
+    limiters: HashMap<String, Arc<dyn RateLimiter>>,
+    paths: HashMap<String, Attachment>,
+    rpc_methods: HashMap<String, Attachment>,
+}
+
+#[derive(Clone)]
+struct Attachment {
+    limiter: Arc<dyn RateLimiter>,
+    cost: u64,
+}
+
+impl RateLimitPolicies {
+    /// Builds a limiter per policy, backed by the shared store when one is configured.
+    /// Expects a validated config, where every attachment names an existing policy.
+    pub fn from_config(config: &RateLimitConfig, clock: Arc<dyn Clock>) -> Self {
+        let store = config.store.as_ref().map(|store| {
+            let redis = RedisStore::new(store).expect("invalid rate limit store url");
+            (
+                Arc::new(redis) as Arc<dyn RateLimitStore>,
+                &store.key_prefix,
+            )
+        });
+        let build = |name: &str, policy: &RateLimitPolicy| -> Arc<dyn RateLimiter> {
+            let local = from_policy(policy, config, clock.clone());
+            match &store {
+                Some((store, key_prefix)) => Arc::new(StoreRateLimiter::new(
+                    store.clone(),
+                    &format!("{}:{}", key_prefix, name),
+                    policy.max_requests,
+                    policy.window(),
+                    local,
+                )),
+                None => local,
+            }
+        };
+
+        let mut limiters = HashMap::new();
+        limiters.insert(
+            DEFAULT_POLICY.to_string(),
+            build(DEFAULT_POLICY, &config.default_policy()),
+        );
+        for (name, policy) in &config.policies {
+            limiters.insert(name.clone(), build(name, policy));
+        }
+
+        let attach = |refs: &HashMap<String, PolicyRef>| -> HashMap<String, Attachment> {
+            refs.iter()
+                .map(|(target, policy_ref)| {
+                    let limiter = limiters
+                        .get(&policy_ref.policy)
+                        .unwrap_or_else(|| {
+                            panic!("unknown rate limit policy {}", policy_ref.policy)
+                        })
+                        .clone();
+                    let cost = policy_ref.cost;
+                    (target.clone(), Attachment { limiter, cost })
+                })
+                .collect()
+        };
+        // gRPC methods are routed by path just like axum routes
+        let mut paths = attach(&config.routes);
+        paths.extend(attach(&config.grpc_methods));
+        let rpc_methods = attach(&config.rpc_methods);
+
+        Self {
+            limiters,
+            paths,
+            rpc_methods,
+        }
+    }
+
+    /// Charges a request for `path` to the policy of its route or gRPC method, or to the
+    /// default policy if it has none.
+    pub async fn check_path(&self, path: &str, key: &str) -> Decision {
+        match self.paths.get(path) {
+            Some(attachment) => attachment.limiter.check_cost(key, attachment.cost).await,
+            None => self.limiters[DEFAULT_POLICY].check(key).await,
+        }
+    }
+
+    /// Charges a JSON-RPC call to its method's policy, if it has one. The call has already been
+    /// charged to the policy of its path by `check_path`.
+    pub async fn check_rpc_method(&self, method: &str, key: &str) -> Option<Decision> {
+        let attachment = self.rpc_methods.get(method)?;
+        Some(attachment.limiter.check_cost(key, attachment.cost).await)
+    }
+
+    /// Sweeps every policy's limiter and returns how many keys were dropped in total.
+    pub async fn sweep(&self) -> usize {
+        let mut swept = 0;
+        for limiter in self.limiters.values() {
+            swept += limiter.sweep().await;
+        }
+        swept
+    }
+
+    /// Metrics of every policy's limiter, by policy name.
+    pub fn metrics(&self) -> Vec<(String, RateLimiterMetrics)> {
+        let mut metrics: Vec<_> = self
+            .limiters
+            .iter()
+            .map(|(name, limiter)| (name.clone(), limiter.metrics()))
+            .collect();
+        metrics.sort_by(|a, b| a.0.cmp(&b.0));
+        metrics
+    }
+}
@@ -0,0 +1,375 @@
+use std::convert::Infallible;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::extract::{OptionalFromRequestParts, Request, State};
+use axum::http::{request::Parts, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use super::{
+    from_policy, reject, Clock, Decision, LocalStore, RateLimitStore, RateLimiter, RedisStore,
+    StoreError,
+};
+use crate::config::{QuotaConfig, QuotaLimits, RateLimitConfig};
+use crate::error_code::ErrorCodes;
+use crate::tls::PeerIdentity;
+use crate::{grpc, AppError, AppState};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Where the usage endpoint is served. Requests to it don't count against any quota.
+pub const QUOTA_PATH: &str = "/quota";
+
+/// A calendar period quotas are counted over, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaPeriod {
+    Daily,
+    Monthly,
+}
+
+impl QuotaPeriod {
+    /// Names the period `now` falls in (`2024-02-29` or `2024-02`) and says how long until it
+    /// ends.
+    pub fn current(self, now: SystemTime) -> (String, Duration) {
+        let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let days = secs / SECS_PER_DAY;
+        let (year, month, day) = civil_from_days(days);
+        let (name, end_days) = match self {
+            QuotaPeriod::Daily => (format!("{:04}-{:02}-{:02}", year, month, day), days + 1),
+            QuotaPeriod::Monthly => {
+                let (next_year, next_month) = if month == 12 {
+                    (year + 1, 1)
+                } else {
+                    (year, month + 1)
+                };
+                let end = days_from_civil(next_year, next_month, 1);
+                (format!("{:04}-{:02}", year, month), end)
+            }
+        };
+        (name, Duration::from_secs(end_days * SECS_PER_DAY - secs))
+    }
+}
+
+/// How much of one quota a key has used.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct QuotaUsage {
+    pub period: QuotaPeriod,
+    pub limit: u64,
+    pub used: u64,
+    pub remaining: u64,
+    /// Seconds until the period ends and the quota starts over.
+    pub reset_secs: u64,
+}
+
+/// Daily and monthly quotas per API key or tenant, plus an optional burst limit per key.
+///
+/// A request is counted first and taken back off if that puts it over a quota, or if the
+/// burst limit turns it away, so rejected requests don't use any up. As the count is atomic,
+/// even in the shared store, concurrent requests can't overshoot a quota. One of them may be
+/// rejected for another that ends up rejected too, which is fine for billing periods this long.
+pub struct Quotas {
+    config: QuotaConfig,
+    clock: Arc<dyn Clock>,
+    burst: Option<Arc<dyn RateLimiter>>,
+    shared: Option<(Arc<dyn RateLimitStore>, String)>,
+    local: LocalStore,
+    degraded: AtomicBool,
+}
+
+/// One quota a request was counted against.
+struct Counted {
+    counter: String,
+    limit: u64,
+    used: u64,
+    resets_in: Duration,
+}
+
+impl Quotas {
+    /// Builds the quotas in `config`, or returns `None` if there aren't any. Expects a
+    /// validated config.
+    pub fn from_config(config: &RateLimitConfig, clock: Arc<dyn Clock>) -> Option<Self> {
+        let quota = config.quota.clone()?;
+        let shared = config.store.as_ref().map(|store| {
+            let redis = RedisStore::new(store).expect("invalid rate limit store url");
+            (
+                Arc::new(redis) as Arc<dyn RateLimitStore>,
+                store.key_prefix.clone(),
+            )
+        });
+        let max_keys = NonZeroUsize::new(config.max_keys).expect("max_keys must be positive");
+
+        Some(Self {
+            burst: quota
+                .burst
+                .as_ref()
+                .map(|burst| from_policy(burst, config, clock.clone())),
+            local: LocalStore::new(max_keys, clock.clone()),
+            config: quota,
+            clock,
+            shared,
+            degraded: AtomicBool::new(false),
+        })
+    }
+
+    /// The API key or tenant of a request: the configured header, or the client certificate's
+    /// common name if enabled. A header that isn't valid text is an error rather than no key,
+    /// so it can't be used to skip the quotas.
+    pub fn key(&self, request: &Request) -> Result<Option<String>, AppError> {
+        if let Some(key) = request.headers().get(self.config.header.as_str()) {
+            let key = key.to_str().map_err(|_| {
+                AppError::coded(
+                    ErrorCodes::BAD_REQUEST,
+                    anyhow::anyhow!("Invalid {} header", self.config.header),
+                )
+            })?;
+            return Ok(Some(key.to_string()));
+        }
+        if self.config.client_cert {
+            return Ok(request
+                .extensions()
+                .get::<PeerIdentity>()
+                .and_then(|peer| peer.common_name.clone()));
+        }
+        Ok(None)
+    }
+
+    /// Checks every quota and the burst limit for `key`, counting the request if all of them
+    /// allow it. Returns `None` if `key` has no limits at all.
+    pub async fn check(&self, key: &str) -> Option<Decision> {
+        // Counting before comparing means concurrent requests can't all take the last one
+        let limits = self.limits(key);
+        let now = self.clock.system_now();
+        let mut counted = Vec::new();
+        for (period, limit) in [
+            (QuotaPeriod::Daily, limits.daily),
+            (QuotaPeriod::Monthly, limits.monthly),
+        ] {
+            let Some(limit) = limit else { continue };
+            let (name, resets_in) = period.current(now);
+            let counter = self.counter_key(key, &name);
+            let used = self.increment(&counter, resets_in).await;
+            counted.push(Counted {
+                counter,
+                limit,
+                used,
+                resets_in,
+            });
+            if used > limit {
+                self.uncount(&counted).await;
+                return Some(Decision::reject(limit, resets_in, resets_in));
+            }
+        }
+
+        // Quotas go first, so requests they turn away don't use up the burst limit
+        let burst = match &self.burst {
+            Some(burst) => Some(burst.check(key).await),
+            None => None,
+        };
+        if let Some(decision) = burst.as_ref().filter(|d| !d.allowed) {
+            self.uncount(&counted).await;
+            return Some(decision.clone());
+        }
+
+        // Report whichever quota is closest to running out
+        counted
+            .iter()
+            .min_by_key(|counted| counted.limit - counted.used)
+            .map(|counted| {
+                let remaining = counted.limit - counted.used;
+                Decision::allow(counted.limit, remaining, counted.resets_in)
+            })
+            .or(burst)
+    }
+
+    /// How much of each of its quotas `key` has used in the current periods.
+    pub async fn usage(&self, key: &str) -> Vec<QuotaUsage> {
+        let limits = self.limits(key);
+        let now = self.clock.system_now();
+        let mut usage = Vec::new();
+        for (period, limit) in [
+            (QuotaPeriod::Daily, limits.daily),
+            (QuotaPeriod::Monthly, limits.monthly),
+        ] {
+            let Some(limit) = limit else { continue };
+            let (name, resets_in) = period.current(now);
+            let used = self.get(&self.counter_key(key, &name)).await;
+            usage.push(QuotaUsage {
+                period,
+                limit,
+                used,
+                remaining: limit.saturating_sub(used),
+                reset_secs: resets_in.as_secs(),
+            });
+        }
+        usage
+    }
+
+    fn limits(&self, key: &str) -> &QuotaLimits {
+        self.config.keys.get(key).unwrap_or(&self.config.limits)
+    }
+
+    fn counter_key(&self, key: &str, period: &str) -> String {
+        match &self.shared {
+            Some((_, prefix)) => format!("{}:quota:{}:{}", prefix, period, key),
+            None => format!("quota:{}:{}", period, key),
+        }
+    }
+
+    async fn get(&self, counter: &str) -> u64 {
+        if let Some((store, _)) = &self.shared {
+            match store.get(counter).await {
+                Ok(count) => return self.recovered(count),
+                Err(e) => self.degrade(e),
+            }
+        }
+        // The local store never fails
+        self.local.get(counter).await.unwrap_or_default()
+    }
+
+    async fn increment(&self, counter: &str, resets_in: Duration) -> u64 {
+        if let Some((store, _)) = &self.shared {
+            match store.increment(counter, 1, resets_in).await {
+                Ok((count, _)) => return self.recovered(count),
+                Err(e) => self.degrade(e),
+            }
+        }
+        self.local
+            .increment(counter, 1, resets_in)
+            .await
+            .map_or(0, |(count, _)| count)
+    }
+
+    /// Takes back a request `check` counted before it was turned away.
+    async fn uncount(&self, counted: &[Counted]) {
+        for Counted { counter, .. } in counted {
+            if let Some((store, _)) = &self.shared {
+                match store.decrement(counter, 1).await {
+                    Ok(()) => continue,
+                    Err(e) => self.degrade(e),
+                }
+            }
+            let _ = self.local.decrement(counter, 1).await;
+        }
+    }
+
+    fn recovered<T>(&self, value: T) -> T {
+        if self.degraded.swap(false, Ordering::Relaxed) {
+            info!("Rate limit store is back, using shared quotas again");
+        }
+        value
+    }
+
+    fn degrade(&self, e: StoreError) {
+        if !self.degraded.swap(true, Ordering::Relaxed) {
+            warn!("Falling back to local quotas: {}", e);
+        }
+    }
+}
+
+/// The API key or tenant a request was charged to by the quota middleware, for handlers that
+/// charge the calls it carries one by one, like JSON-RPC batches. Missing when quotas are off
+/// or the request has no key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaKey(pub String);
+
+impl<S: Send + Sync> OptionalFromRequestParts<S> for QuotaKey {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        Ok(parts.extensions.get::<QuotaKey>().cloned())
+    }
+}
+
+/// This middleware enforces the quotas of requests that carry an API key or tenant ID.
+/// Requests without one are only subject to the per-IP limits.
+pub(crate) async fn quota_limiter(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(quotas) = &state.quotas else {
+        return next.run(request).await;
+    };
+    if request.uri().path() == QUOTA_PATH {
+        return next.run(request).await;
+    }
+    let key = match quotas.key(&request) {
+        Ok(Some(key)) => key,
+        Ok(None) => return next.run(request).await,
+        Err(e) if grpc::status::is_grpc(request.headers()) => {
+            return grpc::status::into_response(e.into())
+        }
+        Err(e) => return e.into_response(),
+    };
+
+    match quotas.check(&key).await {
+        Some(decision) if !decision.allowed => reject(&request, &decision),
+        _ => {
+            request.extensions_mut().insert(QuotaKey(key));
+            next.run(request).await
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct QuotaReport {
+    key: String,
+    quotas: Vec<QuotaUsage>,
+}
+
+/// Reports the caller's usage and remaining quota, identified the same way as for limiting.
+pub(crate) async fn quota_usage(
+    State(state): State<AppState>,
+    request: Request,
+) -> Result<impl IntoResponse, AppError> {
+    let quotas = state.quotas.as_ref().ok_or_else(|| {
+        AppError::CustomCode(
+            anyhow::anyhow!("Quotas are not enabled"),
+            StatusCode::NOT_FOUND,
+        )
+    })?;
+    let key = quotas.key(&request)?.ok_or_else(|| {
+        AppError::CustomCode(
+            anyhow::anyhow!("Missing {} header", quotas.config.header),
+            StatusCode::UNAUTHORIZED,
+        )
+    })?;
+
+    let usage = quotas.usage(&key).await;
+    Ok(Json(QuotaReport { key, quotas: usage }))
+}
+
+/// Converts days since the Unix epoch to a (year, month, day) date in the proleptic
+/// Gregorian calendar, see https://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// The inverse of `civil_from_days`.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year % 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
@@ -0,0 +1,305 @@
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+use redis::aio::MultiplexedConnection;
+use redis::AsyncConnectionConfig;
+use tracing::{info, warn};
+
+use super::{Clock, Decision, RateLimiter, RateLimiterMetrics};
+use crate::config::RateLimitStoreConfig;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("rate limit store error: {0}")]
+    Redis(#[from] redis::RedisError),
+    #[error("rate limit store timed out")]
+    Timeout,
+    #[error("rate limit store is unavailable, retrying in {0:?}")]
+    Unavailable(Duration),
+}
+
+/// Counter storage shared by every replica, so they all draw from the same budget.
+#[async_trait::async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Atomically adds `cost` to the counter for `key`, which starts at zero and expires
+    /// `window` after it was created. Returns the new count and how long until it expires.
+    async fn increment(
+        &self,
+        key: &str,
+        cost: u64,
+        window: Duration,
+    ) -> Result<(u64, Duration), StoreError>;
+
+    /// Takes `cost` back off the counter for `key`, undoing an `increment` that turned out not
+    /// to count. A counter that has expired since is left alone, and none goes below zero.
+    async fn decrement(&self, key: &str, cost: u64) -> Result<(), StoreError>;
+
+    /// The current count for `key`, 0 if it doesn't exist or has expired.
+    async fn get(&self, key: &str) -> Result<u64, StoreError>;
+}
+
+/// Decrements `KEYS[1]` by `ARGV[1]` if it exists, stopping at zero.
+const DECREMENT_SCRIPT: &str = "\
+local count = redis.call('GET', KEYS[1])
+if count then
+    redis.call('DECRBY', KEYS[1], math.min(tonumber(count), tonumber(ARGV[1])))
+end";
+
+/// A `RateLimitStore` speaking the Redis protocol, so it works with Redis, Valkey, KeyDB,
+/// Dragonfly and the like.
+///
+/// The connection is made on first use and remade after it fails. While the store is down,
+/// calls fail fast for `retry_interval` instead of waiting on a connect timeout every time.
+pub struct RedisStore {
+    client: redis::Client,
+    connection_config: AsyncConnectionConfig,
+    timeout: Duration,
+    retry_interval: Duration,
+    connection: tokio::sync::Mutex<Connection>,
+}
+
+enum Connection {
+    Idle,
+    Connected(MultiplexedConnection),
+    Failed(Instant),
+}
+
+impl RedisStore {
+    pub fn new(config: &RateLimitStoreConfig) -> Result<Self, StoreError> {
+        Ok(Self {
+            client: redis::Client::open(config.url.as_str())?,
+            connection_config: AsyncConnectionConfig::new()
+                .set_connection_timeout(config.timeout())
+                .set_response_timeout(config.timeout()),
+            timeout: config.timeout(),
+            retry_interval: config.retry_interval(),
+            connection: tokio::sync::Mutex::new(Connection::Idle),
+        })
+    }
+
+    async fn connection(&self) -> Result<MultiplexedConnection, StoreError> {
+        // Held while connecting, so a burst of requests makes one attempt rather than many
+        let mut connection = self.connection.lock().await;
+        match &*connection {
+            Connection::Connected(conn) => return Ok(conn.clone()),
+            Connection::Failed(at) if at.elapsed() < self.retry_interval => {
+                return Err(StoreError::Unavailable(self.retry_interval - at.elapsed()));
+            }
+            _ => {}
+        }
+
+        let connect = self
+            .client
+            .get_multiplexed_async_connection_with_config(&self.connection_config);
+        match tokio::time::timeout(self.timeout, connect).await {
+            Ok(Ok(conn)) => {
+                *connection = Connection::Connected(conn.clone());
+                Ok(conn)
+            }
+            Ok(Err(e)) => {
+                *connection = Connection::Failed(Instant::now());
+                Err(e.into())
+            }
+            Err(_) => {
+                *connection = Connection::Failed(Instant::now());
+                Err(StoreError::Timeout)
+            }
+        }
+    }
+
+    async fn query<T: redis::FromRedisValue>(
+        &self,
+        pipeline: &redis::Pipeline,
+    ) -> Result<T, StoreError> {
+        let mut conn = self.connection().await?;
+        match pipeline.query_async(&mut conn).await {
+            Ok(reply) => Ok(reply),
+            Err(e) => {
+                if e.is_io_error() || e.is_timeout() || e.is_connection_dropped() {
+                    *self.connection.lock().await = Connection::Failed(Instant::now());
+                }
+                Err(e.into())
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RateLimitStore for RedisStore {
+    async fn increment(
+        &self,
+        key: &str,
+        cost: u64,
+        window: Duration,
+    ) -> Result<(u64, Duration), StoreError> {
+        // INCRBY keeps the TTL set by whichever request created the counter. MULTI/EXEC makes
+        // the three commands atomic, so no other client can see a counter without a TTL.
+        let (count, ttl_ms): (u64, i64) = self
+            .query(
+                redis::pipe()
+                    .atomic()
+                    .cmd("SET")
+                    .arg(key)
+                    .arg(0)
+                    .arg("PX")
+                    .arg(window.as_millis() as u64)
+                    .arg("NX")
+                    .ignore()
+                    .cmd("INCRBY")
+                    .arg(key)
+                    .arg(cost)
+                    .cmd("PTTL")
+                    .arg(key),
+            )
+            .await?;
+
+        // A negative TTL means someone else created the key without one
+        let ttl = u64::try_from(ttl_ms).map_or(window, Duration::from_millis);
+        Ok((count, ttl))
+    }
+
+    async fn decrement(&self, key: &str, cost: u64) -> Result<(), StoreError> {
+        // A plain DECRBY would recreate an expired counter below zero and without a TTL
+        self.query::<()>(
+            redis::pipe()
+                .cmd("EVAL")
+                .arg(DECREMENT_SCRIPT)
+                .arg(1)
+                .arg(key)
+                .arg(cost)
+                .ignore(),
+        )
+        .await
+    }
+
+    async fn get(&self, key: &str) -> Result<u64, StoreError> {
+        let (count,): (Option<u64>,) = self.query(redis::pipe().cmd("GET").arg(key)).await?;
+        Ok(count.unwrap_or(0))
+    }
+}
+
+/// A `RateLimitStore` in this process, for counters that don't need to be shared or as the
+/// fallback for one that does. Holds at most `max_keys` counters, evicting the least recently
+/// used ones beyond that.
+pub struct LocalStore {
+    clock: Arc<dyn Clock>,
+    counters: Mutex<LruCache<String, (u64, Instant)>>,
+}
+
+impl LocalStore {
+    pub fn new(max_keys: NonZeroUsize, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            counters: Mutex::new(LruCache::new(max_keys)),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RateLimitStore for LocalStore {
+    async fn increment(
+        &self,
+        key: &str,
+        cost: u64,
+        window: Duration,
+    ) -> Result<(u64, Duration), StoreError> {
+        let now = self.clock.now();
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters.get_or_insert_mut(key.to_string(), || (0, now + window));
+        if counter.1 <= now {
+            *counter = (0, now + window);
+        }
+        counter.0 += cost;
+        Ok((counter.0, counter.1 - now))
+    }
+
+    async fn decrement(&self, key: &str, cost: u64) -> Result<(), StoreError> {
+        let now = self.clock.now();
+        let mut counters = self.counters.lock().unwrap();
+        if let Some(counter) = counters.peek_mut(key).filter(|(_, expires)| *expires > now) {
+            counter.0 = counter.0.saturating_sub(cost);
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<u64, StoreError> {
+        let now = self.clock.now();
+        let counters = self.counters.lock().unwrap();
+        Ok(counters
+            .peek(key)
+            .filter(|(_, expires)| *expires > now)
+            .map_or(0, |(count, _)| *count))
+    }
+}
+
+/// Keeps fixed-window counters in a `RateLimitStore`, falling back to a local limiter while
+/// the store is unreachable. Falling back means each replica enforces the full limit on its
+/// own until the store is back, which beats rejecting or allowing everything.
+///
+/// Rejected requests are counted too, as with any INCR based limiter, so clients that keep
+/// retrying stay limited.
+pub struct StoreRateLimiter {
+    store: Arc<dyn RateLimitStore>,
+    key_prefix: String,
+    limit: u64,
+    window: Duration,
+    fallback: Arc<dyn RateLimiter>,
+    degraded: AtomicBool,
+}
+
+impl StoreRateLimiter {
+    pub fn new(
+        store: Arc<dyn RateLimitStore>,
+        key_prefix: &str,
+        limit: u64,
+        window: Duration,
+        fallback: Arc<dyn RateLimiter>,
+    ) -> Self {
+        Self {
+            store,
+            key_prefix: key_prefix.to_string(),
+            limit,
+            window,
+            fallback,
+            degraded: AtomicBool::new(false),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RateLimiter for StoreRateLimiter {
+    async fn check_cost(&self, key: &str, cost: u64) -> Decision {
+        let store_key = format!("{}:{}", self.key_prefix, key);
+        let cost = cost.min(self.limit);
+        match self.store.increment(&store_key, cost, self.window).await {
+            Ok((count, ttl)) => {
+                if self.degraded.swap(false, Ordering::Relaxed) {
+                    info!("Rate limit store is back, using shared limits again");
+                }
+                if count <= self.limit {
+                    Decision::allow(self.limit, self.limit - count, ttl)
+                } else {
+                    Decision::reject(self.limit, ttl, ttl)
+                }
+            }
+            Err(e) => {
+                if !self.degraded.swap(true, Ordering::Relaxed) {
+                    warn!("Falling back to local rate limits: {}", e);
+                }
+                self.fallback.check_cost(key, cost).await
+            }
+        }
+    }
+
+    // Counters in the store expire on their own, only the fallback keeps local state
+    async fn sweep(&self) -> usize {
+        self.fallback.sweep().await
+    }
+
+    fn metrics(&self) -> RateLimiterMetrics {
+        self.fallback.metrics()
+    }
+}
@@ -1,29 +1,68 @@
 // pub mod echo;
 mod echo;
+mod ws;
 use axum::{
-    extract::State,
+    extract::{OriginalUri, State},
     response::sse::{Event, Sse},
-    Json,
+    routing::{get, post},
+    Extension, Json,
 };
 pub use echo::*;
+pub use ws::*;
 
-use axum::{body::Bytes, response::IntoResponse};
+use axum::{
+    body::Bytes,
+    response::{IntoResponse, Response},
+};
 use futures::stream::{self, Stream};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::Value;
 use std::convert::Infallible;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio_stream::StreamExt;
+use validator::Validate;
 
 use axum::{extract::Request, http::StatusCode};
 
 use crate::{
+    client_ip::ClientIp,
+    error_code::ErrorCodes,
+    internal_error::InternalError,
     json_rpc::{
-        self, JsonRpcRequest, JsonRpcResponse, JsonRpcResponseError, JsonRpcResponseSuccess,
+        Id, JsonRpcError, JsonRpcPayload, JsonRpcRequest, JsonRpcResponse, JsonRpcResponseSuccess,
+        JsonRpcRouter, Params, JSONRPC_VERSION,
     },
+    rate_limiter::{self, Decision, QuotaKey},
     AppError, AppState,
 };
 
+/// The example routes that ship with the template, see `ServerBuilder::with_default_services`.
+pub(crate) fn router() -> axum::Router<AppState> {
+    axum::Router::new()
+        .route("/echo/json", post(echo_json))
+        .route("/echo/json_extractor", post(echo_json_extractor))
+        .route(
+            "/echo/nested_function_tracing",
+            post(echo_nested_function_tracing),
+        )
+        .route("/sse", get(sse_res))
+        .route("/stream", get(stream_res))
+        .route("/stream_handler", post(stream_handler))
+    // .route(
+    //     "/{key}",
+    //     get(get::get_key).post(post::write_key),
+    // )
+}
+
+/// The example JSON-RPC methods that ship with the template.
+pub(crate) fn rpc_router() -> JsonRpcRouter {
+    JsonRpcRouter::new()
+        .method_with_schema("my_rpc", my_rpc)
+        .method_with_schema("greeting_rpc", greeting_rpc)
+}
+
 pub async fn sse_res() -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
     // Create a stream that yields bytes every 100ms
 
@@ -49,7 +88,7 @@ pub async fn stream_res() -> impl IntoResponse {
     let bytes = Bytes::from("Hello, World!\n").to_vec();
     let chunks: Vec<_> = bytes.chunks(3).map(|x| x.to_vec()).collect();
 
-    let s = stream::iter(chunks.into_iter().map(|x| Ok::<_, std::io::Error>(x)))
+    let s = stream::iter(chunks.into_iter().map(Ok::<_, std::io::Error>))
         .throttle(Duration::from_millis(100));
 
     // Convert the stream into a response
@@ -83,92 +122,310 @@ pub async fn stream_handler(request: Request) -> Result<String, (StatusCode, Str
         .map_err(|err| (StatusCode::BAD_REQUEST, format!("Invalid UTF-8: {}", err)))
 }
 
+/// Serves single JSON-RPC calls and batches of them. Batch requests run concurrently and their
+/// responses come back in whatever order they finish, matched up by `id`. Requests without an
+/// `id` are notifications: they get no response, or a 204 if they came on their own.
+///
+/// Every call of a batch is charged to the policy of the path and to the caller's quotas like a
+/// request of its own, the first one being covered by the HTTP request carrying them.
+///
+/// `ServerBuilder` serves one of these for each path a `JsonRpcRouter` is mounted on.
 pub async fn json_rpc(
-    State(_state): State<AppState>, // state must be listed first in params
-    Json(payload): Json<JsonRpcRequest>,
-) -> Result<Json<serde_json::Value>, AppError> {
-    let res: Result<serde_json::Value, anyhow::Error> = match payload.method.as_str() {
-        "my_rpc" => match my_rpc(serde_json::from_value(payload.params).unwrap()).await {
-            Ok(response) => Ok(response.with_id(payload.id.clone()).into()),
-            Err(e) => Err(e),
-        },
-        "greeting_rpc" => {
-            match greeting_rpc(serde_json::from_value(payload.params).unwrap()).await {
-                Ok(response) => Ok(response.with_id(payload.id.clone()).into()),
-                Err(e) => Err(e),
+    State(state): State<AppState>, // state must be listed first in params
+    Extension(router): Extension<Arc<JsonRpcRouter>>,
+    OriginalUri(uri): OriginalUri,
+    client_ip: Option<ClientIp>,
+    quota_key: Option<QuotaKey>,
+    payload: JsonRpcPayload,
+) -> Result<Response, AppError> {
+    let key = rate_limiter::client_key(client_ip);
+    let quota_key = quota_key.map(|QuotaKey(key)| key);
+    let batch = match payload {
+        JsonRpcPayload::Batch(batch) => batch,
+        JsonRpcPayload::Single(payload) => {
+            let request = match parse_request(payload) {
+                Ok(request) => request,
+                Err((e, id)) => return Ok(json_rpc_response(Err(e), Some(id))),
+            };
+            let id = request.id.clone();
+            // Methods can have a policy of their own on top of the one for `/json_rpc`
+            if let Some(decision) = state
+                .rate_limits
+                .check_rpc_method(&request.method, &key)
+                .await
+            {
+                if !decision.allowed {
+                    let error = Err(rate_limiter::rate_limited(&decision));
+                    let mut response = json_rpc_response(error, id);
+                    decision.write_headers(response.headers_mut());
+                    return Ok(response);
+                }
             }
+            return Ok(json_rpc_response(call(&router, request).await, id));
+        }
+    };
+
+    let path = Some(uri.path());
+    let (responses, internal) =
+        call_batch(&state, &router, &key, quota_key.as_deref(), path, 1, batch).await;
+    let mut response = match responses {
+        Some(responses) => Json(responses).into_response(),
+        // Nothing but notifications
+        None => StatusCode::NO_CONTENT.into_response(),
+    };
+    response.extensions_mut().insert(internal);
+    Ok(response)
+}
+
+/// Handles a message of a transport other than HTTP, where every call, including a single one,
+/// is answered with an error object when rate limited. Calls are charged to the policy of
+/// `path`, if any, and to the quotas of `quota_key`, if any, except for the first `prepaid`
+/// ones. Returns what to send back, if anything, along with the internal errors it reports.
+pub(crate) async fn dispatch(
+    state: &AppState,
+    router: &JsonRpcRouter,
+    key: &str,
+    quota_key: Option<&str>,
+    path: Option<&str>,
+    prepaid: usize,
+    payload: JsonRpcPayload,
+) -> (Option<Value>, Vec<InternalError>) {
+    match payload {
+        JsonRpcPayload::Single(request) => {
+            let paid = match prepaid {
+                0 => charge(state, key, quota_key, path).await,
+                _ => None,
+            };
+            let (response, internal) = call_in_batch(state, router, key, paid, request).await;
+            (response.map(Value::from), internal.into_iter().collect())
         }
-        _ => Ok(JsonRpcResponseError {
-            jsonrpc: payload.jsonrpc.clone(),
-            id: payload.id.clone(),
-            data: Some::<json_rpc::InternalError>(anyhow::anyhow!("Method not found").into()),
-            code: json_rpc::METHOD_NOT_FOUND,
+        JsonRpcPayload::Batch(batch) => {
+            call_batch(state, router, key, quota_key, path, prepaid, batch).await
         }
-        .into()),
+    }
+}
+
+/// Charges one call to the policy of `path` and then to the quotas of `quota_key`, like the
+/// middleware charges a request, stopping at the first that turns it away. Returns the
+/// decision that turned it away, or else the path's, if anything was charged.
+async fn charge(
+    state: &AppState,
+    key: &str,
+    quota_key: Option<&str>,
+    path: Option<&str>,
+) -> Option<Decision> {
+    let paid = match path {
+        Some(path) => Some(state.rate_limits.check_path(path, key).await),
+        None => None,
+    };
+    if paid.as_ref().is_some_and(|decision| !decision.allowed) {
+        return paid;
+    }
+    let (Some(quotas), Some(quota_key)) = (&state.quotas, quota_key) else {
+        return paid;
     };
+    match quotas.check(quota_key).await {
+        Some(decision) if !decision.allowed => Some(decision),
+        _ => paid,
+    }
+}
 
-    match res {
-        Ok(response) => Ok(Json(response)),
-        Err(e) => Ok(Json(
-            JsonRpcResponseError {
-                jsonrpc: payload.jsonrpc,
-                id: payload.id,
-                data: Some(json!({ "error": e.to_string() })),
-                code: json_rpc::INTERNAL_ERROR,
-            }
-            .into(),
-        )),
+/// Runs the requests of a batch concurrently, returning their responses unless the batch was
+/// nothing but notifications. Each request past the first `prepaid` is charged to the policy
+/// of `path` and the quotas of `quota_key` first, in order, so a batch can't be used to get
+/// around the limits.
+async fn call_batch(
+    state: &AppState,
+    router: &JsonRpcRouter,
+    key: &str,
+    quota_key: Option<&str>,
+    path: Option<&str>,
+    prepaid: usize,
+    batch: Vec<Value>,
+) -> (Option<Value>, Vec<InternalError>) {
+    let max_batch_size = state.json_rpc.max_batch_size;
+    if batch.is_empty() || batch.len() > max_batch_size {
+        let e = match batch.len() {
+            0 => anyhow::anyhow!("Empty batch"),
+            size => anyhow::anyhow!(
+                "Batch of {} requests exceeds the limit of {}",
+                size,
+                max_batch_size
+            ),
+        };
+        let (response, _) = into_json_rpc_response(
+            Err(AppError::coded(ErrorCodes::BAD_REQUEST, e)),
+            Some(Id::Null),
+        );
+        return (response.map(Value::from), Vec::new());
+    }
+
+    let mut paid = Vec::with_capacity(batch.len());
+    for i in 0..batch.len() {
+        paid.push(if i >= prepaid {
+            charge(state, key, quota_key, path).await
+        } else {
+            None
+        });
     }
+    let calls = batch
+        .into_iter()
+        .zip(paid)
+        .map(|(request, paid)| call_in_batch(state, router, key, paid, request));
+    let results: Vec<(Option<JsonRpcResponse>, Option<InternalError>)> =
+        futures::StreamExt::buffer_unordered(stream::iter(calls), state.json_rpc.batch_concurrency)
+            .collect()
+            .await;
+    let (responses, internal): (Vec<_>, Vec<_>) = results.into_iter().unzip();
+    let responses: Vec<Value> = responses.into_iter().flatten().map(Value::from).collect();
+    let internal = internal.into_iter().flatten().collect();
+    if responses.is_empty() {
+        return (None, internal);
+    }
+    (Some(responses.into()), internal)
+}
+
+/// Runs one request of a batch or of a message that didn't come over HTTP, which pays for
+/// its method like a single call would. `paid` is what charging it to its path and quotas
+/// decided, if it was charged.
+async fn call_in_batch(
+    state: &AppState,
+    router: &JsonRpcRouter,
+    key: &str,
+    paid: Option<Decision>,
+    request: Value,
+) -> (Option<JsonRpcResponse>, Option<InternalError>) {
+    let request = match parse_request(request) {
+        Ok(request) => request,
+        Err((e, id)) => return into_json_rpc_response(Err(e), Some(id)),
+    };
+    let id = request.id.clone();
+    if let Some(decision) = paid.filter(|decision| !decision.allowed) {
+        return into_json_rpc_response(Err(rate_limiter::rate_limited(&decision)), id);
+    }
+    let result = match state
+        .rate_limits
+        .check_rpc_method(&request.method, key)
+        .await
+    {
+        Some(decision) if !decision.allowed => Err(rate_limiter::rate_limited(&decision)),
+        _ => call(router, request).await,
+    };
+    into_json_rpc_response(result, id)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+async fn call(
+    router: &JsonRpcRouter,
+    request: JsonRpcRequest,
+) -> Result<JsonRpcResponse, AppError> {
+    let result = router
+        .call(&request.method, request.params)
+        .await
+        .unwrap_or_else(|| {
+            Err(AppError::coded(
+                ErrorCodes::METHOD_NOT_FOUND,
+                anyhow::anyhow!("Method not found: {}", request.method),
+            ))
+        })?;
+    Ok(JsonRpcResponseSuccess::from(result).into())
+}
+
+/// Reads a request, or says why it's invalid along with whatever id it has, as invalid
+/// requests are answered even if they look like notifications.
+fn parse_request(request: Value) -> Result<JsonRpcRequest, (AppError, Id)> {
+    let invalid = |e: anyhow::Error| {
+        let id = request
+            .get("id")
+            .and_then(|id| Id::deserialize(id).ok())
+            .unwrap_or_default();
+        (AppError::coded(ErrorCodes::BAD_REQUEST, e), id)
+    };
+    let parsed = JsonRpcRequest::deserialize(&request).map_err(|e| invalid(e.into()))?;
+    if parsed.jsonrpc != JSONRPC_VERSION {
+        return Err(invalid(anyhow::anyhow!(
+            "Unsupported jsonrpc version {:?}, expected \"{}\"",
+            parsed.jsonrpc,
+            JSONRPC_VERSION
+        )));
+    }
+    Ok(parsed)
+}
+
+/// The response to the request with `id`, or none for notifications, along with the internal
+/// error it reports, if any. Notifications' errors are still logged.
+fn into_json_rpc_response(
+    result: Result<JsonRpcResponse, AppError>,
+    id: Option<Id>,
+) -> (Option<JsonRpcResponse>, Option<InternalError>) {
+    let (response, internal) = match result {
+        Ok(response) => (response, None),
+        Err(e) => {
+            let error = JsonRpcError::from(e);
+            let internal = error.internal.clone();
+            (error.into_response(Id::Null), internal)
+        }
+    };
+    (id.map(|id| response.with_id(id)), internal)
+}
+
+fn json_rpc_response(result: Result<JsonRpcResponse, AppError>, id: Option<Id>) -> Response {
+    let (response, internal) = into_json_rpc_response(result, id);
+    let mut response = match response {
+        Some(response) => Json(Value::from(response)).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    };
+    if let Some(internal) = internal {
+        response.extensions_mut().insert(internal);
+    }
+    response
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate, JsonSchema)]
 pub struct MyRpcParams {
+    /// Who to say hello to.
+    #[validate(length(min = 1))]
     pub name: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, JsonSchema)]
 pub struct MyRpcResponse {
     pub message: String,
 }
 
-pub async fn my_rpc(params: MyRpcParams) -> Result<JsonRpcResponse, anyhow::Error> {
+pub async fn my_rpc(Params(params): Params<MyRpcParams>) -> Result<MyRpcResponse, AppError> {
     if params.name == "error" {
-        return Ok(JsonRpcResponseError {
-            jsonrpc: "2.0".to_string(),
-            id: Some(1),
-            data: Some(json!({ "error": "Internal error" })),
-            code: json_rpc::INTERNAL_ERROR,
-        }
-        .into());
+        return Err(anyhow::anyhow!("my_rpc was asked to fail").into());
     }
-    Ok(JsonRpcResponseSuccess::from(MyRpcResponse {
+    Ok(MyRpcResponse {
         message: format!("Hello, {}!", params.name),
     })
-    .into())
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Validate, JsonSchema)]
 pub struct GreetingRpcParams {
     pub name: String,
+    /// `spanish` or `french`, anything else gets an English greeting.
     pub language: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, JsonSchema)]
 pub struct GreetingRpcResponse {
     pub greeting: String,
+    /// Whether the greeting is in another language than English.
     pub translated: bool,
 }
 
-async fn greeting_rpc(params: GreetingRpcParams) -> Result<JsonRpcResponse, anyhow::Error> {
+async fn greeting_rpc(
+    Params(params): Params<GreetingRpcParams>,
+) -> Result<GreetingRpcResponse, AppError> {
     let greeting = match params.language.to_lowercase().as_str() {
         "spanish" => format!("¡Hola, {}!", params.name),
         "french" => format!("Bonjour, {}!", params.name),
         _ => format!("Hello, {}!", params.name),
     };
 
-    Ok(JsonRpcResponseSuccess::from(GreetingRpcResponse {
+    Ok(GreetingRpcResponse {
         greeting,
         translated: params.language.to_lowercase() != "english",
     })
-    .into())
 }
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{OriginalUri, State};
+use axum::response::Response;
+use axum::Extension;
+use futures::{SinkExt, StreamExt};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tokio::task::{AbortHandle, JoinSet};
+use tracing::{debug, info_span, warn, Instrument};
+use validator::Validate;
+
+use crate::{
+    client_ip::ClientIp,
+    error_code::ErrorCodes,
+    internal_error,
+    json_rpc::{Events, JsonRpcPayload, JsonRpcRequest, JsonRpcRouter, Params, JSONRPC_VERSION},
+    rate_limiter::{self, QuotaKey},
+    AppError, AppState,
+};
+
+/// The methods every WebSocket connection gets on top of those of its router, unless the
+/// router has methods of the same name.
+pub const SUBSCRIBE_METHOD: &str = "subscribe";
+pub const UNSUBSCRIBE_METHOD: &str = "unsubscribe";
+
+/// The notification carrying the events of a subscription, as
+/// `{"subscription": <id>, "result": <event>}`.
+pub const SUBSCRIPTION_METHOD: &str = "subscription";
+
+/// Serves the methods of a `JsonRpcRouter` over WebSocket. Every text or binary message is a
+/// single call or a batch, and up to `ws_max_in_flight` of them run at the same time, so
+/// responses come back in whatever order they finish, matched up by `id`. Each call, including
+/// every call of a batch, is charged to the policy of the WebSocket path like an HTTP request
+/// would be.
+///
+/// The server pushes the notifications sent with `Events::notify`, and the events of the
+/// topics a client subscribed to with `subscribe`, up to `ws_max_subscriptions` of them at a
+/// time. Messages larger than `body_limit` close the connection. On shutdown calls in flight
+/// get until the shutdown deadline to finish before the socket is closed, and
+/// `ServerBuilder::serve` waits for that like it does for requests.
+///
+/// `ServerBuilder` serves one of these for each path a `JsonRpcRouter` is mounted on, at
+/// `json_rpc::ws_path`.
+pub async fn json_rpc_ws(
+    State(state): State<AppState>,
+    Extension(router): Extension<Arc<JsonRpcRouter>>,
+    OriginalUri(uri): OriginalUri,
+    client_ip: Option<ClientIp>,
+    quota_key: Option<QuotaKey>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    // Messages are held to the same limit as request bodies
+    let ws = ws
+        .max_message_size(state.body_limit)
+        .max_frame_size(state.body_limit);
+    let span = info_span!(
+        "json_rpc_ws",
+        path = %uri.path(),
+        client_ip = %client_ip.map(|ip| ip.0.to_string()).unwrap_or("?".to_string()),
+    );
+    let caller = Caller {
+        key: rate_limiter::client_key(client_ip),
+        quota_key: quota_key.map(|QuotaKey(key)| key),
+        path: uri.path().to_string(),
+    };
+    ws.on_upgrade(move |socket| async move {
+        // The upgrade request is done once it hands over the socket, so the session is tracked
+        // on its own for shutdown to wait for
+        let shutdown = state.shutdown.clone();
+        let path = caller.path.clone();
+        let session = serve_socket(socket, state, router, caller).instrument(span);
+        shutdown.spawn("WebSocket", "GET", &path, session);
+    })
+}
+
+/// Who the calls of a connection are charged to: the client's rate limiting key, its API key
+/// or tenant if it has one, and the path it connected to.
+#[derive(Clone)]
+struct Caller {
+    key: String,
+    quota_key: Option<String>,
+    path: String,
+}
+
+async fn serve_socket(
+    socket: WebSocket,
+    state: AppState,
+    router: Arc<JsonRpcRouter>,
+    caller: Caller,
+) {
+    debug!("WebSocket connected");
+    let (mut sink, mut stream) = socket.split();
+    let (outgoing, mut outgoing_rx) = mpsc::channel::<Message>(state.json_rpc.ws_max_in_flight);
+    let writer = tokio::spawn(async move {
+        while let Some(message) = outgoing_rx.recv().await {
+            if sink.send(message).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let session = Arc::new(Session {
+        events: state.events.clone(),
+        outgoing: outgoing.clone(),
+        next_id: AtomicU64::new(1),
+        max_subscriptions: state.json_rpc.ws_max_subscriptions,
+        subscriptions: Mutex::new(HashMap::new()),
+    });
+    let router = Arc::new(session_router(&router, session.clone()));
+    let notifications = tokio::spawn(forward_notifications(
+        state.events.notifications(),
+        outgoing.clone(),
+    ));
+
+    let in_flight = Arc::new(Semaphore::new(state.json_rpc.ws_max_in_flight));
+    let mut calls = JoinSet::new();
+    let shutting_down = loop {
+        let message = tokio::select! {
+            message = stream.next() => message,
+            Some(_) = calls.join_next() => continue,
+            _ = state.shutdown.triggered() => break true,
+        };
+        let message = match message {
+            Some(Ok(Message::Text(text))) => text.as_bytes().to_vec(),
+            Some(Ok(Message::Binary(bytes))) => bytes.to_vec(),
+            // Pings are answered by axum
+            Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+            Some(Ok(Message::Close(_))) | None => break false,
+            Some(Err(e)) => {
+                debug!("WebSocket closed with error: {}", e);
+                break false;
+            }
+        };
+        // Waiting here stops reading, so clients can't queue up more than the limit
+        let Ok(permit) = in_flight.clone().acquire_owned().await else {
+            break false;
+        };
+        let state = state.clone();
+        let router = router.clone();
+        let caller = caller.clone();
+        let outgoing = outgoing.clone();
+        calls.spawn(
+            async move {
+                if let Some(response) = handle_message(&state, &router, &caller, &message).await {
+                    let _ = outgoing
+                        .send(Message::Text(response.to_string().into()))
+                        .await;
+                }
+                drop(permit);
+            }
+            .in_current_span(),
+        );
+    };
+
+    if shutting_down {
+        let deadline = state.shutdown_deadline;
+        let drained = tokio::time::timeout(deadline, async {
+            while calls.join_next().await.is_some() {}
+        })
+        .await
+        .is_ok();
+        if !drained {
+            warn!(
+                "Shutdown deadline of {:?} exceeded, aborting {} WebSocket calls",
+                deadline,
+                calls.len()
+            );
+            calls.abort_all();
+        }
+        let _ = outgoing
+            .send(Message::Close(Some(CloseFrame {
+                code: close_code::AWAY,
+                reason: "Server shutting down".into(),
+            })))
+            .await;
+    } else {
+        calls.abort_all();
+    }
+    notifications.abort();
+    session.unsubscribe_all();
+    drop((router, session, outgoing));
+    let _ = writer.await;
+    debug!("WebSocket disconnected");
+}
+
+/// The response to a message, if it needs one.
+async fn handle_message(
+    state: &AppState,
+    router: &JsonRpcRouter,
+    caller: &Caller,
+    message: &[u8],
+) -> Option<Value> {
+    let payload = match JsonRpcPayload::from_slice(message) {
+        Ok(payload) => payload,
+        Err(response) => return Some(response),
+    };
+
+    let quota_key = caller.quota_key.as_deref();
+    let path = Some(caller.path.as_str());
+    let (response, internal) =
+        super::dispatch(state, router, &caller.key, quota_key, path, 0, payload).await;
+    for internal in &internal {
+        internal_error::log(internal);
+    }
+    response
+}
+
+async fn forward_notifications(
+    mut notifications: broadcast::Receiver<Arc<JsonRpcRequest>>,
+    outgoing: mpsc::Sender<Message>,
+) {
+    loop {
+        let notification = match notifications.recv().await {
+            Ok(notification) => notification,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("WebSocket client missed {} notifications", missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let Ok(notification) = serde_json::to_string(&*notification) else {
+            continue;
+        };
+        if outgoing
+            .send(Message::Text(notification.into()))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// The subscriptions of one connection.
+struct Session {
+    events: Events,
+    outgoing: mpsc::Sender<Message>,
+    next_id: AtomicU64,
+    max_subscriptions: usize,
+    /// The tasks forwarding events, by subscription id.
+    subscriptions: Mutex<HashMap<u64, AbortHandle>>,
+}
+
+impl Session {
+    fn unsubscribe_all(&self) {
+        for (_, task) in self.subscriptions.lock().unwrap().drain() {
+            task.abort();
+        }
+    }
+}
+
+/// `router` plus `subscribe` and `unsubscribe` for `session`.
+fn session_router(router: &JsonRpcRouter, session: Arc<Session>) -> JsonRpcRouter {
+    let taken: Vec<_> = router.method_names().collect();
+    let mut methods = JsonRpcRouter::new();
+    if !taken.contains(&SUBSCRIBE_METHOD) {
+        methods = methods.method_with_schema(SUBSCRIBE_METHOD, subscribe);
+    }
+    if !taken.contains(&UNSUBSCRIBE_METHOD) {
+        methods = methods.method_with_schema(UNSUBSCRIBE_METHOD, unsubscribe);
+    }
+    router.clone().merge(methods.with_state(session))
+}
+
+#[derive(Debug, Deserialize, Validate, JsonSchema)]
+struct SubscribeParams {
+    /// One of the topics the server publishes, see `Events::publish`.
+    topic: String,
+}
+
+/// Subscribes to a topic, returning the id its events are sent with.
+async fn subscribe(
+    State(session): State<Arc<Session>>,
+    Params(params): Params<SubscribeParams>,
+) -> Result<u64, AppError> {
+    // Held throughout, so concurrent calls can't get past the limit together
+    let mut subscriptions = session.subscriptions.lock().unwrap();
+    if subscriptions.len() >= session.max_subscriptions {
+        return Err(AppError::coded(
+            ErrorCodes::LIMIT_REACHED,
+            anyhow::anyhow!(
+                "Already subscribed to {} topics, unsubscribe from one first",
+                subscriptions.len()
+            ),
+        ));
+    }
+    let Some(mut events) = session.events.subscribe(&params.topic) else {
+        return Err(AppError::coded(
+            ErrorCodes::NOT_FOUND,
+            anyhow::anyhow!("No topic called {}", params.topic),
+        ));
+    };
+    let id = session.next_id.fetch_add(1, Ordering::Relaxed);
+    let outgoing = session.outgoing.clone();
+    let topic = params.topic;
+    let task = tokio::spawn(
+        async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Subscription to {} missed {} events", topic, missed);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                let notification = JsonRpcRequest {
+                    jsonrpc: JSONRPC_VERSION.to_string(),
+                    method: SUBSCRIPTION_METHOD.to_string(),
+                    params: json!({ "subscription": id, "result": event }),
+                    id: None,
+                };
+                let Ok(notification) = serde_json::to_string(&notification) else {
+                    continue;
+                };
+                if outgoing
+                    .send(Message::Text(notification.into()))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+        .in_current_span(),
+    );
+    subscriptions.insert(id, task.abort_handle());
+    Ok(id)
+}
+
+#[derive(Debug, Deserialize, Validate, JsonSchema)]
+struct UnsubscribeParams {
+    /// The id `subscribe` returned.
+    subscription: u64,
+}
+
+/// Stops a subscription, returning whether there was one with that id.
+async fn unsubscribe(
+    State(session): State<Arc<Session>>,
+    Params(params): Params<UnsubscribeParams>,
+) -> Result<bool, AppError> {
+    let task = session
+        .subscriptions
+        .lock()
+        .unwrap()
+        .remove(&params.subscription);
+    Ok(task.map(|task| task.abort()).is_some())
+}
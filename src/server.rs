@@ -0,0 +1,633 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    error_handling::HandleErrorLayer,
+    extract::{ConnectInfo, DefaultBodyLimit, Request},
+    http::HeaderMap,
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, Route},
+    Extension,
+};
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+use tonic::{body::Body, server::NamedService, service::Routes};
+use tower::{
+    buffer::BufferLayer, timeout::error::Elapsed, BoxError, Layer, Service, ServiceBuilder,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::client_ip::{self, TrustedProxies};
+use crate::config::ServerConfig;
+use crate::error_code::{self, ErrorCatalog, ErrorCode, ErrorCodes};
+use crate::grpc::{self, hello_world::helloworld::greeter_server};
+use crate::internal_error::ErrorVerbosity;
+use crate::json_rpc::{self, openrpc, Events, JsonRpcRouter, RpcHandler, JSON_RPC_PATH};
+use crate::local::{self, Framing, Transport};
+use crate::rate_limiter::{
+    self, ip_rate_limiter, quota_limiter, Quotas, RateLimitPolicies, SystemClock, QUOTA_PATH,
+};
+use crate::shutdown::{self, Shutdown};
+use crate::tls::{PeerIdentity, ReloadingTlsAcceptor};
+use crate::{routes, trace_http, AppError, AppState};
+
+/// Slow or stalled handshakes are dropped so they can't tie up connection slots.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The wait between retries of a failing `accept`, doubling from 10ms up to a second like in
+/// hyper's and axum's `serve`. Errors like running out of file descriptors don't clear up on
+/// their own, so retrying straight away would only spin and flood the logs.
+#[derive(Default)]
+struct AcceptBackoff(Option<Duration>);
+
+impl AcceptBackoff {
+    const MIN: Duration = Duration::from_millis(10);
+    const MAX: Duration = Duration::from_secs(1);
+
+    fn next(&mut self) -> Duration {
+        let delay = self.0.map_or(Self::MIN, |delay| (delay * 2).min(Self::MAX));
+        self.0 = Some(delay);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.0 = None;
+    }
+}
+
+type RouterLayer = Box<dyn FnOnce(axum::Router) -> axum::Router + Send>;
+
+/// Composes the HTTP, gRPC and JSON-RPC services into one router.
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use rust_http_template::{config::ServerConfig, ServerBuilder};
+///
+/// let config = ServerConfig::load(None).unwrap();
+/// let listener = tokio::net::TcpListener::bind(&config.http_addr).await?;
+/// ServerBuilder::new(config)
+///     .with_default_services()
+///     .merge(axum::Router::new().route("/ping", axum::routing::get(|| async { "pong" })))
+///     .serve(listener)
+///     .await
+/// # }
+/// ```
+pub struct ServerBuilder {
+    config: ServerConfig,
+    trusted_proxies: Arc<TrustedProxies>,
+    app_routes: axum::Router<AppState>,
+    routes: axum::Router,
+    grpc: Option<Routes>,
+    /// JSON-RPC routers by the path they are served on.
+    rpc_routers: BTreeMap<String, JsonRpcRouter>,
+    layers: Vec<RouterLayer>,
+    shutdown: Shutdown,
+    rate_limits: Arc<RateLimitPolicies>,
+    error_codes: Vec<ErrorCode>,
+    events: Events,
+}
+
+impl ServerBuilder {
+    /// Creates a builder with no routes other than `/json_rpc`, which dispatches to whatever
+    /// methods get registered with `rpc_method`.
+    ///
+    /// # Panics
+    ///
+    /// If `config.trusted_proxies` holds something other than an address or CIDR, which
+    /// `ServerConfig::load` rejects.
+    pub fn new(config: ServerConfig) -> Self {
+        let trusted_proxies = match TrustedProxies::parse(&config.trusted_proxies) {
+            Ok(trusted_proxies) => Arc::new(trusted_proxies),
+            Err(e) => panic!("invalid trusted_proxies: {}", e),
+        };
+        let rate_limits = Arc::new(RateLimitPolicies::from_config(
+            &config.rate_limit,
+            Arc::new(SystemClock),
+        ));
+        let events = Events::new(config.json_rpc.ws_event_buffer);
+        Self {
+            config,
+            trusted_proxies,
+            app_routes: axum::Router::new(),
+            routes: axum::Router::new(),
+            grpc: None,
+            rpc_routers: BTreeMap::from([(JSON_RPC_PATH.to_string(), JsonRpcRouter::new())]),
+            layers: Vec::new(),
+            shutdown: Shutdown::default(),
+            rate_limits,
+            error_codes: ErrorCodes::CODES.to_vec(),
+            events,
+        }
+    }
+
+    /// Handle for triggering shutdown programmatically or checking what is still in flight.
+    /// `serve` also triggers it on SIGINT and SIGTERM.
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Handle for pushing notifications and topic events to JSON-RPC WebSocket clients.
+    pub fn events(&self) -> Events {
+        self.events.clone()
+    }
+
+    /// Lets JSON-RPC WebSocket clients subscribe to `topic`, whose events are published with
+    /// `Events::publish`.
+    pub fn topic(self, topic: &str) -> Self {
+        self.events.add_topic(topic);
+        self
+    }
+
+    /// Adds the example routes, gRPC greeter and JSON-RPC methods that ship with the template.
+    pub fn with_default_services(mut self) -> Self {
+        self.app_routes = self.app_routes.merge(routes::router());
+        self = self.json_rpc_router(JSON_RPC_PATH, routes::rpc_router());
+        let max_decoding_message_size = self.config.grpc_max_decoding_message_size;
+        self.grpc_service(
+            greeter_server::GreeterServer::new(grpc::hello_world::MyGreeter::default())
+                .max_decoding_message_size(max_decoding_message_size),
+        )
+    }
+
+    /// Merges an axum router. It must already have its state applied with `with_state`.
+    pub fn merge(mut self, router: axum::Router) -> Self {
+        self.routes = self.routes.merge(router);
+        self
+    }
+
+    /// Nests an axum router under `path`. It must already have its state applied with `with_state`.
+    pub fn nest(mut self, path: &str, router: axum::Router) -> Self {
+        self.routes = self.routes.nest(path, router);
+        self
+    }
+
+    /// Adds a tonic service, routed by its fully qualified service name.
+    pub fn grpc_service<S>(mut self, svc: S) -> Self
+    where
+        S: Service<axum::http::Request<Body>, Error = Infallible>
+            + NamedService
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        S::Response: IntoResponse,
+        S::Future: Send + 'static,
+    {
+        self.grpc = Some(self.grpc.take().unwrap_or_default().add_service(svc));
+        self
+    }
+
+    /// Registers a JSON-RPC method served from `/json_rpc`. Methods that need state are
+    /// registered on a `JsonRpcRouter` instead, see `json_rpc_router`.
+    ///
+    /// # Panics
+    ///
+    /// If a method called `name` is already registered.
+    pub fn rpc_method<H, T>(self, name: &str, handler: H) -> Self
+    where
+        H: RpcHandler<T, ()>,
+    {
+        self.json_rpc_router(JSON_RPC_PATH, JsonRpcRouter::new().method(name, handler))
+    }
+
+    /// Serves the methods of `router` from `path`, alongside any already served from there.
+    ///
+    /// # Panics
+    ///
+    /// If a method of `router` is already served from `path`.
+    pub fn json_rpc_router(mut self, path: &str, router: JsonRpcRouter) -> Self {
+        let mounted = self.rpc_routers.remove(path).unwrap_or_default();
+        self.rpc_routers
+            .insert(path.to_string(), mounted.merge(router));
+        self
+    }
+
+    /// Adds the codes of `C` to the catalog served at `/errors`.
+    ///
+    /// # Panics
+    ///
+    /// If a code is already registered with a different definition.
+    pub fn error_codes<C: ErrorCatalog>(mut self) -> Self {
+        for code in C::CODES {
+            match self
+                .error_codes
+                .iter()
+                .find(|known| known.code == code.code)
+            {
+                Some(known) if known == code => {}
+                Some(_) => panic!("error code {} is registered twice", code.code),
+                None => self.error_codes.push(*code),
+            }
+        }
+        self
+    }
+
+    /// Makes `value` available to every handler through the `Extension<T>` extractor.
+    pub fn extension<T>(self, value: T) -> Self
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        self.layer(Extension(value))
+    }
+
+    /// Wraps every route with `layer`. Layers run inside the built-in tracing, timeout and
+    /// rate limiting stack, in the order they were added (the last one added runs first).
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<Route> + Clone + Send + Sync + 'static,
+        L::Service: Service<Request> + Clone + Send + Sync + 'static,
+        <L::Service as Service<Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request>>::Future: Send + 'static,
+    {
+        self.layers
+            .push(Box::new(move |router| router.layer(layer)));
+        self
+    }
+
+    /// Builds the fully layered router without binding anything, handy for tests.
+    /// Expired rate limiter state is only swept while running `serve`, `serve_unix` or
+    /// `serve_stdio`.
+    pub fn build(self) -> axum::Router {
+        let state = self.app_state();
+        let config = self.config;
+
+        let mut app_routes = self.app_routes.route(
+            error_code::ERRORS_PATH,
+            get(error_code::catalog).with_state(Arc::new(self.error_codes)),
+        );
+        if state.quotas.is_some() {
+            app_routes = app_routes.route(QUOTA_PATH, get(rate_limiter::quota_usage));
+        }
+
+        // Each path also serves its methods' OpenRPC document on GET, and the methods
+        // themselves over WebSocket
+        for (path, router) in self.rpc_routers {
+            let router = Extension(Arc::new(router));
+            app_routes = app_routes
+                .route(
+                    &path,
+                    get(openrpc::document)
+                        .post(routes::json_rpc)
+                        .layer(router.clone()),
+                )
+                .route(
+                    &json_rpc::ws_path(&path),
+                    get(routes::json_rpc_ws).layer(router),
+                );
+        }
+        let mut app = app_routes.with_state(state.clone()).merge(self.routes);
+        if let Some(grpc) = self.grpc {
+            app = app.merge(grpc.prepare().into_axum_router());
+        }
+        for layer in self.layers {
+            app = layer(app);
+        }
+
+        app.layer(
+            ServiceBuilder::new()
+                .layer(middleware::from_fn_with_state(
+                    self.shutdown.clone(),
+                    shutdown::track_in_flight,
+                ))
+                .layer(middleware::from_fn_with_state(
+                    self.trusted_proxies,
+                    client_ip::resolve_client_ip,
+                ))
+                .layer(Extension(ErrorVerbosity {
+                    verbose: config.dev_mode,
+                }))
+                .layer(middleware::from_fn(trace_http))
+                // https://github.com/tokio-rs/axum/discussions/987
+                .layer(HandleErrorLayer::new(handle_layer_error))
+                .layer(BufferLayer::new(config.buffer_capacity))
+                .layer(DefaultBodyLimit::max(config.body_limit))
+                // also see https://docs.rs/tower-http/0.6.1/tower_http/request_id/index.html#example
+                .layer(tower::timeout::TimeoutLayer::new(config.request_timeout()))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    ip_rate_limiter,
+                ))
+                .layer(middleware::from_fn_with_state(state, quota_limiter)),
+        )
+        // Added after the layers so probes and scrapes are never rate limited or buffered
+        .route("/readyz", get(shutdown::readyz).with_state(self.shutdown))
+        .route(
+            "/metrics",
+            get(rate_limiter::metrics).with_state(self.rate_limits),
+        )
+    }
+
+    /// Serves the JSON-RPC methods of `/json_rpc` over stdin and stdout until stdin closes or
+    /// shutdown is triggered, for local tools and editors. On shutdown calls in flight get
+    /// until the shutdown deadline to finish. Nothing else may write to stdout, so logs have to
+    /// go to stderr.
+    pub async fn serve_stdio(self, framing: Framing) -> std::io::Result<()> {
+        spawn_signal_handler(self.shutdown.clone());
+        let sweeper = self.spawn_sweeper();
+        let transport = self.local_transport("stdio", framing);
+        info!("Serving JSON-RPC on stdio");
+        let result = local::serve_stream(tokio::io::stdin(), tokio::io::stdout(), transport).await;
+        if let Some(sweeper) = sweeper {
+            sweeper.abort();
+        }
+        result
+    }
+
+    /// Serves the JSON-RPC methods of `/json_rpc` on every connection to `listener` until
+    /// shutdown is triggered, for local tools and editors. Calls in flight then get until the
+    /// shutdown deadline to finish.
+    pub async fn serve_unix(
+        self,
+        listener: tokio::net::UnixListener,
+        framing: Framing,
+    ) -> std::io::Result<()> {
+        let shutdown = self.shutdown.clone();
+        let deadline = self.config.shutdown.deadline();
+        spawn_signal_handler(shutdown.clone());
+        let sweeper = self.spawn_sweeper();
+
+        info!("Serving JSON-RPC on {:?}", listener.local_addr()?);
+        let transport = self.local_transport("unix", framing);
+        let mut connections = JoinSet::new();
+        let mut backoff = AcceptBackoff::default();
+        loop {
+            let stream = tokio::select! {
+                conn = listener.accept() => match conn {
+                    Ok((stream, _)) => {
+                        backoff.reset();
+                        stream
+                    }
+                    Err(e) => {
+                        let delay = backoff.next();
+                        error!("Failed to accept connection, retrying in {:?}: {}", delay, e);
+                        tokio::select! {
+                            _ = tokio::time::sleep(delay) => continue,
+                            _ = shutdown.triggered() => break,
+                        }
+                    }
+                },
+                _ = shutdown.triggered() => break,
+            };
+            let transport = transport.clone();
+            connections.spawn(async move {
+                let (reader, writer) = stream.into_split();
+                if let Err(e) = local::serve_stream(reader, writer, transport).await {
+                    debug!("Unix socket connection closed with error: {}", e);
+                }
+            });
+            // Reap finished connections so the set doesn't grow forever
+            while connections.try_join_next().is_some() {}
+        }
+        drop(listener);
+        if let Some(sweeper) = sweeper {
+            sweeper.abort();
+        }
+
+        let drained = tokio::time::timeout(deadline, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await
+        .is_ok();
+        if !drained {
+            warn!(
+                "Shutdown deadline of {:?} exceeded, force closing {} connections",
+                deadline,
+                connections.len()
+            );
+            connections.shutdown().await;
+        }
+        Ok(())
+    }
+
+    /// The state every handler gets, shared by all transports.
+    fn app_state(&self) -> AppState {
+        let config = &self.config;
+        AppState {
+            rate_limits: self.rate_limits.clone(),
+            quotas: Quotas::from_config(&config.rate_limit, Arc::new(SystemClock)).map(Arc::new),
+            json_rpc: config.json_rpc.clone(),
+            body_limit: config.body_limit,
+            events: self.events.clone(),
+            shutdown: self.shutdown.clone(),
+            shutdown_deadline: config.shutdown.deadline(),
+        }
+    }
+
+    /// Drops expired rate limiter state every `sweep_interval_secs` while serving, unless
+    /// sweeping is disabled.
+    fn spawn_sweeper(&self) -> Option<JoinHandle<()>> {
+        let interval = self.config.rate_limit.sweep_interval()?;
+        Some(tokio::spawn(rate_limiter::sweep_every(
+            self.rate_limits.clone(),
+            interval,
+        )))
+    }
+
+    fn local_transport(mut self, name: &'static str, framing: Framing) -> Transport {
+        let state = self.app_state();
+        let router = self.rpc_routers.remove(JSON_RPC_PATH).unwrap_or_default();
+        Transport {
+            name,
+            framing,
+            max_size: self.config.body_limit,
+            state,
+            router: Arc::new(router),
+        }
+    }
+
+    /// Builds the router and serves it on `listener` until shutdown is triggered,
+    /// terminating TLS on every connection when `config.tls` is set.
+    ///
+    /// On shutdown `/readyz` flips to 503, the listener keeps accepting for the configured
+    /// drain delay, and then in-flight requests and streams get until the deadline to finish
+    /// before the remaining connections are force-closed.
+    pub async fn serve(self, listener: tokio::net::TcpListener) -> std::io::Result<()> {
+        let shutdown = self.shutdown.clone();
+        let drain_delay = self.config.shutdown.drain_delay();
+        let deadline = self.config.shutdown.deadline();
+
+        spawn_signal_handler(shutdown.clone());
+
+        let tls = match self.config.tls.clone() {
+            Some(tls_config) => Some(Arc::new(
+                ReloadingTlsAcceptor::new(tls_config)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+            )),
+            None => None,
+        };
+        let reload = tls.clone().map(|tls| tokio::spawn(tls.watch()));
+        let sweeper = self.spawn_sweeper();
+
+        info!(
+            "Starting on {}{}",
+            listener.local_addr()?,
+            if tls.is_some() { " with TLS" } else { "" }
+        );
+        let app = self.build();
+        // Owning the connection tasks lets us abort them once the deadline passes
+        let mut connections = JoinSet::new();
+        let (stop_tx, stop_rx) = watch::channel(false);
+
+        let stop_accepting = async {
+            shutdown.triggered().await;
+            tokio::time::sleep(drain_delay).await;
+        };
+        tokio::pin!(stop_accepting);
+
+        let mut backoff = AcceptBackoff::default();
+        loop {
+            let (stream, remote_addr) = tokio::select! {
+                conn = listener.accept() => match conn {
+                    Ok(conn) => {
+                        backoff.reset();
+                        conn
+                    }
+                    Err(e) => {
+                        let delay = backoff.next();
+                        error!("Failed to accept connection, retrying in {:?}: {}", delay, e);
+                        tokio::select! {
+                            _ = tokio::time::sleep(delay) => continue,
+                            _ = &mut stop_accepting => break,
+                        }
+                    }
+                },
+                _ = &mut stop_accepting => break,
+            };
+
+            let app = app.clone();
+            let tls = tls.clone();
+            let stop = stop_rx.clone();
+            connections.spawn(async move {
+                let res = match tls {
+                    Some(tls) => {
+                        let handshake = tls.acceptor().accept(stream);
+                        let stream =
+                            match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, handshake).await {
+                                Ok(Ok(stream)) => stream,
+                                Ok(Err(e)) => {
+                                    debug!("TLS handshake with {} failed: {}", remote_addr, e);
+                                    return;
+                                }
+                                Err(_) => {
+                                    debug!("TLS handshake with {} timed out", remote_addr);
+                                    return;
+                                }
+                            };
+                        let peer = PeerIdentity::from_stream(&stream);
+                        serve_connection(stream, remote_addr, app, peer, stop).await
+                    }
+                    None => serve_connection(stream, remote_addr, app, None, stop).await,
+                };
+                if let Err(e) = res {
+                    debug!("Connection from {} closed with error: {}", remote_addr, e);
+                }
+            });
+            // Reap finished connections so the set doesn't grow forever
+            while connections.try_join_next().is_some() {}
+        }
+        drop(listener);
+        stop_tx.send_replace(true);
+        for task in [reload, sweeper].into_iter().flatten() {
+            task.abort();
+        }
+
+        info!(
+            "Stopped accepting connections, waiting up to {:?} for {} in-flight requests",
+            deadline,
+            shutdown.in_flight().len()
+        );
+        let drained = tokio::time::timeout(deadline, async {
+            while connections.join_next().await.is_some() {}
+            // Upgraded connections, like WebSockets, outlive the connection task
+            shutdown.join_tasks().await;
+        })
+        .await
+        .is_ok();
+
+        if drained {
+            info!("Shutdown complete");
+        } else {
+            let cut_off = shutdown.in_flight();
+            warn!(
+                "Shutdown deadline of {:?} exceeded, force closing {} in-flight requests: {:?}",
+                deadline,
+                cut_off.len(),
+                cut_off
+            );
+            connections.shutdown().await;
+            shutdown.abort_tasks().await;
+        }
+        Ok(())
+    }
+}
+
+/// Triggers `shutdown` on SIGINT or SIGTERM.
+fn spawn_signal_handler(shutdown: Shutdown) {
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = shutdown::wait_for_signal() => {
+                info!("Received shutdown signal");
+                shutdown.trigger();
+            },
+            _ = shutdown.triggered() => {},
+        }
+    });
+}
+
+/// Turns errors from the timeout and buffer layers into a `TIMEOUT` error, or an internal one
+/// that only `trace_http` gets to see the details of. gRPC calls get them as a status.
+async fn handle_layer_error(headers: HeaderMap, err: BoxError) -> Response {
+    let error = if err.is::<Elapsed>() {
+        warn!("Request timed out");
+        AppError::coded(ErrorCodes::TIMEOUT, anyhow::anyhow!("Request timed out"))
+    } else {
+        AppError::Anyhow(anyhow::anyhow!(err).context("Unhandled error"))
+    };
+    if grpc::status::is_grpc(&headers) {
+        return grpc::status::into_response(error.into());
+    }
+    error.into_response()
+}
+
+/// Serves HTTP/1 and HTTP/2 on a single connection until it closes, or gracefully closes it
+/// once `stop` flips to true. Every request gets the connection's `ConnectInfo` and, for
+/// mTLS, its `PeerIdentity`.
+async fn serve_connection<I>(
+    io: I,
+    remote_addr: SocketAddr,
+    app: axum::Router,
+    peer: Option<PeerIdentity>,
+    mut stop: watch::Receiver<bool>,
+) -> Result<(), BoxError>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req: axum::http::Request<Incoming>| {
+        let mut req = req.map(axum::body::Body::new);
+        req.extensions_mut().insert(ConnectInfo(remote_addr));
+        if let Some(peer) = &peer {
+            req.extensions_mut().insert(peer.clone());
+        }
+        app.clone().call(req)
+    });
+
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let conn = builder.serve_connection_with_upgrades(TokioIo::new(io), service);
+    tokio::pin!(conn);
+
+    tokio::select! {
+        res = conn.as_mut() => return res,
+        _ = stop.wait_for(|stop| *stop) => conn.as_mut().graceful_shutdown(),
+    }
+    conn.await
+}
@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use axum::body::{Body, Bytes};
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http_body::{Frame, SizeHint};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+use crate::grpc;
+
+/// Coordinates shutdown between the signal handlers, the readiness probe and the server.
+/// Cloning is cheap, every clone refers to the same shutdown state.
+#[derive(Clone)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    triggered: watch::Sender<bool>,
+    next_id: AtomicU64,
+    in_flight: Mutex<HashMap<u64, InFlight>>,
+    /// Work started with `spawn`, which `serve` waits for along with its connections.
+    tasks: Mutex<JoinSet<()>>,
+}
+
+#[derive(Debug, Clone)]
+struct InFlight {
+    kind: &'static str,
+    method: String,
+    path: String,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                triggered: watch::Sender::new(false),
+                next_id: AtomicU64::new(0),
+                in_flight: Mutex::new(HashMap::new()),
+                tasks: Mutex::new(JoinSet::new()),
+            }),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Marks the service not-ready and starts draining. Calling this more than once is a no-op.
+    pub fn trigger(&self) {
+        self.inner.triggered.send_replace(true);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        !*self.inner.triggered.borrow()
+    }
+
+    /// Resolves once `trigger` has been called.
+    pub async fn triggered(&self) {
+        let mut rx = self.inner.triggered.subscribe();
+        // The sender lives in `self`, so this can't fail
+        let _ = rx.wait_for(|triggered| *triggered).await;
+    }
+
+    /// Requests (including SSE and gRPC streams) whose response body has not finished yet, and
+    /// WebSocket sessions still open, formatted like `SSE GET /sse`.
+    pub fn in_flight(&self) -> Vec<String> {
+        self.inner
+            .in_flight
+            .lock()
+            .unwrap()
+            .values()
+            .map(|r| format!("{} {} {}", r.kind, r.method, r.path))
+            .collect()
+    }
+
+    /// Runs `task`, work that outlives the request that started it like an upgraded WebSocket,
+    /// as in flight until it is done. `serve` waits for it on shutdown, until the deadline.
+    pub(crate) fn spawn<F>(&self, kind: &'static str, method: &str, path: &str, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let guard = self.track_as(InFlight {
+            kind,
+            method: method.to_string(),
+            path: path.to_string(),
+        });
+        let mut tasks = self.inner.tasks.lock().unwrap();
+        tasks.spawn(async move {
+            task.await;
+            drop(guard);
+        });
+        // Reap finished tasks so the set doesn't grow forever
+        while tasks.try_join_next().is_some() {}
+    }
+
+    /// Waits for the tasks started with `spawn`, including any started in the meantime.
+    /// Dropping the future aborts the ones it was waiting for.
+    pub(crate) async fn join_tasks(&self) {
+        loop {
+            let mut tasks = std::mem::take(&mut *self.inner.tasks.lock().unwrap());
+            if tasks.is_empty() {
+                return;
+            }
+            while tasks.join_next().await.is_some() {}
+        }
+    }
+
+    /// Aborts the tasks started with `spawn` and waits for them to stop.
+    pub(crate) async fn abort_tasks(&self) {
+        let mut tasks = std::mem::take(&mut *self.inner.tasks.lock().unwrap());
+        tasks.shutdown().await;
+    }
+
+    fn track(&self, req: &Request) -> InFlightGuard {
+        let kind = if grpc::status::is_grpc(req.headers()) {
+            "gRPC"
+        } else {
+            "HTTP"
+        };
+        self.track_as(InFlight {
+            kind,
+            method: req.method().to_string(),
+            path: req.uri().path().to_string(),
+        })
+    }
+
+    fn track_as(&self, in_flight: InFlight) -> InFlightGuard {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        self.inner.in_flight.lock().unwrap().insert(id, in_flight);
+        InFlightGuard {
+            shutdown: self.clone(),
+            id,
+        }
+    }
+}
+
+/// Resolves on SIGINT (Ctrl+C) or SIGTERM, whichever comes first.
+pub async fn wait_for_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Readiness probe, flips to 503 as soon as shutdown is triggered so load balancers stop
+/// sending new traffic while we drain.
+pub(crate) async fn readyz(State(shutdown): State<Shutdown>) -> impl IntoResponse {
+    if shutdown.is_ready() {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "shutting down")
+    }
+}
+
+/// This middleware tracks every request until its response body is done, so streams are
+/// counted as in-flight for as long as they are open.
+pub(crate) async fn track_in_flight(
+    State(shutdown): State<Shutdown>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let mut guard = shutdown.track(&req);
+    let response = next.run(req).await;
+
+    let is_sse = response
+        .headers()
+        .get("content-type")
+        .is_some_and(|v| v.as_bytes().starts_with(b"text/event-stream"));
+    if is_sse {
+        guard.set_kind("SSE");
+    }
+
+    let (parts, body) = response.into_parts();
+    Response::from_parts(
+        parts,
+        Body::new(TrackedBody {
+            inner: body,
+            _guard: guard,
+        }),
+    )
+}
+
+/// Removes its request from the in-flight set when dropped.
+struct InFlightGuard {
+    shutdown: Shutdown,
+    id: u64,
+}
+
+impl InFlightGuard {
+    fn set_kind(&mut self, kind: &'static str) {
+        if let Some(r) = self
+            .shutdown
+            .inner
+            .in_flight
+            .lock()
+            .unwrap()
+            .get_mut(&self.id)
+        {
+            r.kind = kind;
+        }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.shutdown
+            .inner
+            .in_flight
+            .lock()
+            .unwrap()
+            .remove(&self.id);
+    }
+}
+
+/// Response body that holds an `InFlightGuard` until it is finished or dropped.
+struct TrackedBody {
+    inner: Body,
+    _guard: InFlightGuard,
+}
+
+impl http_body::Body for TrackedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
@@ -0,0 +1,198 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::RootCertStore;
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info};
+
+use crate::config::TlsConfig;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("failed to read {path}: {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("no certificates found in {0}")]
+    NoCertificates(String),
+    #[error("no private key found in {0}")]
+    NoPrivateKey(String),
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+    #[error(transparent)]
+    ClientVerifier(#[from] rustls::server::VerifierBuilderError),
+}
+
+/// The verified client certificate of an mTLS connection. Handlers can extract it with
+/// `Extension<PeerIdentity>` and gRPC services can read it from `request.extensions()`.
+/// Connections without a client certificate don't get one.
+#[derive(Debug, Clone)]
+pub struct PeerIdentity {
+    pub common_name: Option<String>,
+    pub dns_names: Vec<String>,
+    /// The presented chain, leaf first.
+    pub certificates: Vec<CertificateDer<'static>>,
+}
+
+impl PeerIdentity {
+    pub(crate) fn from_stream(stream: &TlsStream<TcpStream>) -> Option<Self> {
+        let certificates = stream.get_ref().1.peer_certificates()?.to_vec();
+        let (_, leaf) = x509_parser::parse_x509_certificate(certificates.first()?).ok()?;
+
+        let common_name = leaf
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(|cn| cn.to_string());
+        let dns_names = match leaf.subject_alternative_name() {
+            Ok(Some(san)) => san
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    x509_parser::extensions::GeneralName::DNSName(dns) => Some(dns.to_string()),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Some(Self {
+            common_name,
+            dns_names,
+            certificates,
+        })
+    }
+}
+
+/// Holds the current rustls config and swaps it out when the files on disk change,
+/// so rotated certificates are picked up by new connections without a restart.
+pub(crate) struct ReloadingTlsAcceptor {
+    config: TlsConfig,
+    current: RwLock<Arc<rustls::ServerConfig>>,
+}
+
+impl ReloadingTlsAcceptor {
+    pub(crate) fn new(config: TlsConfig) -> Result<Self, TlsError> {
+        let server_config = load_server_config(&config)?;
+        Ok(Self {
+            config,
+            current: RwLock::new(server_config),
+        })
+    }
+
+    pub(crate) fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.read().unwrap().clone())
+    }
+
+    /// Polls the certificate files and reloads them when their modification time changes.
+    /// A bad reload is logged and the previous config is kept, then retried on the next tick
+    /// since the cert and key are often not replaced at the same instant.
+    pub(crate) async fn watch(self: Arc<Self>) {
+        let Some(interval) = self.config.reload_interval() else {
+            return;
+        };
+        let mut last_modified = self.modified();
+        loop {
+            tokio::time::sleep(interval).await;
+            let modified = self.modified();
+            if modified == last_modified {
+                continue;
+            }
+
+            match load_server_config(&self.config) {
+                Ok(server_config) => {
+                    *self.current.write().unwrap() = server_config;
+                    last_modified = modified;
+                    info!("Reloaded TLS certificates");
+                }
+                Err(e) => error!(
+                    "Failed to reload TLS certificates, keeping the old ones: {}",
+                    e
+                ),
+            }
+        }
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [
+            Some(&self.config.cert_path),
+            Some(&self.config.key_path),
+            self.config.client_ca_path.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+    }
+}
+
+fn load_server_config(config: &TlsConfig) -> Result<Arc<rustls::ServerConfig>, TlsError> {
+    // Explicit provider so we don't depend on which crypto features other crates enable
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match &config.client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if config.client_auth_optional {
+                verifier.allow_unauthenticated().build()?
+            } else {
+                verifier.build()?
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config = builder.with_single_cert(
+        load_certs(&config.cert_path)?,
+        load_private_key(&config.key_path)?,
+    )?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(server_config))
+}
+
+fn open(path: &str) -> Result<BufReader<File>, TlsError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|source| TlsError::Io {
+            path: path.to_string(),
+            source,
+        })
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|source| TlsError::Io {
+            path: path.to_string(),
+            source,
+        })?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(path.to_string()));
+    }
+    Ok(certs)
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKeyDer<'static>, TlsError> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .map_err(|source| TlsError::Io {
+            path: path.to_string(),
+            source,
+        })?
+        .ok_or_else(|| TlsError::NoPrivateKey(path.to_string()))
+}
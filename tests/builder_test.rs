@@ -0,0 +1,100 @@
+use axum::{extract::Request, middleware::Next, response::Response, routing::get, Extension};
+use reqwest::Client;
+use rust_http_template::config::ServerConfig;
+use rust_http_template::json_rpc::Params;
+use rust_http_template::ServerBuilder;
+use serde::Deserialize;
+use serde_json::json;
+use validator::Validate;
+
+mod common;
+
+#[derive(Clone)]
+struct Greeting(&'static str);
+
+#[derive(Deserialize, Validate)]
+struct AddParams {
+    a: i64,
+    b: i64,
+}
+
+async fn add(Params(params): Params<AddParams>) -> Result<i64, anyhow::Error> {
+    Ok(params.a + params.b)
+}
+
+async fn add_header(req: Request, next: Next) -> Response {
+    let mut res = next.run(req).await;
+    res.headers_mut()
+        .insert("x-custom-layer", "applied".parse().unwrap());
+    res
+}
+
+#[tokio::test]
+async fn test_builder_custom_services() {
+    let base_url = common::spawn(
+        ServerBuilder::new(ServerConfig::default())
+            .merge(axum::Router::new().route(
+                "/greeting",
+                get(|Extension(greeting): Extension<Greeting>| async move { greeting.0 }),
+            ))
+            .extension(Greeting("howdy"))
+            .layer(axum::middleware::from_fn(add_header))
+            .rpc_method("add", add),
+    )
+    .await;
+    let client = Client::new();
+
+    let response = client
+        .get(format!("{}/greeting", base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("x-custom-layer").unwrap(), "applied");
+    assert_eq!(response.text().await.unwrap(), "howdy");
+
+    let response = client
+        .post(format!("{}/json_rpc", base_url))
+        .json(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "add",
+            "params": { "a": 2, "b": 3 }
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.json::<serde_json::Value>().await.unwrap(),
+        json!({ "jsonrpc": "2.0", "id": 1, "result": 5 })
+    );
+
+    // Default services were not requested
+    let response = client
+        .post(format!("{}/echo/json", base_url))
+        .json(&json!({}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+}
+
+#[tokio::test]
+async fn test_builder_default_services() {
+    let base_url =
+        common::spawn(ServerBuilder::new(ServerConfig::default()).with_default_services()).await;
+
+    let test_json = json!({ "message": "hello" });
+    let response = Client::new()
+        .post(format!("{}/echo/json", base_url))
+        .json(&test_json)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.json::<serde_json::Value>().await.unwrap(),
+        test_json
+    );
+}
@@ -0,0 +1,140 @@
+use axum::routing::get;
+use reqwest::Client;
+use rust_http_template::client_ip::ClientIp;
+use rust_http_template::config::ServerConfig;
+use rust_http_template::ServerBuilder;
+
+mod common;
+
+async fn client_ip(ClientIp(ip): ClientIp) -> String {
+    ip.to_string()
+}
+
+/// Serves `/client_ip` on a random local port and returns its URL.
+async fn spawn(trusted_proxies: &[&str]) -> String {
+    let config = ServerConfig {
+        trusted_proxies: trusted_proxies.iter().map(|p| p.to_string()).collect(),
+        ..Default::default()
+    };
+    let builder =
+        ServerBuilder::new(config).merge(axum::Router::new().route("/client_ip", get(client_ip)));
+    format!("{}/client_ip", common::spawn(builder).await)
+}
+
+async fn resolve(url: &str, headers: &[(&str, &str)]) -> String {
+    let mut request = Client::new().get(url);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request.send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    response.text().await.unwrap()
+}
+
+#[tokio::test]
+async fn test_client_ip_untrusted_peer_ignores_headers() {
+    let url = spawn(&[]).await;
+
+    assert_eq!(resolve(&url, &[]).await, "127.0.0.1");
+    assert_eq!(
+        resolve(&url, &[("x-forwarded-for", "203.0.113.7")]).await,
+        "127.0.0.1"
+    );
+    assert_eq!(
+        resolve(&url, &[("forwarded", "for=203.0.113.7")]).await,
+        "127.0.0.1"
+    );
+}
+
+#[tokio::test]
+async fn test_client_ip_trusted_proxy_chain() {
+    let url = spawn(&["127.0.0.1", "10.0.0.0/8"]).await;
+
+    assert_eq!(
+        resolve(&url, &[("x-forwarded-for", "203.0.113.7, 10.0.0.5")]).await,
+        "203.0.113.7"
+    );
+    // Whatever the client prepends is ignored, the right-most untrusted hop wins
+    assert_eq!(
+        resolve(
+            &url,
+            &[("x-forwarded-for", "1.1.1.1, 203.0.113.7, 10.0.0.5")]
+        )
+        .await,
+        "203.0.113.7"
+    );
+    // Multiple header lines are one list
+    assert_eq!(
+        resolve(
+            &url,
+            &[
+                ("x-forwarded-for", "198.51.100.1"),
+                ("x-forwarded-for", "10.0.0.5")
+            ]
+        )
+        .await,
+        "198.51.100.1"
+    );
+    // Every hop is trusted, so the left-most one is as far as we can see
+    assert_eq!(
+        resolve(&url, &[("x-forwarded-for", "10.0.0.1, 10.0.0.2")]).await,
+        "10.0.0.1"
+    );
+    // An unusable hop stops the walk at the last trusted proxy
+    assert_eq!(
+        resolve(
+            &url,
+            &[("x-forwarded-for", "203.0.113.7, garbage, 10.0.0.5")]
+        )
+        .await,
+        "10.0.0.5"
+    );
+    assert_eq!(
+        resolve(&url, &[("x-real-ip", "203.0.113.9")]).await,
+        "203.0.113.9"
+    );
+}
+
+#[tokio::test]
+async fn test_client_ip_forwarded_header() {
+    let url = spawn(&["127.0.0.1/32", "10.0.0.0/8"]).await;
+
+    assert_eq!(
+        resolve(
+            &url,
+            &[(
+                "forwarded",
+                r#"for="[2001:db8:cafe::17]:4711";proto=https, for=10.0.0.5;by=10.0.0.6"#
+            )]
+        )
+        .await,
+        "2001:db8:cafe::17"
+    );
+    assert_eq!(
+        resolve(&url, &[("forwarded", "for=unknown, for=10.0.0.5")]).await,
+        "10.0.0.5"
+    );
+    // Forwarded takes precedence over X-Forwarded-For
+    assert_eq!(
+        resolve(
+            &url,
+            &[
+                ("forwarded", "for=192.0.2.60:8080"),
+                ("x-forwarded-for", "203.0.113.7")
+            ]
+        )
+        .await,
+        "192.0.2.60"
+    );
+}
+
+#[test]
+#[should_panic(expected = "invalid trusted_proxies: invalid proxy address or CIDR: 10.0.0.0/33")]
+fn test_invalid_trusted_proxies_panic_up_front() {
+    let config = ServerConfig {
+        trusted_proxies: vec!["10.0.0.0/33".to_string()],
+        ..Default::default()
+    };
+    // Before anything is built or served
+    let _ = ServerBuilder::new(config);
+}
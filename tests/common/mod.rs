@@ -0,0 +1,22 @@
+//! Helpers shared by the integration tests, pulled in with `mod common;`.
+// Each test file is its own crate, and not every one of them uses every helper
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+
+use rust_http_template::ServerBuilder;
+use tokio::task::JoinHandle;
+
+/// Serves `builder` on a free local port, returning its base URL like `http://127.0.0.1:1234`.
+pub async fn spawn(builder: ServerBuilder) -> String {
+    let (addr, _) = serve(builder).await;
+    format!("http://{}", addr)
+}
+
+/// Like `spawn`, but returns the address and the task running `ServerBuilder::serve`, for
+/// tests that need to know when the server has stopped.
+pub async fn serve(builder: ServerBuilder) -> (SocketAddr, JoinHandle<std::io::Result<()>>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    (addr, tokio::spawn(builder.serve(listener)))
+}
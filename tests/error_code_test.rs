@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+use axum::response::IntoResponse;
+use axum::routing::get;
+use rust_http_template::config::ServerConfig;
+use rust_http_template::error_code::{ErrorCatalog, ErrorCodes};
+use rust_http_template::problem::Problem;
+use rust_http_template::{error_codes, AppError, ServerBuilder};
+
+mod common;
+
+error_codes! {
+    /// What an orders service might declare.
+    pub struct OrderErrors {
+        ORDER_NOT_FOUND => (NOT_FOUND, NotFound, -32004, "The order does not exist"),
+        ORDER_CLOSED => (CONFLICT, FailedPrecondition, -32009, "The order can't be changed"),
+    }
+}
+
+error_codes! {
+    struct Clashing {
+        RATE_LIMITED => (IM_A_TEAPOT, Unknown, 1, "Not the built-in one"),
+    }
+}
+
+#[tokio::test]
+async fn test_coded_error_response() {
+    let error = AppError::coded(
+        OrderErrors::ORDER_CLOSED,
+        anyhow::anyhow!("order 7 shipped"),
+    );
+    assert_eq!(error.code(), OrderErrors::ORDER_CLOSED);
+
+    let response = error.into_response();
+    assert_eq!(response.status(), 409);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let problem: Problem = serde_json::from_slice(&body).unwrap();
+    assert_eq!(problem.code.as_deref(), Some("ORDER_CLOSED"));
+    assert_eq!(problem.detail.as_deref(), Some("order 7 shipped"));
+
+    // Errors that only come with a status get the closest built-in code
+    let error = AppError::CustomCode(anyhow::anyhow!("nope"), axum::http::StatusCode::NOT_FOUND);
+    assert_eq!(error.code(), ErrorCodes::NOT_FOUND);
+    assert_eq!(AppError::rate_limited().code(), ErrorCodes::RATE_LIMITED);
+}
+
+#[tokio::test]
+async fn test_catalog_endpoint() {
+    let url = common::spawn(
+        ServerBuilder::new(ServerConfig::default())
+            .error_codes::<OrderErrors>()
+            // Registering the same codes again is harmless
+            .error_codes::<OrderErrors>(),
+    )
+    .await;
+
+    let catalog: serde_json::Value = reqwest::get(format!("{}/errors", url))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let errors = catalog["errors"].as_array().unwrap();
+    assert_eq!(
+        errors.len(),
+        ErrorCodes::CODES.len() + OrderErrors::CODES.len()
+    );
+    let rate_limited = errors.iter().find(|e| e["code"] == "RATE_LIMITED").unwrap();
+    assert_eq!(rate_limited["status"], 429);
+    assert_eq!(rate_limited["grpc_code"], "RESOURCE_EXHAUSTED");
+    assert_eq!(rate_limited["json_rpc_code"], -32029);
+    assert_eq!(
+        errors.last().unwrap(),
+        &serde_json::json!({
+            "code": "ORDER_CLOSED",
+            "status": 409,
+            "grpc_code": "FAILED_PRECONDITION",
+            "json_rpc_code": -32009,
+            "description": "The order can't be changed",
+        })
+    );
+}
+
+#[test]
+#[should_panic(expected = "error code RATE_LIMITED is registered twice")]
+fn test_conflicting_codes_panic() {
+    let _ = ServerBuilder::new(ServerConfig::default()).error_codes::<Clashing>();
+}
+
+#[tokio::test]
+async fn test_timeout_is_coded() {
+    let config = ServerConfig {
+        request_timeout_secs: 1,
+        ..Default::default()
+    };
+    let slow = axum::Router::new().route(
+        "/slow",
+        get(|| async {
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            "done"
+        }),
+    );
+    let url = common::spawn(ServerBuilder::new(config).merge(slow)).await;
+
+    let response = reqwest::get(format!("{}/slow", url)).await.unwrap();
+    assert_eq!(response.status(), 504);
+    let problem: Problem = response.json().await.unwrap();
+    assert_eq!(problem.code.as_deref(), Some("TIMEOUT"));
+    assert_eq!(problem.instance.as_deref(), Some("/slow"));
+}
@@ -0,0 +1,181 @@
+use rust_http_template::error_code::ErrorCodes;
+use rust_http_template::grpc::hello_world::helloworld::{
+    greeter_client::GreeterClient,
+    greeter_server::{Greeter, GreeterServer},
+    HelloReply, HelloRequest,
+};
+use rust_http_template::grpc::status::{detail, rpc, ERROR_DOMAIN};
+use rust_http_template::internal_error::InternalError;
+use rust_http_template::json_rpc::JsonRpcError;
+use rust_http_template::{AppError, ServerBuilder};
+use serde_json::json;
+use tonic::{Code, Request, Response, Status};
+use validator::{ValidationError, ValidationErrors};
+
+mod common;
+
+fn invalid_name() -> AppError {
+    let mut errors = ValidationErrors::new();
+    errors.add("name", ValidationError::new("required"));
+    AppError::ValidationError(errors)
+}
+
+/// A greeter that insists on a name, failing with an `AppError` like a real service would.
+struct ValidatingGreeter;
+
+#[tonic::async_trait]
+impl Greeter for ValidatingGreeter {
+    async fn say_hello(
+        &self,
+        request: Request<HelloRequest>,
+    ) -> Result<Response<HelloReply>, Status> {
+        let name = request.into_inner().name;
+        if name.is_empty() {
+            return Err(invalid_name().into());
+        }
+        Ok(Response::new(HelloReply {
+            message: format!("Hello {}!", name),
+        }))
+    }
+
+    type StreamHelloStream = futures::stream::Empty<Result<HelloReply, Status>>;
+
+    async fn stream_hello(
+        &self,
+        _request: Request<tonic::Streaming<HelloRequest>>,
+    ) -> Result<Response<Self::StreamHelloStream>, Status> {
+        Err(Status::unimplemented("not needed here"))
+    }
+}
+
+async fn spawn() -> String {
+    common::spawn(ServerBuilder::new(Default::default()).with_default_services()).await
+}
+
+#[test]
+fn test_validation_error_to_status() {
+    let status = Status::from(invalid_name());
+    assert_eq!(status.code(), Code::InvalidArgument);
+    assert_eq!(status.message(), "Request validation failed");
+
+    let info = detail::<rpc::ErrorInfo>(&status).unwrap();
+    assert_eq!(info.reason, "VALIDATION_FAILED");
+    assert_eq!(info.domain, ERROR_DOMAIN);
+    let bad_request = detail::<rpc::BadRequest>(&status).unwrap();
+    assert_eq!(bad_request.field_violations.len(), 1);
+    assert_eq!(bad_request.field_violations[0].field, "/name");
+    assert_eq!(bad_request.field_violations[0].reason, "REQUIRED");
+    assert_eq!(bad_request.field_violations[0].description, "is required");
+}
+
+#[test]
+fn test_internal_error_to_status_is_redacted() {
+    let status = Status::from(AppError::Anyhow(anyhow::anyhow!("disk full")));
+    assert_eq!(status.code(), Code::Internal);
+    assert!(!status.message().contains("disk full"));
+
+    let info = detail::<rpc::ErrorInfo>(&status).unwrap();
+    assert_eq!(info.reason, "INTERNAL");
+    let error_id = &info.metadata["error_id"];
+    assert_eq!(
+        status.message(),
+        format!("Internal server error, reference {}", error_id)
+    );
+
+    // The full error stays reachable for logging
+    let internal = std::error::Error::source(&status)
+        .and_then(|source| source.downcast_ref::<InternalError>())
+        .unwrap();
+    assert_eq!(&internal.id, error_id);
+    assert_eq!(internal.error.to_string(), "disk full");
+}
+
+#[test]
+fn test_status_to_app_error() {
+    let status = Status::from(AppError::coded(
+        ErrorCodes::METHOD_NOT_FOUND,
+        anyhow::anyhow!("no such method"),
+    ));
+    let error = AppError::from_status(&status);
+    assert_eq!(error.code(), ErrorCodes::METHOD_NOT_FOUND);
+    assert_eq!(error.report().message, "no such method");
+
+    // Statuses from other servers only have their code to go by
+    let error = AppError::from_status(&Status::not_found("no such order"));
+    assert_eq!(error.code(), ErrorCodes::NOT_FOUND);
+    let error = AppError::from_status(&Status::unavailable("try later"));
+    assert_eq!(error.code(), ErrorCodes::INTERNAL);
+}
+
+#[test]
+fn test_app_error_to_json_rpc_error() {
+    let error = JsonRpcError::from(invalid_name());
+    assert_eq!(error.code, -32602);
+    assert_eq!(error.message, "Request validation failed");
+    assert_eq!(
+        error.data.unwrap(),
+        json!({
+            "code": "VALIDATION_FAILED",
+            "errors": [{ "pointer": "/name", "code": "required", "detail": "is required" }]
+        })
+    );
+
+    let error = JsonRpcError::from(AppError::coded(
+        ErrorCodes::NOT_FOUND,
+        anyhow::anyhow!("no such order"),
+    ));
+    assert_eq!(error.code, -32004);
+    assert_eq!(error.message, "no such order");
+    assert!(error.internal.is_none());
+
+    let error = JsonRpcError::from(AppError::Anyhow(anyhow::anyhow!("disk full")));
+    assert_eq!(error.code, -32603);
+    let internal = error.internal.unwrap();
+    assert_eq!(error.data.unwrap()["error_id"], internal.id.as_str());
+}
+
+#[tokio::test]
+async fn test_greeter_reports_validation_errors() {
+    let url = common::spawn(
+        ServerBuilder::new(Default::default()).grpc_service(GreeterServer::new(ValidatingGreeter)),
+    )
+    .await;
+    let mut client = GreeterClient::connect(url).await.unwrap();
+
+    let status = client
+        .say_hello(HelloRequest {
+            name: String::new(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    let bad_request = detail::<rpc::BadRequest>(&status).unwrap();
+    assert_eq!(bad_request.field_violations[0].field, "/name");
+}
+
+#[tokio::test]
+async fn test_json_rpc_reports_catalog_codes() {
+    let url = spawn().await;
+    let client = reqwest::Client::new();
+    let call = |method: &str, params: serde_json::Value| {
+        client
+            .post(format!("{}/json_rpc", url))
+            .json(&json!({ "jsonrpc": "2.0", "id": 7, "method": method, "params": params }))
+            .send()
+    };
+
+    let response: serde_json::Value = call("nope", json!({})).await.unwrap().json().await.unwrap();
+    assert_eq!(response["id"], 7);
+    assert_eq!(response["error"]["code"], -32601);
+    assert_eq!(response["error"]["data"]["code"], "METHOD_NOT_FOUND");
+    assert_eq!(response["error"]["message"], "Method not found: nope");
+
+    let response: serde_json::Value = call("my_rpc", json!({ "nom": "Alice" }))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response["error"]["code"], -32602);
+    assert_eq!(response["error"]["data"]["code"], "VALIDATION_FAILED");
+}
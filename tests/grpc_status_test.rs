@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use rust_http_template::config::{RateLimitConfig, ServerConfig};
+use rust_http_template::grpc::hello_world::helloworld::{
+    greeter_client::GreeterClient,
+    greeter_server::{Greeter, GreeterServer},
+    HelloReply, HelloRequest,
+};
+use rust_http_template::grpc::status::retry_delay;
+use rust_http_template::ServerBuilder;
+use tonic::{Code, Request, Response, Status};
+
+mod common;
+
+/// A greeter that takes longer to answer than the request timeout allows.
+struct SlowGreeter;
+
+#[tonic::async_trait]
+impl Greeter for SlowGreeter {
+    async fn say_hello(
+        &self,
+        request: Request<HelloRequest>,
+    ) -> Result<Response<HelloReply>, Status> {
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        Ok(Response::new(HelloReply {
+            message: format!("Hello {}!", request.into_inner().name),
+        }))
+    }
+
+    type StreamHelloStream = futures::stream::Empty<Result<HelloReply, Status>>;
+
+    async fn stream_hello(
+        &self,
+        _request: Request<tonic::Streaming<HelloRequest>>,
+    ) -> Result<Response<Self::StreamHelloStream>, Status> {
+        Err(Status::unimplemented("not needed here"))
+    }
+}
+
+fn hello() -> HelloRequest {
+    HelloRequest {
+        name: "World".to_string(),
+    }
+}
+
+#[tokio::test]
+async fn test_rate_limited_call_is_resource_exhausted() {
+    let config = ServerConfig {
+        rate_limit: RateLimitConfig {
+            max_requests: 1,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let url = common::spawn(ServerBuilder::new(config).with_default_services()).await;
+    let mut client = GreeterClient::connect(url.clone()).await.unwrap();
+
+    assert!(client.say_hello(hello()).await.is_ok());
+    let status = client.say_hello(hello()).await.unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+    assert!(status.message().starts_with("Rate limit exceeded"));
+    let delay = retry_delay(&status).unwrap();
+    assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(60));
+    assert_eq!(status.metadata().get("ratelimit-limit").unwrap(), "1");
+
+    // Plain HTTP requests still get a 429
+    let response = reqwest::get(format!("{}/stream", url)).await.unwrap();
+    assert_eq!(response.status(), 429);
+}
+
+#[tokio::test]
+async fn test_timed_out_call_is_deadline_exceeded() {
+    let config = ServerConfig {
+        request_timeout_secs: 1,
+        ..Default::default()
+    };
+    let url =
+        common::spawn(ServerBuilder::new(config).grpc_service(GreeterServer::new(SlowGreeter)))
+            .await;
+    let mut client = GreeterClient::connect(url).await.unwrap();
+
+    let status = client.say_hello(hello()).await.unwrap_err();
+    assert_eq!(status.code(), Code::DeadlineExceeded);
+    // Like every 5xx, a timeout only tells the client where to find it in the logs
+    assert!(status
+        .message()
+        .starts_with("Internal server error, reference "));
+}
@@ -0,0 +1,210 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use axum::http::StatusCode;
+use axum::routing::get;
+use rust_http_template::config::ServerConfig;
+use rust_http_template::grpc::hello_world::helloworld::{
+    greeter_client::GreeterClient,
+    greeter_server::{Greeter, GreeterServer},
+    HelloReply, HelloRequest,
+};
+use rust_http_template::problem::Problem;
+use rust_http_template::{AppError, ServerBuilder};
+use tonic::{Code, Request, Response, Status};
+
+mod common;
+
+type Logs = Arc<Mutex<Vec<u8>>>;
+
+struct LogWriter(Logs);
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Everything logged by the tests in this file.
+fn logs() -> Logs {
+    static LOGS: OnceLock<Logs> = OnceLock::new();
+    LOGS.get_or_init(|| {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || LogWriter(writer.clone()))
+            .init();
+        logs
+    })
+    .clone()
+}
+
+async fn failing() -> Result<String, AppError> {
+    let cause = anyhow::anyhow!("connection to postgres://app:hunter2@db refused");
+    Err(AppError::Anyhow(cause.context("loading user 42")))
+}
+
+async fn unavailable() -> Result<String, AppError> {
+    let cause = anyhow::anyhow!("replica pool at redis://:hunter2@cache is empty");
+    Err(AppError::CustomCode(cause, StatusCode::SERVICE_UNAVAILABLE))
+}
+
+/// A greeter whose database is always down.
+struct FailingGreeter;
+
+#[tonic::async_trait]
+impl Greeter for FailingGreeter {
+    async fn say_hello(
+        &self,
+        _request: Request<HelloRequest>,
+    ) -> Result<Response<HelloReply>, Status> {
+        let cause = anyhow::anyhow!("connection to postgres://app:hunter2@db refused");
+        Err(AppError::Anyhow(cause.context("loading greeting")).into())
+    }
+
+    type StreamHelloStream = futures::stream::Empty<Result<HelloReply, Status>>;
+
+    async fn stream_hello(
+        &self,
+        _request: Request<tonic::Streaming<HelloRequest>>,
+    ) -> Result<Response<Self::StreamHelloStream>, Status> {
+        Err(Status::unimplemented("not needed here"))
+    }
+}
+
+async fn spawn(dev_mode: bool) -> String {
+    let config = ServerConfig {
+        dev_mode,
+        ..Default::default()
+    };
+    let builder = ServerBuilder::new(config)
+        .merge(
+            axum::Router::new()
+                .route("/fail", get(failing))
+                .route("/unavailable", get(unavailable)),
+        )
+        .grpc_service(GreeterServer::new(FailingGreeter));
+    format!("{}/fail", common::spawn(builder).await)
+}
+
+async fn fail(url: &str, request_id: &str) -> Problem {
+    let response = reqwest::Client::new()
+        .get(url)
+        .header("x-request-id", request_id)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 500);
+    response.json().await.unwrap()
+}
+
+#[tokio::test]
+async fn test_internal_errors_are_redacted() {
+    let logs = logs();
+    let url = spawn(false).await;
+
+    let problem = fail(&url, "req-redacted").await;
+    let error_id = problem.error_id.unwrap();
+    assert_eq!(
+        problem.detail.unwrap(),
+        format!("Internal server error, reference {}", error_id)
+    );
+    assert_eq!(problem.code.as_deref(), Some("INTERNAL"));
+
+    // The whole chain is logged under the error ID, in the request's span
+    let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+    let line = logs
+        .lines()
+        .find(|line| line.contains(&error_id))
+        .expect("error was not logged");
+    assert!(line.contains("req_id=req-redacted"), "{}", line);
+    assert!(line.contains("loading user 42"), "{}", line);
+    assert!(logs.contains("hunter2"));
+}
+
+#[tokio::test]
+async fn test_every_server_error_is_redacted() {
+    let logs = logs();
+    let url = spawn(false).await.replace("/fail", "/unavailable");
+
+    let response = reqwest::get(&url).await.unwrap();
+    assert_eq!(response.status(), 503);
+    let problem: Problem = response.json().await.unwrap();
+    let error_id = problem.error_id.unwrap();
+    assert_eq!(
+        problem.detail.unwrap(),
+        format!("Internal server error, reference {}", error_id)
+    );
+
+    let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+    let line = logs
+        .lines()
+        .find(|line| line.contains(&error_id))
+        .expect("error was not logged");
+    assert!(line.contains("replica pool"), "{}", line);
+}
+
+#[tokio::test]
+async fn test_dev_mode_shows_error_chain() {
+    let url = spawn(true).await;
+
+    let problem = fail(&url, "req-verbose").await;
+    assert!(problem.error_id.is_some());
+    assert_eq!(
+        problem.detail.unwrap(),
+        "loading user 42: connection to postgres://app:hunter2@db refused"
+    );
+}
+
+#[tokio::test]
+async fn test_grpc_internal_errors_are_redacted_and_logged() {
+    let logs = logs();
+    let url = spawn(false).await;
+    let mut client = GreeterClient::connect(url.trim_end_matches("/fail").to_string())
+        .await
+        .unwrap();
+
+    let status = client
+        .say_hello(HelloRequest {
+            name: "World".to_string(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Internal);
+    let error_id = status
+        .message()
+        .strip_prefix("Internal server error, reference ")
+        .unwrap();
+
+    let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+    let line = logs
+        .lines()
+        .find(|line| line.contains(error_id))
+        .expect("error was not logged");
+    assert!(line.contains("loading greeting"), "{}", line);
+}
+
+#[tokio::test]
+async fn test_dev_mode_shows_grpc_error_chain() {
+    let url = spawn(true).await;
+    let mut client = GreeterClient::connect(url.trim_end_matches("/fail").to_string())
+        .await
+        .unwrap();
+
+    let status = client
+        .say_hello(HelloRequest {
+            name: "World".to_string(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(
+        status.message(),
+        "loading greeting: connection to postgres://app:hunter2@db refused"
+    );
+}
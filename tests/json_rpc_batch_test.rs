@@ -0,0 +1,152 @@
+use std::time::{Duration, Instant};
+
+use reqwest::Client;
+use rust_http_template::config::{JsonRpcConfig, RateLimitConfig, ServerConfig};
+use rust_http_template::json_rpc::Params;
+use rust_http_template::ServerBuilder;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use validator::Validate;
+
+mod common;
+
+#[derive(Deserialize, Validate)]
+struct SleepParams {
+    millis: u64,
+}
+
+async fn sleep(Params(params): Params<SleepParams>) -> Result<u64, anyhow::Error> {
+    tokio::time::sleep(Duration::from_millis(params.millis)).await;
+    Ok(params.millis)
+}
+
+async fn spawn(json_rpc: JsonRpcConfig) -> String {
+    let config = ServerConfig {
+        json_rpc,
+        ..Default::default()
+    };
+    let builder = ServerBuilder::new(config)
+        .with_default_services()
+        .rpc_method("sleep", sleep);
+    format!("{}/json_rpc", common::spawn(builder).await)
+}
+
+async fn post(url: &str, body: Value) -> reqwest::Response {
+    Client::new().post(url).json(&body).send().await.unwrap()
+}
+
+#[tokio::test]
+async fn test_batch() {
+    let url = spawn(JsonRpcConfig::default()).await;
+
+    let response = post(
+        &url,
+        json!([
+            { "jsonrpc": "2.0", "id": 1, "method": "my_rpc", "params": { "name": "Alice" } },
+            { "jsonrpc": "2.0", "method": "my_rpc", "params": { "name": "Bob" } },
+            { "jsonrpc": "2.0", "id": 2, "method": "nope", "params": {} },
+            { "foo": "bar" },
+        ]),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    let mut responses: Vec<Value> = response.json().await.unwrap();
+    // Responses come in any order
+    responses.sort_by_key(|response| response["id"].as_i64());
+
+    assert_eq!(responses.len(), 3, "{:?}", responses);
+    // The invalid request has no id to echo
+    assert_eq!(responses[0]["id"], Value::Null);
+    assert_eq!(responses[0]["error"]["code"], -32600);
+    assert_eq!(
+        responses[1],
+        json!({ "jsonrpc": "2.0", "id": 1, "result": { "message": "Hello, Alice!" } })
+    );
+    assert_eq!(responses[2]["id"], 2);
+    assert_eq!(responses[2]["error"]["code"], -32601);
+}
+
+#[tokio::test]
+async fn test_batch_runs_concurrently() {
+    let url = spawn(JsonRpcConfig {
+        batch_concurrency: 4,
+        ..Default::default()
+    })
+    .await;
+    let batch: Vec<Value> = (0..4)
+        .map(|id| json!({ "jsonrpc": "2.0", "id": id, "method": "sleep", "params": { "millis": 500 } }))
+        .collect();
+
+    let started = Instant::now();
+    let responses: Vec<Value> = post(&url, json!(batch)).await.json().await.unwrap();
+    assert_eq!(responses.len(), 4);
+    assert!(started.elapsed() < Duration::from_millis(1500));
+}
+
+#[tokio::test]
+async fn test_invalid_batches() {
+    let url = spawn(JsonRpcConfig {
+        max_batch_size: 2,
+        ..Default::default()
+    })
+    .await;
+
+    let response: Value = post(&url, json!([])).await.json().await.unwrap();
+    assert_eq!(response["id"], Value::Null);
+    assert_eq!(response["error"]["code"], -32600);
+    assert_eq!(response["error"]["message"], "Empty batch");
+
+    let call =
+        json!({ "jsonrpc": "2.0", "id": 1, "method": "my_rpc", "params": { "name": "Alice" } });
+    let response: Value = post(&url, json!([call, call, call]))
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response["error"]["code"], -32600);
+    assert_eq!(
+        response["error"]["message"],
+        "Batch of 3 requests exceeds the limit of 2"
+    );
+}
+
+#[tokio::test]
+async fn test_batch_of_notifications_has_no_response() {
+    let url = spawn(JsonRpcConfig::default()).await;
+
+    let notification =
+        json!({ "jsonrpc": "2.0", "method": "my_rpc", "params": { "name": "Alice" } });
+    let response = post(&url, json!([notification, notification])).await;
+    assert_eq!(response.status(), 204);
+    assert!(response.text().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_batch_calls_are_rate_limited_one_by_one() {
+    let config = ServerConfig {
+        rate_limit: RateLimitConfig {
+            max_requests: 2,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let base = common::spawn(ServerBuilder::new(config).with_default_services()).await;
+    let url = format!("{}/json_rpc", base);
+    let batch: Vec<Value> = (0..5)
+        .map(|id| json!({ "jsonrpc": "2.0", "id": id, "method": "my_rpc", "params": { "name": "Alice" } }))
+        .collect();
+
+    let response = post(&url, batch.into()).await;
+    assert_eq!(response.status(), 200);
+    let mut responses: Vec<Value> = response.json().await.unwrap();
+    responses.sort_by_key(|response| response["id"].as_i64());
+
+    // The request pays for the first call, the second one uses up the rest of the budget
+    assert_eq!(responses.len(), 5);
+    assert_eq!(responses[0]["result"]["message"], "Hello, Alice!");
+    assert_eq!(responses[1]["result"]["message"], "Hello, Alice!");
+    for response in &responses[2..] {
+        assert_eq!(response["error"]["code"], -32029);
+        assert_eq!(response["error"]["data"]["code"], "RATE_LIMITED");
+    }
+}
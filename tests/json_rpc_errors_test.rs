@@ -0,0 +1,166 @@
+use reqwest::Client;
+use rust_http_template::config::ServerConfig;
+use rust_http_template::json_rpc::{params, JsonRpcError};
+use rust_http_template::ServerBuilder;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+mod common;
+
+async fn explode() -> Result<(), anyhow::Error> {
+    panic!("boom");
+}
+
+async fn spawn() -> String {
+    let builder = ServerBuilder::new(ServerConfig::default())
+        .with_default_services()
+        .rpc_method("explode", explode);
+    format!("{}/json_rpc", common::spawn(builder).await)
+}
+
+async fn post(url: &str, body: Value) -> Value {
+    let response = Client::new().post(url).json(&body).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    response.json().await.unwrap()
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+struct Order {
+    items: Vec<Item>,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+struct Item {
+    quantity: u32,
+}
+
+#[test]
+fn test_params_errors_point_at_the_param() {
+    let order: Result<Order, _> =
+        params(json!({ "items": [{ "quantity": 1 }, { "quantity": -1 }] }));
+    let body = serde_json::to_value(JsonRpcError::from(order.unwrap_err())).unwrap();
+    assert_eq!(body["code"], -32602);
+    assert_eq!(
+        body["data"]["errors"][0]["pointer"],
+        "/params/items/1/quantity"
+    );
+    assert_eq!(body["data"]["errors"][0]["code"], "parse");
+
+    // Positional params work for structs too
+    let order: Result<Order, _> = params(json!([[{ "quantity": 1 }]]));
+    assert_eq!(order.ok().unwrap().items.len(), 1);
+}
+
+#[tokio::test]
+async fn test_parse_error() {
+    let url = spawn().await;
+
+    let response = Client::new()
+        .post(&url)
+        .header("content-type", "application/json")
+        .body(r#"{"jsonrpc": "2.0", "method": "my_rpc", "#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let response: Value = response.json().await.unwrap();
+    assert_eq!(response["id"], Value::Null);
+    assert_eq!(response["error"]["code"], -32700);
+    assert_eq!(response["error"]["data"]["code"], "PARSE_ERROR");
+
+    // The content type doesn't matter as long as the body is JSON
+    let response: Value = Client::new()
+        .post(&url)
+        .header("content-type", "text/plain")
+        .body(r#"{"jsonrpc": "2.0", "id": 1, "method": "my_rpc", "params": {"name": "Alice"}}"#)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response["result"]["message"], "Hello, Alice!");
+}
+
+#[tokio::test]
+async fn test_invalid_requests() {
+    let url = spawn().await;
+
+    let response = post(
+        &url,
+        json!({ "jsonrpc": "1.0", "id": 3, "method": "my_rpc", "params": { "name": "Alice" } }),
+    )
+    .await;
+    assert_eq!(response["id"], 3);
+    assert_eq!(response["error"]["code"], -32600);
+    assert_eq!(
+        response["error"]["message"],
+        "Unsupported jsonrpc version \"1.0\", expected \"2.0\""
+    );
+
+    for request in [
+        json!({ "jsonrpc": "2.0", "id": 4 }),
+        json!(42),
+        json!("my_rpc"),
+    ] {
+        let response = post(&url, request).await;
+        assert_eq!(response["error"]["code"], -32600, "{}", response);
+    }
+}
+
+#[tokio::test]
+async fn test_invalid_params() {
+    let url = spawn().await;
+
+    let response = post(
+        &url,
+        json!({ "jsonrpc": "2.0", "id": 5, "method": "my_rpc", "params": { "name": 5 } }),
+    )
+    .await;
+    assert_eq!(response["id"], 5);
+    assert_eq!(response["error"]["code"], -32602);
+    assert_eq!(
+        response["error"]["data"]["errors"],
+        json!([{
+            "pointer": "/params/name",
+            "code": "parse",
+            "detail": "invalid type: integer `5`, expected a string"
+        }])
+    );
+
+    let response = post(
+        &url,
+        json!({ "jsonrpc": "2.0", "id": 6, "method": "my_rpc", "params": {} }),
+    )
+    .await;
+    assert_eq!(response["error"]["code"], -32602);
+    assert_eq!(response["error"]["data"]["errors"][0]["pointer"], "/params");
+    assert_eq!(
+        response["error"]["data"]["errors"][0]["detail"],
+        "missing field `name`"
+    );
+}
+
+#[tokio::test]
+async fn test_panicking_method() {
+    let url = spawn().await;
+
+    let mut responses: Vec<Value> = serde_json::from_value(
+        post(
+            &url,
+            json!([
+                { "jsonrpc": "2.0", "id": 1, "method": "explode", "params": {} },
+                { "jsonrpc": "2.0", "id": 2, "method": "my_rpc", "params": { "name": "Alice" } },
+            ]),
+        )
+        .await,
+    )
+    .unwrap();
+    responses.sort_by_key(|response| response["id"].as_i64());
+
+    assert_eq!(responses[0]["error"]["code"], -32603);
+    assert!(responses[0]["error"]["data"]["error_id"].is_string());
+    assert_eq!(responses[1]["result"]["message"], "Hello, Alice!");
+}
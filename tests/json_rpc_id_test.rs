@@ -0,0 +1,92 @@
+use reqwest::Client;
+use rust_http_template::config::ServerConfig;
+use rust_http_template::json_rpc::{Id, JsonRpcRequest};
+use rust_http_template::ServerBuilder;
+use serde_json::{json, Value};
+
+mod common;
+
+async fn spawn() -> String {
+    let builder = ServerBuilder::new(ServerConfig::default()).with_default_services();
+    format!("{}/json_rpc", common::spawn(builder).await)
+}
+
+async fn post(url: &str, body: Value) -> reqwest::Response {
+    Client::new().post(url).json(&body).send().await.unwrap()
+}
+
+fn hello(id: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "method": "my_rpc", "params": { "name": "Alice" } })
+}
+
+#[test]
+fn test_id_serde() {
+    let request: JsonRpcRequest =
+        serde_json::from_value(json!({ "jsonrpc": "2.0", "method": "m", "params": [] })).unwrap();
+    assert!(request.is_notification());
+
+    let request: JsonRpcRequest = serde_json::from_value(
+        json!({ "jsonrpc": "2.0", "method": "m", "params": [], "id": null }),
+    )
+    .unwrap();
+    assert_eq!(request.id, Some(Id::Null));
+    assert!(!request.is_notification());
+
+    for id in [json!(7), json!(-1.5), json!("abc"), json!(null)] {
+        let parsed: Id = serde_json::from_value(id.clone()).unwrap();
+        assert_eq!(serde_json::to_value(parsed).unwrap(), id);
+    }
+    assert_eq!(Id::from(7), serde_json::from_value(json!(7)).unwrap());
+    assert_eq!(Id::from("7"), serde_json::from_value(json!("7")).unwrap());
+}
+
+#[tokio::test]
+async fn test_ids_are_echoed_as_sent() {
+    let url = spawn().await;
+
+    for id in [
+        json!(1),
+        json!("req-1"),
+        json!(12345678901_i64),
+        json!(null),
+    ] {
+        let response: Value = post(&url, hello(id.clone())).await.json().await.unwrap();
+        assert_eq!(response["id"], id);
+        assert_eq!(response["result"]["message"], "Hello, Alice!");
+    }
+    // "1" and 1 are different ids
+    let response: Value = post(&url, hello(json!("1"))).await.json().await.unwrap();
+    assert_eq!(response["id"], "1");
+
+    // Invalid requests still get their id back when it can be read
+    let response: Value = post(&url, json!({ "jsonrpc": "2.0", "id": "bad" }))
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response["id"], "bad");
+    assert_eq!(response["error"]["code"], -32600);
+}
+
+#[tokio::test]
+async fn test_notifications_have_no_response() {
+    let url = spawn().await;
+
+    let notification =
+        json!({ "jsonrpc": "2.0", "method": "my_rpc", "params": { "name": "Alice" } });
+    let response = post(&url, notification.clone()).await;
+    assert_eq!(response.status(), 204);
+    assert!(response.text().await.unwrap().is_empty());
+
+    // Failing notifications aren't answered either
+    let failing = json!({ "jsonrpc": "2.0", "method": "my_rpc", "params": { "name": "error" } });
+    assert_eq!(post(&url, failing).await.status(), 204);
+
+    let response: Vec<Value> = post(&url, json!([notification, hello(json!("only"))]))
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response.len(), 1);
+    assert_eq!(response[0]["id"], "only");
+}
@@ -0,0 +1,226 @@
+use std::process::Stdio;
+use std::time::Duration;
+
+use rust_http_template::config::{RateLimitConfig, ServerConfig};
+use rust_http_template::local::Framing;
+use rust_http_template::ServerBuilder;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+async fn spawn_unix(config: ServerConfig, framing: Framing) -> (UnixStream, std::path::PathBuf) {
+    let socket = std::env::temp_dir().join(format!("{}.sock", uuid::Uuid::new_v4()));
+    let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+    tokio::spawn(
+        ServerBuilder::new(config)
+            .with_default_services()
+            .serve_unix(listener, framing),
+    );
+    (UnixStream::connect(&socket).await.unwrap(), socket)
+}
+
+#[tokio::test]
+async fn test_unix_socket_lines() {
+    let (stream, socket) = spawn_unix(ServerConfig::default(), Framing::Lines).await;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let call =
+        json!({ "jsonrpc": "2.0", "id": 1, "method": "my_rpc", "params": { "name": "Alice" } });
+    let notification = json!({ "jsonrpc": "2.0", "method": "my_rpc", "params": { "name": "Bob" } });
+    let batch = json!([
+        { "jsonrpc": "2.0", "id": 2, "method": "greeting_rpc", "params": ["Ana", "spanish"] },
+        { "jsonrpc": "2.0", "id": 3, "method": "my_rpc", "params": { "name": "" } },
+    ]);
+    let messages = format!("{}\n\n{}\n{{\n{}\n", call, notification, batch);
+    writer.write_all(messages.as_bytes()).await.unwrap();
+
+    let mut responses = Vec::new();
+    for _ in 0..3 {
+        let line = tokio::time::timeout(Duration::from_secs(5), lines.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        responses.push(serde_json::from_str::<Value>(&line).unwrap());
+    }
+    // Messages are handled concurrently, so look the responses up by what they answer
+    let response = responses.iter().find(|r| r["id"] == 1).unwrap();
+    assert_eq!(response["result"]["message"], "Hello, Alice!");
+    let parse_error = responses.iter().find(|r| r["id"].is_null()).unwrap();
+    assert_eq!(parse_error["error"]["code"], -32700);
+    let batch = responses.iter().find(|r| r.is_array()).unwrap();
+    let greeting = batch
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|r| r["id"] == 2)
+        .unwrap();
+    assert_eq!(greeting["result"]["greeting"], "¡Hola, Ana!");
+    let invalid = batch
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|r| r["id"] == 3)
+        .unwrap();
+    assert_eq!(invalid["error"]["code"], -32602);
+
+    let _ = std::fs::remove_file(socket);
+}
+
+#[tokio::test]
+async fn test_unix_socket_content_length() {
+    let (mut stream, socket) = spawn_unix(ServerConfig::default(), Framing::ContentLength).await;
+
+    let call =
+        json!({ "jsonrpc": "2.0", "id": "a", "method": "my_rpc", "params": ["Alice"] }).to_string();
+    let message = format!(
+        "Content-Length: {}\r\nContent-Type: application/vscode-jsonrpc; charset=utf-8\r\n\r\n{}",
+        call.len(),
+        call
+    );
+    stream.write_all(message.as_bytes()).await.unwrap();
+
+    let mut reader = BufReader::new(stream);
+    let mut header = String::new();
+    reader.read_line(&mut header).await.unwrap();
+    let length: usize = header
+        .trim()
+        .strip_prefix("Content-Length: ")
+        .unwrap()
+        .parse()
+        .unwrap();
+    let mut blank = String::new();
+    reader.read_line(&mut blank).await.unwrap();
+    assert_eq!(blank, "\r\n");
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await.unwrap();
+    let response: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        response,
+        json!({ "jsonrpc": "2.0", "result": { "message": "Hello, Alice!" }, "id": "a" })
+    );
+
+    let _ = std::fs::remove_file(socket);
+}
+
+#[tokio::test]
+async fn test_unix_socket_calls_are_rate_limited() {
+    let config = ServerConfig {
+        rate_limit: RateLimitConfig {
+            max_requests: 2,
+            window_secs: 60,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let (stream, socket) = spawn_unix(config, Framing::Lines).await;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let batch: Vec<Value> = (1..=3)
+        .map(|id| json!({ "jsonrpc": "2.0", "id": id, "method": "my_rpc", "params": ["Alice"] }))
+        .collect();
+    writer
+        .write_all(format!("{}\n", Value::from(batch)).as_bytes())
+        .await
+        .unwrap();
+    let line = tokio::time::timeout(Duration::from_secs(5), lines.next_line())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let mut responses: Vec<Value> = serde_json::from_str(&line).unwrap();
+    responses.sort_by_key(|response| response["id"].as_i64());
+    assert_eq!(responses[0]["result"]["message"], "Hello, Alice!");
+    assert_eq!(responses[1]["result"]["message"], "Hello, Alice!");
+    assert_eq!(responses[2]["error"]["code"], -32029);
+
+    // Local clients share one key, so another connection is out of requests too
+    let mut other = UnixStream::connect(&socket).await.unwrap();
+    let call = json!({ "jsonrpc": "2.0", "id": 4, "method": "my_rpc", "params": ["Bob"] });
+    other
+        .write_all(format!("{}\n", call).as_bytes())
+        .await
+        .unwrap();
+    let mut line = String::new();
+    tokio::time::timeout(
+        Duration::from_secs(5),
+        BufReader::new(other).read_line(&mut line),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let response: Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(response["error"]["code"], -32029);
+
+    let _ = std::fs::remove_file(socket);
+}
+
+#[tokio::test]
+async fn test_stdio_command() {
+    let mut child = tokio::process::Command::new(env!("CARGO_BIN_EXE_rust_http_template"))
+        .arg("stdio")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut stdin = child.stdin.take().unwrap();
+    let call =
+        json!({ "jsonrpc": "2.0", "id": 1, "method": "my_rpc", "params": { "name": "Alice" } });
+    let missing = json!({ "jsonrpc": "2.0", "id": 2, "method": "nope" });
+    stdin
+        .write_all(format!("{}\n{}\n", call, missing).as_bytes())
+        .await
+        .unwrap();
+    // Responses to everything read before stdin closed are still sent
+    drop(stdin);
+
+    let output = tokio::time::timeout(Duration::from_secs(10), child.wait_with_output())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(output.status.success());
+    let mut responses: Vec<Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    responses.sort_by_key(|response| response["id"].as_i64());
+    assert_eq!(responses.len(), 2);
+    assert_eq!(responses[0]["result"]["message"], "Hello, Alice!");
+    assert_eq!(responses[1]["error"]["code"], -32601);
+}
+
+#[tokio::test]
+async fn test_unix_command_keeps_what_is_at_the_path() {
+    let run = |path: std::path::PathBuf| async move {
+        let child = tokio::process::Command::new(env!("CARGO_BIN_EXE_rust_http_template"))
+            .arg("unix")
+            .arg(&path)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), child.wait_with_output())
+            .await
+            .unwrap()
+            .unwrap()
+            .status
+    };
+
+    let file = std::env::temp_dir().join(format!("{}.txt", uuid::Uuid::new_v4()));
+    std::fs::write(&file, "notes").unwrap();
+    assert!(!run(file.clone()).await.success());
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "notes");
+    std::fs::remove_file(file).unwrap();
+
+    // A socket another server is still listening on
+    let socket = std::env::temp_dir().join(format!("{}.sock", uuid::Uuid::new_v4()));
+    let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+    assert!(!run(socket.clone()).await.success());
+    assert!(UnixStream::connect(&socket).await.is_ok());
+    drop(listener);
+    std::fs::remove_file(socket).unwrap();
+}
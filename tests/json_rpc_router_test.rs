@@ -0,0 +1,163 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use axum::extract::State;
+use reqwest::Client;
+use rust_http_template::config::ServerConfig;
+use rust_http_template::json_rpc::{JsonRpcRouter, Params};
+use rust_http_template::{AppError, ServerBuilder};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use validator::Validate;
+
+mod common;
+
+#[derive(Deserialize, Validate)]
+struct AddParams {
+    #[validate(range(max = 1000))]
+    a: i64,
+    b: i64,
+}
+
+async fn add(Params(params): Params<AddParams>) -> Result<i64, AppError> {
+    Ok(params.a + params.b)
+}
+
+async fn ping() -> Result<&'static str, AppError> {
+    Ok("pong")
+}
+
+/// A counter module, with state of its own.
+mod counter {
+    use super::*;
+
+    #[derive(Deserialize, Validate)]
+    pub struct IncrementParams {
+        #[validate(range(min = 1))]
+        pub by: u64,
+    }
+
+    async fn increment(
+        State(count): State<Arc<AtomicU64>>,
+        Params(params): Params<IncrementParams>,
+    ) -> Result<u64, AppError> {
+        Ok(count.fetch_add(params.by, Ordering::SeqCst) + params.by)
+    }
+
+    async fn get(State(count): State<Arc<AtomicU64>>) -> Result<u64, AppError> {
+        Ok(count.load(Ordering::SeqCst))
+    }
+
+    pub fn router() -> JsonRpcRouter {
+        JsonRpcRouter::new()
+            .method("counter.increment", increment)
+            .method("counter.get", get)
+            .with_state(Arc::new(AtomicU64::new(0)))
+    }
+}
+
+fn router() -> JsonRpcRouter {
+    JsonRpcRouter::new()
+        .method("add", add)
+        .method("ping", ping)
+        .merge(counter::router())
+}
+
+async fn spawn() -> String {
+    common::spawn(
+        ServerBuilder::new(ServerConfig::default())
+            .with_default_services()
+            .json_rpc_router("/rpc", router()),
+    )
+    .await
+}
+
+async fn call(url: &str, method: &str, params: Value) -> Value {
+    Client::new()
+        .post(url)
+        .json(&json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_router_call() {
+    let router = router();
+    assert_eq!(
+        router.method_names().collect::<Vec<_>>(),
+        ["add", "counter.get", "counter.increment", "ping"]
+    );
+
+    let result = router.call("add", json!({ "a": 1, "b": 2 })).await;
+    assert_eq!(result.unwrap().ok(), Some(json!(3)));
+    let result = router.call("ping", Value::Null).await;
+    assert_eq!(result.unwrap().ok(), Some(json!("pong")));
+    assert!(router.call("nope", Value::Null).await.is_none());
+}
+
+#[tokio::test]
+async fn test_router_mounted_on_path() {
+    let base = spawn().await;
+    let url = format!("{}/rpc", base);
+
+    // By name and by position
+    assert_eq!(
+        call(&url, "add", json!({ "a": 2, "b": 3 })).await["result"],
+        5
+    );
+    assert_eq!(call(&url, "add", json!([2, 3])).await["result"], 5);
+    assert_eq!(call(&url, "ping", Value::Null).await["result"], "pong");
+
+    // State is kept between calls
+    assert_eq!(
+        call(&url, "counter.increment", json!({ "by": 2 })).await["result"],
+        2
+    );
+    assert_eq!(
+        call(&url, "counter.increment", json!([3])).await["result"],
+        5
+    );
+    assert_eq!(call(&url, "counter.get", json!({})).await["result"], 5);
+
+    // The default methods stay on `/json_rpc`
+    let default_url = format!("{}/json_rpc", base);
+    let response = call(&default_url, "my_rpc", json!({ "name": "Alice" })).await;
+    assert_eq!(response["result"]["message"], "Hello, Alice!");
+    assert_eq!(
+        call(&default_url, "add", json!([2, 3])).await["error"]["code"],
+        -32601
+    );
+}
+
+#[tokio::test]
+async fn test_params_are_validated() {
+    let url = format!("{}/rpc", spawn().await);
+
+    let response = call(&url, "add", json!({ "a": 1001, "b": 0 })).await;
+    assert_eq!(response["error"]["code"], -32602);
+    assert_eq!(
+        response["error"]["data"]["errors"],
+        json!([{
+            "pointer": "/params/a",
+            "code": "range",
+            "detail": "must be at most 1000",
+            "params": { "max": 1000 }
+        }])
+    );
+
+    let response = call(&url, "counter.increment", json!([0])).await;
+    assert_eq!(
+        response["error"]["data"]["errors"][0]["pointer"],
+        "/params/by"
+    );
+}
+
+#[test]
+#[should_panic(expected = "JSON-RPC method ping is registered twice")]
+fn test_duplicate_methods_panic() {
+    let _ = router().merge(JsonRpcRouter::new().method("ping", ping));
+}
@@ -0,0 +1,372 @@
+use std::time::{Duration, Instant};
+
+use futures_util::{SinkExt, StreamExt};
+use rust_http_template::config::{
+    JsonRpcConfig, QuotaConfig, QuotaLimits, RateLimitConfig, ServerConfig,
+};
+use rust_http_template::json_rpc::{JsonRpcRouter, Params};
+use rust_http_template::{AppError, ServerBuilder};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use validator::Validate;
+
+mod common;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Deserialize, Validate)]
+struct SleepParams {
+    ms: u64,
+}
+
+async fn sleep(Params(params): Params<SleepParams>) -> Result<u64, AppError> {
+    tokio::time::sleep(Duration::from_millis(params.ms)).await;
+    Ok(params.ms)
+}
+
+async fn spawn(builder: ServerBuilder) -> String {
+    serve(builder).await.0
+}
+
+/// Like `spawn`, also returning the task running the server.
+async fn serve(builder: ServerBuilder) -> (String, JoinHandle<std::io::Result<()>>) {
+    let (addr, server) = common::serve(
+        builder
+            .with_default_services()
+            .json_rpc_router("/rpc", JsonRpcRouter::new().method("sleep", sleep)),
+    )
+    .await;
+    (format!("ws://{}/rpc/ws", addr), server)
+}
+
+async fn connect(url: &str) -> Socket {
+    tokio_tungstenite::connect_async(url).await.unwrap().0
+}
+
+async fn send(socket: &mut Socket, message: Value) {
+    socket
+        .send(Message::text(message.to_string()))
+        .await
+        .unwrap();
+}
+
+async fn receive(socket: &mut Socket) -> Value {
+    let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+        .await
+        .expect("no message within 5 seconds")
+        .unwrap()
+        .unwrap();
+    serde_json::from_str(message.to_text().unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn test_calls_are_multiplexed() {
+    let mut socket = connect(&spawn(ServerBuilder::new(ServerConfig::default())).await).await;
+
+    send(
+        &mut socket,
+        json!({ "jsonrpc": "2.0", "id": "slow", "method": "sleep", "params": { "ms": 300 } }),
+    )
+    .await;
+    send(
+        &mut socket,
+        json!({ "jsonrpc": "2.0", "id": 2, "method": "sleep", "params": [0] }),
+    )
+    .await;
+    // Notifications get no response
+    send(
+        &mut socket,
+        json!({ "jsonrpc": "2.0", "method": "sleep", "params": [0] }),
+    )
+    .await;
+
+    // The fast call doesn't wait for the slow one
+    assert_eq!(
+        receive(&mut socket).await,
+        json!({ "jsonrpc": "2.0", "result": 0, "id": 2 })
+    );
+    assert_eq!(
+        receive(&mut socket).await,
+        json!({ "jsonrpc": "2.0", "result": 300, "id": "slow" })
+    );
+
+    send(
+        &mut socket,
+        json!([
+            { "jsonrpc": "2.0", "id": 3, "method": "sleep", "params": [0] },
+            { "jsonrpc": "2.0", "id": 4, "method": "nope" },
+        ]),
+    )
+    .await;
+    let batch = receive(&mut socket).await;
+    let mut batch = batch.as_array().unwrap().clone();
+    batch.sort_by_key(|response| response["id"].as_i64());
+    assert_eq!(batch[0]["result"], 0);
+    assert_eq!(batch[1]["error"]["code"], -32601);
+
+    socket.send(Message::text("{")).await.unwrap();
+    let response = receive(&mut socket).await;
+    assert_eq!(response["error"]["code"], -32700);
+    assert_eq!(response["id"], Value::Null);
+}
+
+#[tokio::test]
+async fn test_calls_are_rate_limited_one_by_one() {
+    let config = ServerConfig {
+        rate_limit: RateLimitConfig {
+            max_requests: 3,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    // The upgrade request takes one of the three
+    let mut socket = connect(&spawn(ServerBuilder::new(config)).await).await;
+
+    let batch: Vec<Value> = (0..5)
+        .map(|id| json!({ "jsonrpc": "2.0", "id": id, "method": "sleep", "params": [0] }))
+        .collect();
+    send(&mut socket, batch.into()).await;
+    let batch = receive(&mut socket).await;
+    let mut batch = batch.as_array().unwrap().clone();
+    batch.sort_by_key(|response| response["id"].as_i64());
+    assert_eq!(batch.len(), 5);
+    assert_eq!(batch[0]["result"], 0);
+    assert_eq!(batch[1]["result"], 0);
+    for response in &batch[2..] {
+        assert_eq!(response["error"]["code"], -32029);
+    }
+
+    send(
+        &mut socket,
+        json!({ "jsonrpc": "2.0", "id": "late", "method": "sleep", "params": [0] }),
+    )
+    .await;
+    let response = receive(&mut socket).await;
+    assert_eq!(response["id"], "late");
+    assert_eq!(response["error"]["code"], -32029);
+}
+
+#[tokio::test]
+async fn test_calls_use_up_quota_one_by_one() {
+    let config = ServerConfig {
+        rate_limit: RateLimitConfig {
+            max_requests: 100,
+            quota: Some(QuotaConfig {
+                limits: QuotaLimits {
+                    daily: Some(3),
+                    monthly: None,
+                },
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut request = spawn(ServerBuilder::new(config))
+        .await
+        .into_client_request()
+        .unwrap();
+    request
+        .headers_mut()
+        .insert("x-api-key", "key-1".parse().unwrap());
+    // The upgrade request takes one of the three
+    let (mut socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+
+    let batch: Vec<Value> = (0..3)
+        .map(|id| json!({ "jsonrpc": "2.0", "id": id, "method": "sleep", "params": [0] }))
+        .collect();
+    send(&mut socket, batch.into()).await;
+    let batch = receive(&mut socket).await;
+    let mut batch = batch.as_array().unwrap().clone();
+    batch.sort_by_key(|response| response["id"].as_i64());
+    assert_eq!(batch[0]["result"], 0);
+    assert_eq!(batch[1]["result"], 0);
+    assert_eq!(batch[2]["error"]["code"], -32029);
+}
+
+#[tokio::test]
+async fn test_subscriptions() {
+    let builder = ServerBuilder::new(ServerConfig::default()).topic("ticks");
+    let events = builder.events();
+    let url = spawn(builder).await;
+    let mut socket = connect(&url).await;
+
+    send(
+        &mut socket,
+        json!({ "jsonrpc": "2.0", "id": 1, "method": "subscribe", "params": { "topic": "ticks" } }),
+    )
+    .await;
+    let subscription = receive(&mut socket).await["result"].clone();
+    assert!(subscription.is_u64());
+
+    assert_eq!(events.publish("ticks", json!({ "n": 1 })), 1);
+    assert_eq!(events.publish("other", json!({ "n": 1 })), 0);
+    assert_eq!(
+        receive(&mut socket).await,
+        json!({
+            "jsonrpc": "2.0",
+            "method": "subscription",
+            "params": { "subscription": subscription, "result": { "n": 1 } }
+        })
+    );
+
+    assert_eq!(events.notify("server.hello", json!(["hi"])), 1);
+    assert_eq!(
+        receive(&mut socket).await,
+        json!({ "jsonrpc": "2.0", "method": "server.hello", "params": ["hi"] })
+    );
+
+    send(
+        &mut socket,
+        json!({ "jsonrpc": "2.0", "id": 2, "method": "unsubscribe", "params": [subscription] }),
+    )
+    .await;
+    assert_eq!(receive(&mut socket).await["result"], true);
+    send(
+        &mut socket,
+        json!({ "jsonrpc": "2.0", "id": 3, "method": "unsubscribe", "params": [subscription] }),
+    )
+    .await;
+    assert_eq!(receive(&mut socket).await["result"], false);
+
+    send(
+        &mut socket,
+        json!({ "jsonrpc": "2.0", "id": 4, "method": "subscribe", "params": ["nope"] }),
+    )
+    .await;
+    assert_eq!(receive(&mut socket).await["error"]["code"], -32004);
+
+    // Subscriptions end with their connection
+    drop(socket);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(events.publish("ticks", json!({ "n": 2 })), 0);
+}
+
+#[tokio::test]
+async fn test_shutdown_closes_socket() {
+    let builder = ServerBuilder::new(ServerConfig::default());
+    let shutdown = builder.shutdown_handle();
+    let (url, server) = serve(builder).await;
+    let mut socket = connect(&url).await;
+
+    send(
+        &mut socket,
+        json!({ "jsonrpc": "2.0", "id": 1, "method": "sleep", "params": [300] }),
+    )
+    .await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(shutdown.in_flight(), vec!["WebSocket GET /rpc/ws"]);
+    let started = Instant::now();
+    shutdown.trigger();
+
+    // The server only stops once the session is done
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    assert!(shutdown.in_flight().is_empty());
+
+    // The call in flight still got its response
+    assert_eq!(receive(&mut socket).await["result"], 300);
+    let message = socket.next().await.unwrap().unwrap();
+    assert!(matches!(message, Message::Close(Some(frame)) if frame.code == 1001.into()));
+}
+
+#[tokio::test]
+async fn test_shutdown_deadline_cuts_off_calls() {
+    let mut config = ServerConfig::default();
+    config.shutdown.deadline_secs = 1;
+    let builder = ServerBuilder::new(config);
+    let shutdown = builder.shutdown_handle();
+    let (url, server) = serve(builder).await;
+    let mut socket = connect(&url).await;
+
+    send(
+        &mut socket,
+        json!({ "jsonrpc": "2.0", "id": 1, "method": "sleep", "params": [30000] }),
+    )
+    .await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let started = Instant::now();
+    shutdown.trigger();
+
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert!(started.elapsed() < Duration::from_secs(3));
+    assert!(shutdown.in_flight().is_empty());
+
+    // The stuck call never got a response, the socket was closed instead
+    let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+        .await
+        .unwrap();
+    assert!(!matches!(message, Some(Ok(Message::Text(_)))));
+}
+
+#[tokio::test]
+async fn test_subscriptions_are_capped() {
+    let config = ServerConfig {
+        json_rpc: JsonRpcConfig {
+            ws_max_subscriptions: 2,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let url = spawn(ServerBuilder::new(config).topic("ticks")).await;
+    let mut socket = connect(&url).await;
+
+    let subscribe =
+        |id: u64| json!({ "jsonrpc": "2.0", "id": id, "method": "subscribe", "params": ["ticks"] });
+    for id in 0..2 {
+        send(&mut socket, subscribe(id)).await;
+        assert!(receive(&mut socket).await["result"].is_u64());
+    }
+    send(&mut socket, subscribe(2)).await;
+    let response = receive(&mut socket).await;
+    assert_eq!(response["error"]["code"], -32030);
+    assert_eq!(response["error"]["data"]["code"], "LIMIT_REACHED");
+
+    // Unsubscribing makes room again
+    send(
+        &mut socket,
+        json!({ "jsonrpc": "2.0", "id": 3, "method": "unsubscribe", "params": [1] }),
+    )
+    .await;
+    assert_eq!(receive(&mut socket).await["result"], true);
+    send(&mut socket, subscribe(4)).await;
+    assert!(receive(&mut socket).await["result"].is_u64());
+}
+
+#[tokio::test]
+async fn test_messages_are_held_to_the_body_limit() {
+    let config = ServerConfig {
+        body_limit: 1024,
+        ..Default::default()
+    };
+    let mut socket = connect(&spawn(ServerBuilder::new(config)).await).await;
+
+    let name = "a".repeat(2048);
+    send(
+        &mut socket,
+        json!({ "jsonrpc": "2.0", "id": 1, "method": "my_rpc", "params": [name] }),
+    )
+    .await;
+    // The server closes the connection rather than answering
+    let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+        .await
+        .unwrap();
+    assert!(
+        matches!(message, Some(Ok(Message::Close(_))) | Some(Err(_)) | None),
+        "{:?}",
+        message
+    );
+}
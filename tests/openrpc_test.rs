@@ -0,0 +1,134 @@
+use reqwest::Client;
+use rust_http_template::config::ServerConfig;
+use rust_http_template::json_rpc::{JsonRpcRouter, Params};
+use rust_http_template::{AppError, ServerBuilder};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use validator::Validate;
+
+mod common;
+
+#[derive(Deserialize, Validate, JsonSchema)]
+struct AddParams {
+    /// The first summand.
+    #[validate(range(max = 1000))]
+    a: i64,
+    b: Option<i64>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Sum {
+    sum: i64,
+}
+
+async fn add(Params(params): Params<AddParams>) -> Result<Sum, AppError> {
+    Ok(Sum {
+        sum: params.a + params.b.unwrap_or_default(),
+    })
+}
+
+async fn ping() -> Result<&'static str, AppError> {
+    Ok("pong")
+}
+
+async fn document(url: &str) -> Value {
+    let response = Client::new().get(url).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    response.json().await.unwrap()
+}
+
+#[tokio::test]
+async fn test_default_methods_are_documented() {
+    let base =
+        common::spawn(ServerBuilder::new(ServerConfig::default()).with_default_services()).await;
+    let document = document(&format!("{}/json_rpc", base)).await;
+
+    assert_eq!(document["openrpc"], "1.3.2");
+    let methods = document["methods"].as_array().unwrap();
+    let names: Vec<_> = methods.iter().map(|method| &method["name"]).collect();
+    assert_eq!(names, ["greeting_rpc", "my_rpc"]);
+    // Methods registered without a schema would only get an empty one
+    for method in methods {
+        assert_ne!(
+            method["result"]["schema"],
+            json!({}),
+            "{} has no schema",
+            method["name"]
+        );
+        assert!(!method["params"].as_array().unwrap().is_empty());
+    }
+
+    let my_rpc = &methods[1];
+    assert_eq!(my_rpc["paramStructure"], "either");
+    assert_eq!(
+        my_rpc["params"],
+        json!([{
+            "name": "name",
+            "description": "Who to say hello to.",
+            "required": true,
+            "schema": {
+                "type": "string",
+                "description": "Who to say hello to.",
+                "minLength": 1
+            }
+        }])
+    );
+    assert_eq!(
+        my_rpc["result"]["schema"]["$ref"],
+        "#/components/schemas/MyRpcResponse"
+    );
+    assert_eq!(
+        document["components"]["schemas"]["MyRpcResponse"]["properties"]["message"]["type"],
+        "string"
+    );
+}
+
+#[tokio::test]
+async fn test_rpc_discover() {
+    let router = JsonRpcRouter::new()
+        .method_with_schema("add", add)
+        .method("ping", ping);
+    let base = common::spawn(
+        ServerBuilder::new(ServerConfig::default()).json_rpc_router("/rpc", router.clone()),
+    )
+    .await;
+
+    let response: Value = Client::new()
+        .post(format!("{}/rpc", base))
+        .json(&json!({ "jsonrpc": "2.0", "id": 1, "method": "rpc.discover" }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let document = &response["result"];
+    assert_eq!(document, &router.openrpc());
+    assert_eq!(*document, self::document(&format!("{}/rpc", base)).await);
+
+    let add = &document["methods"][0];
+    assert_eq!(add["name"], "add");
+    assert_eq!(add["params"][0]["name"], "a");
+    assert_eq!(add["params"][0]["required"], true);
+    assert_eq!(add["params"][0]["schema"]["maximum"], 1000);
+    assert_eq!(add["params"][1]["name"], "b");
+    assert_eq!(add["params"][1]["required"], false);
+    assert_eq!(
+        document["components"]["schemas"]["Sum"]["required"],
+        json!(["sum"])
+    );
+
+    // Listed, but with nothing to say about it
+    assert_eq!(
+        document["methods"][1],
+        json!({ "name": "ping", "params": [], "result": { "name": "result", "schema": {} } })
+    );
+    assert_eq!(router.undocumented_methods().collect::<Vec<_>>(), ["ping"]);
+}
+
+#[test]
+#[should_panic(expected = "JSON-RPC method rpc.discover is reserved")]
+fn test_discover_is_reserved() {
+    let _: JsonRpcRouter = JsonRpcRouter::new().method("rpc.discover", ping);
+}
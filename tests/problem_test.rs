@@ -0,0 +1,121 @@
+use rust_http_template::config::ServerConfig;
+use rust_http_template::problem::{field_errors, FieldError, Problem};
+use rust_http_template::ServerBuilder;
+use serde_json::json;
+use validator::{Validate, ValidationError};
+
+mod common;
+
+#[derive(Validate)]
+#[validate(schema(function = "validate_order"))]
+struct Order {
+    #[validate(email(message = "must be an email address we can reach"))]
+    email: String,
+    #[validate(nested)]
+    items: Vec<Item>,
+}
+
+#[derive(Validate)]
+struct Item {
+    #[validate(length(min = 1, max = 20))]
+    name: String,
+    #[validate(range(min = 1))]
+    quantity: u32,
+}
+
+fn validate_order(order: &Order) -> Result<(), ValidationError> {
+    if order.items.is_empty() {
+        return Err(ValidationError::new("empty_order"));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_field_errors_point_at_fields() {
+    let order = Order {
+        email: "nope".to_string(),
+        items: vec![
+            Item {
+                name: "tea".to_string(),
+                quantity: 1,
+            },
+            Item {
+                name: String::new(),
+                quantity: 0,
+            },
+        ],
+    };
+    let errors = field_errors(&order.validate().unwrap_err());
+
+    let summary: Vec<_> = errors
+        .iter()
+        .map(|e| (e.pointer.as_str(), e.code.as_str(), e.detail.as_str()))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            ("/email", "email", "must be an email address we can reach"),
+            (
+                "/items/1/name",
+                "length",
+                "must be between 1 and 20 characters long"
+            ),
+            ("/items/1/quantity", "range", "must be at least 1"),
+        ]
+    );
+    // Rule parameters are kept, the rejected value is not
+    assert_eq!(errors[1].params["min"], 1);
+    assert!(!errors[1].params.contains_key("value"));
+
+    // Errors on the struct itself point at the struct
+    let empty = Order {
+        email: "a@example.com".to_string(),
+        items: Vec::new(),
+    };
+    let errors = field_errors(&empty.validate().unwrap_err());
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        (errors[0].pointer.as_str(), errors[0].code.as_str()),
+        ("", "empty_order")
+    );
+}
+
+#[tokio::test]
+async fn test_validation_error_is_problem_json() {
+    let base =
+        common::spawn(ServerBuilder::new(ServerConfig::default()).with_default_services()).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/echo/json_extractor", base))
+        .header("x-request-id", "req-123")
+        .json(&json!({ "name": "Ab" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/problem+json"
+    );
+
+    let problem: Problem = response.json().await.unwrap();
+    assert_eq!(problem.type_uri, "about:blank");
+    assert_eq!(problem.title, "Bad Request");
+    assert_eq!(problem.status, 400);
+    assert_eq!(problem.code.as_deref(), Some("VALIDATION_FAILED"));
+    assert_eq!(problem.instance.as_deref(), Some("/echo/json_extractor"));
+    assert_eq!(problem.request_id.as_deref(), Some("req-123"));
+    assert_eq!(
+        problem.errors,
+        [FieldError {
+            pointer: "/name".to_string(),
+            code: "length".to_string(),
+            detail: "must be between 3 and 10 characters long".to_string(),
+            params: [
+                ("max".to_string(), json!(10)),
+                ("min".to_string(), json!(3))
+            ]
+            .into(),
+        }]
+    );
+}
@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rust_http_template::config::{
+    QuotaConfig, QuotaLimits, RateLimitConfig, RateLimitPolicy, ServerConfig,
+};
+use rust_http_template::local::Framing;
+use rust_http_template::rate_limiter::{MockClock, QuotaPeriod, QuotaUsage, Quotas};
+use rust_http_template::ServerBuilder;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+mod common;
+
+/// 2024-02-28T23:59:00Z, a minute before a leap day.
+fn leap_day_eve() -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(1_709_164_740)
+}
+
+fn quotas(quota: QuotaConfig, clock: Arc<MockClock>) -> Quotas {
+    let config = RateLimitConfig {
+        quota: Some(quota),
+        ..Default::default()
+    };
+    Quotas::from_config(&config, clock).unwrap()
+}
+
+fn limits(daily: u64, monthly: u64) -> QuotaLimits {
+    QuotaLimits {
+        daily: Some(daily),
+        monthly: Some(monthly),
+    }
+}
+
+#[test]
+fn test_quota_periods() {
+    let now = leap_day_eve();
+    assert_eq!(
+        QuotaPeriod::Daily.current(now),
+        ("2024-02-28".to_string(), Duration::from_secs(60))
+    );
+    // February 2024 has a 29th
+    assert_eq!(
+        QuotaPeriod::Monthly.current(now),
+        ("2024-02".to_string(), Duration::from_secs(60 + 86_400))
+    );
+
+    let new_year = UNIX_EPOCH + Duration::from_secs(1_735_689_599); // 2024-12-31T23:59:59Z
+    assert_eq!(
+        QuotaPeriod::Monthly.current(new_year),
+        ("2024-12".to_string(), Duration::from_secs(1))
+    );
+}
+
+#[tokio::test]
+async fn test_daily_and_monthly_quotas() {
+    let clock = Arc::new(MockClock::at(leap_day_eve()));
+    let quotas = quotas(
+        QuotaConfig {
+            limits: limits(2, 3),
+            ..Default::default()
+        },
+        clock.clone(),
+    );
+
+    assert_eq!(quotas.check("tenant-a").await.unwrap().remaining, 1);
+    assert_eq!(quotas.check("tenant-a").await.unwrap().remaining, 0);
+    let decision = quotas.check("tenant-a").await.unwrap();
+    assert!(!decision.allowed);
+    assert_eq!(decision.retry_after, Some(Duration::from_secs(60)));
+
+    // The daily quota resets at midnight, the monthly one is nearly used up
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(quotas.check("tenant-a").await.unwrap().remaining, 0);
+    let decision = quotas.check("tenant-a").await.unwrap();
+    assert!(!decision.allowed);
+    assert_eq!(decision.retry_after, Some(Duration::from_secs(86_400)));
+
+    // Rejected requests didn't count
+    assert_eq!(
+        quotas.usage("tenant-a").await,
+        vec![
+            QuotaUsage {
+                period: QuotaPeriod::Daily,
+                limit: 2,
+                used: 1,
+                remaining: 1,
+                reset_secs: 86_400,
+            },
+            QuotaUsage {
+                period: QuotaPeriod::Monthly,
+                limit: 3,
+                used: 3,
+                remaining: 0,
+                reset_secs: 86_400,
+            },
+        ]
+    );
+    assert_eq!(quotas.usage("tenant-b").await[1].used, 0);
+}
+
+#[tokio::test]
+async fn test_burst_limit_and_key_overrides() {
+    let clock = Arc::new(MockClock::at(leap_day_eve()));
+    let quotas = quotas(
+        QuotaConfig {
+            burst: Some(RateLimitPolicy {
+                max_requests: 2,
+                window_secs: 1,
+                ..Default::default()
+            }),
+            limits: limits(100, 1000),
+            keys: HashMap::from([("free".to_string(), limits(1, 10))]),
+            ..Default::default()
+        },
+        clock.clone(),
+    );
+
+    assert!(quotas.check("paid").await.unwrap().allowed);
+    assert!(quotas.check("paid").await.unwrap().allowed);
+    let decision = quotas.check("paid").await.unwrap();
+    assert!(!decision.allowed);
+    assert_eq!(decision.limit, 2);
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(quotas.check("paid").await.unwrap().remaining, 97);
+
+    assert!(quotas.check("free").await.unwrap().allowed);
+    clock.advance(Duration::from_secs(1));
+    assert!(!quotas.check("free").await.unwrap().allowed);
+}
+
+#[tokio::test]
+async fn test_quota_rejections_leave_burst_alone() {
+    let clock = Arc::new(MockClock::at(leap_day_eve()));
+    let quotas = quotas(
+        QuotaConfig {
+            burst: Some(RateLimitPolicy {
+                max_requests: 2,
+                window_secs: 3 * 24 * 60 * 60,
+                ..Default::default()
+            }),
+            limits: limits(1, 10),
+            ..Default::default()
+        },
+        clock.clone(),
+    );
+
+    assert!(quotas.check("tenant").await.unwrap().allowed);
+    for _ in 0..3 {
+        assert!(!quotas.check("tenant").await.unwrap().allowed);
+    }
+    // The next day's request still fits in the burst limit
+    clock.advance(Duration::from_secs(60));
+    assert!(quotas.check("tenant").await.unwrap().allowed);
+    clock.advance(Duration::from_secs(24 * 60 * 60));
+    let decision = quotas.check("tenant").await.unwrap();
+    assert!(!decision.allowed);
+    assert_eq!(decision.limit, 2);
+}
+
+#[tokio::test]
+async fn test_quota_endpoint() {
+    let config = ServerConfig {
+        rate_limit: RateLimitConfig {
+            max_requests: 100,
+            quota: Some(QuotaConfig {
+                limits: QuotaLimits {
+                    daily: Some(2),
+                    monthly: None,
+                },
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let base = common::spawn(ServerBuilder::new(config).with_default_services()).await;
+
+    let client = reqwest::Client::new();
+    let stream = format!("{}/stream", base);
+    for expected in [200, 200, 429] {
+        let response = client
+            .get(&stream)
+            .header("x-api-key", "key-1")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), expected);
+    }
+    // Requests without a key only count against the per-IP limit
+    assert_eq!(client.get(&stream).send().await.unwrap().status(), 200);
+
+    let usage: serde_json::Value = client
+        .get(format!("{}/quota", base))
+        .header("x-api-key", "key-1")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(usage["key"], "key-1");
+    assert_eq!(usage["quotas"][0]["period"], "daily");
+    assert_eq!(usage["quotas"][0]["used"], 2);
+    assert_eq!(usage["quotas"][0]["remaining"], 0);
+
+    let response = client.get(format!("{}/quota", base)).send().await.unwrap();
+    assert_eq!(response.status(), 401);
+
+    // A key that isn't valid text is refused rather than ignored
+    let invalid = reqwest::header::HeaderValue::from_bytes(b"key-\xff").unwrap();
+    for url in [&stream, &format!("{}/quota", base)] {
+        let response = client
+            .get(url)
+            .header("x-api-key", invalid.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_requests_cant_overshoot() {
+    let clock = Arc::new(MockClock::at(leap_day_eve()));
+    let quotas = Arc::new(quotas(
+        QuotaConfig {
+            limits: limits(10, 1000),
+            ..Default::default()
+        },
+        clock,
+    ));
+
+    let checks: Vec<_> = (0..50)
+        .map(|_| {
+            let quotas = quotas.clone();
+            tokio::spawn(async move { quotas.check("tenant").await.unwrap().allowed })
+        })
+        .collect();
+    let mut allowed = 0;
+    for check in checks {
+        allowed += usize::from(check.await.unwrap());
+    }
+    assert_eq!(allowed, 10);
+    // Rejected requests were taken back off
+    assert_eq!(quotas.usage("tenant").await[0].used, 10);
+    assert_eq!(quotas.usage("tenant").await[1].used, 10);
+}
+
+fn daily_quota_config(daily: u64) -> ServerConfig {
+    ServerConfig {
+        rate_limit: RateLimitConfig {
+            max_requests: 100,
+            quota: Some(QuotaConfig {
+                limits: QuotaLimits {
+                    daily: Some(daily),
+                    monthly: None,
+                },
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_batch_calls_use_up_quota_one_by_one() {
+    let base =
+        common::spawn(ServerBuilder::new(daily_quota_config(3)).with_default_services()).await;
+    let batch: Vec<Value> = (0..5)
+        .map(|id| json!({ "jsonrpc": "2.0", "id": id, "method": "my_rpc", "params": ["Alice"] }))
+        .collect();
+
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("{}/json_rpc", base))
+        .header("x-api-key", "key-1")
+        .json(&batch)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let mut responses: Vec<Value> = response.json().await.unwrap();
+    responses.sort_by_key(|response| response["id"].as_i64());
+    // The request itself pays for the first call
+    for response in &responses[..3] {
+        assert_eq!(response["result"]["message"], "Hello, Alice!");
+    }
+    for response in &responses[3..] {
+        assert_eq!(response["error"]["code"], -32029);
+    }
+
+    let usage: Value = client
+        .get(format!("{}/quota", base))
+        .header("x-api-key", "key-1")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(usage["quotas"][0]["used"], 3);
+}
+
+#[tokio::test]
+async fn test_local_calls_use_up_quota() {
+    let socket = std::env::temp_dir().join(format!("{}.sock", uuid::Uuid::new_v4()));
+    let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+    tokio::spawn(
+        ServerBuilder::new(daily_quota_config(2))
+            .with_default_services()
+            .serve_unix(listener, Framing::Lines),
+    );
+    let stream = tokio::net::UnixStream::connect(&socket).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let mut codes = Vec::new();
+    for id in 0..3 {
+        let call = json!({ "jsonrpc": "2.0", "id": id, "method": "my_rpc", "params": ["Alice"] });
+        writer
+            .write_all(format!("{}\n", call).as_bytes())
+            .await
+            .unwrap();
+        let line = lines.next_line().await.unwrap().unwrap();
+        let response: Value = serde_json::from_str(&line).unwrap();
+        codes.push(response["error"]["code"].clone());
+    }
+    assert_eq!(codes, [Value::Null, Value::Null, json!(-32029)]);
+
+    let _ = std::fs::remove_file(socket);
+}
@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rust_http_template::config::{
+    QuotaConfig, QuotaLimits, RateLimitConfig, RateLimitStoreConfig, ServerConfig,
+};
+use rust_http_template::local::Framing;
+use rust_http_template::rate_limiter::{
+    FixedWindow, InMemoryRateLimiter, Quotas, RateLimiter, RedisStore, StoreRateLimiter,
+    SystemClock,
+};
+use rust_http_template::ServerBuilder;
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::task::{JoinHandle, JoinSet};
+
+mod common;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+type Keys = Arc<Mutex<HashMap<String, (i64, Option<Instant>)>>>;
+
+/// Just enough of a Redis server for `RedisStore`: RESP2 over TCP with GET, SET, INCRBY,
+/// PTTL, MULTI/EXEC and its decrement script. Aborting the returned task drops every connection.
+async fn spawn_store(addr: SocketAddr, keys: Keys) -> JoinHandle<()> {
+    let listener = TcpListener::bind(addr).await.unwrap();
+    tokio::spawn(async move {
+        let mut connections = JoinSet::new();
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            connections.spawn(serve_store(stream, keys.clone()));
+        }
+    })
+}
+
+async fn serve_store(stream: TcpStream, keys: Keys) {
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+    let mut queued: Option<Vec<Vec<String>>> = None;
+
+    while let Some(command) = read_command(&mut read).await {
+        let reply = match (command[0].to_uppercase().as_str(), &mut queued) {
+            ("MULTI", _) => {
+                queued = Some(Vec::new());
+                b"+OK\r\n".to_vec()
+            }
+            ("EXEC", Some(_)) => {
+                let commands = queued.take().unwrap();
+                let mut reply = format!("*{}\r\n", commands.len()).into_bytes();
+                for command in commands {
+                    reply.extend(execute(&command, &keys));
+                }
+                reply
+            }
+            (_, Some(commands)) => {
+                commands.push(command);
+                b"+QUEUED\r\n".to_vec()
+            }
+            (_, None) => execute(&command, &keys),
+        };
+        if write.write_all(&reply).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn read_command(read: &mut BufReader<tokio::net::tcp::OwnedReadHalf>) -> Option<Vec<String>> {
+    let mut line = String::new();
+    read.read_line(&mut line).await.ok().filter(|n| *n > 0)?;
+    let count: usize = line.trim().strip_prefix('*')?.parse().ok()?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        line.clear();
+        read.read_line(&mut line).await.ok()?;
+        let len: usize = line.trim().strip_prefix('$')?.parse().ok()?;
+        let mut arg = vec![0; len + 2];
+        read.read_exact(&mut arg).await.ok()?;
+        arg.truncate(len);
+        args.push(String::from_utf8(arg).ok()?);
+    }
+    Some(args)
+}
+
+fn execute(command: &[String], keys: &Keys) -> Vec<u8> {
+    let mut keys = keys.lock().unwrap();
+    let now = Instant::now();
+    keys.retain(|_, (_, expires)| expires.is_none_or(|at| at > now));
+
+    let reply = match command[0].to_uppercase().as_str() {
+        "PING" => "+PONG\r\n".to_string(),
+        "CLIENT" | "SELECT" => "+OK\r\n".to_string(),
+        "SET" => {
+            let nx = command.iter().any(|arg| arg.eq_ignore_ascii_case("NX"));
+            let px = command
+                .iter()
+                .position(|arg| arg.eq_ignore_ascii_case("PX"))
+                .map(|i| Duration::from_millis(command[i + 1].parse().unwrap()));
+            if nx && keys.contains_key(&command[1]) {
+                "$-1\r\n".to_string()
+            } else {
+                let value = command[2].parse().unwrap();
+                keys.insert(command[1].clone(), (value, px.map(|px| now + px)));
+                "+OK\r\n".to_string()
+            }
+        }
+        "GET" => match keys.get(&command[1]) {
+            Some((value, _)) => {
+                let value = value.to_string();
+                format!("${}\r\n{}\r\n", value.len(), value)
+            }
+            None => "$-1\r\n".to_string(),
+        },
+        "INCRBY" => {
+            let entry = keys.entry(command[1].clone()).or_insert((0, None));
+            entry.0 += command[2].parse::<i64>().unwrap();
+            format!(":{}\r\n", entry.0)
+        }
+        // The only script `RedisStore` runs: EVAL <decrement> 1 <key> <cost>
+        "EVAL" => {
+            if let Some((value, _)) = keys.get_mut(&command[3]) {
+                *value -= (*value).min(command[4].parse().unwrap());
+            }
+            "$-1\r\n".to_string()
+        }
+        "PTTL" => match keys.get(&command[1]) {
+            Some((_, Some(at))) => format!(":{}\r\n", (*at - now).as_millis()),
+            Some((_, None)) => ":-1\r\n".to_string(),
+            None => ":-2\r\n".to_string(),
+        },
+        other => format!("-ERR unknown command '{}'\r\n", other),
+    };
+    reply.into_bytes()
+}
+
+/// An address nothing is listening on.
+async fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+fn store_config(addr: SocketAddr) -> RateLimitStoreConfig {
+    RateLimitStoreConfig {
+        url: format!("redis://{}", addr),
+        retry_interval_secs: 0,
+        ..Default::default()
+    }
+}
+
+/// What one replica would build: a limiter of `limit` per minute shared through the store.
+fn replica(addr: SocketAddr, limit: u64) -> StoreRateLimiter {
+    let store = RedisStore::new(&store_config(addr)).unwrap();
+    let fallback = InMemoryRateLimiter::new(FixedWindow::new(limit, WINDOW));
+    StoreRateLimiter::new(
+        Arc::new(store),
+        "test:default",
+        limit,
+        WINDOW,
+        Arc::new(fallback),
+    )
+}
+
+#[tokio::test]
+async fn test_replicas_share_budget() {
+    let addr = free_addr().await;
+    let keys = Keys::default();
+    spawn_store(addr, keys.clone()).await;
+    let (a, b) = (replica(addr, 3), replica(addr, 3));
+
+    assert_eq!(a.check("10.0.0.1").await.remaining, 2);
+    assert_eq!(b.check("10.0.0.1").await.remaining, 1);
+    assert_eq!(a.check_cost("10.0.0.1", 1).await.remaining, 0);
+
+    for replica in [&a, &b] {
+        let decision = replica.check("10.0.0.1").await;
+        assert!(!decision.allowed);
+        let retry_after = decision.retry_after.unwrap();
+        assert!(retry_after <= WINDOW && retry_after > WINDOW - Duration::from_secs(5));
+    }
+    // Other clients have a budget of their own
+    assert!(b.check("10.0.0.2").await.allowed);
+
+    let keys = keys.lock().unwrap();
+    assert_eq!(keys["test:default:10.0.0.1"].0, 5);
+    assert!(keys["test:default:10.0.0.1"].1.is_some());
+    // Nothing was limited locally
+    assert_eq!(a.metrics().tracked_keys, 0);
+}
+
+#[tokio::test]
+async fn test_falls_back_to_local_limits() {
+    let addr = free_addr().await;
+    let limiter = replica(addr, 2);
+
+    // Nothing is listening, so each replica enforces the limit on its own
+    assert!(limiter.check("10.0.0.1").await.allowed);
+    assert!(limiter.check("10.0.0.1").await.allowed);
+    assert!(!limiter.check("10.0.0.1").await.allowed);
+    assert_eq!(limiter.metrics().tracked_keys, 1);
+
+    // Once the store is reachable the shared counters take over again
+    let keys = Keys::default();
+    let store = spawn_store(addr, keys.clone()).await;
+    assert_eq!(limiter.check("10.0.0.1").await.remaining, 1);
+    assert_eq!(keys.lock().unwrap()["test:default:10.0.0.1"].0, 1);
+
+    // And when it goes away mid-connection we fall back again
+    store.abort();
+    let _ = store.await;
+    assert!(!limiter.check("10.0.0.1").await.allowed);
+    assert_eq!(keys.lock().unwrap()["test:default:10.0.0.1"].0, 1);
+}
+
+#[tokio::test]
+async fn test_servers_share_budget_through_store() {
+    let store_addr = free_addr().await;
+    spawn_store(store_addr, Keys::default()).await;
+
+    let mut urls = Vec::new();
+    for _ in 0..2 {
+        let config = ServerConfig {
+            rate_limit: RateLimitConfig {
+                max_requests: 2,
+                store: Some(store_config(store_addr)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let base = common::spawn(ServerBuilder::new(config).with_default_services()).await;
+        urls.push(format!("{}/stream", base));
+    }
+
+    let client = reqwest::Client::new();
+    for (url, expected) in [(&urls[0], 200), (&urls[1], 200), (&urls[0], 429)] {
+        let response = client.get(url).send().await.unwrap();
+        assert_eq!(response.status(), expected);
+        assert_eq!(response.headers()["ratelimit-limit"], "2");
+    }
+}
+
+#[tokio::test]
+async fn test_replicas_share_quotas() {
+    let addr = free_addr().await;
+    let keys = Keys::default();
+    spawn_store(addr, keys.clone()).await;
+    let config = RateLimitConfig {
+        store: Some(store_config(addr)),
+        quota: Some(QuotaConfig {
+            limits: QuotaLimits {
+                daily: None,
+                monthly: Some(2),
+            },
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let replica = || Quotas::from_config(&config, Arc::new(SystemClock)).unwrap();
+    let (a, b) = (replica(), replica());
+
+    assert!(a.check("tenant-a").await.unwrap().allowed);
+    assert!(b.check("tenant-a").await.unwrap().allowed);
+    assert!(!a.check("tenant-a").await.unwrap().allowed);
+    // The rejected request was counted in the store and taken back off
+    assert_eq!(b.usage("tenant-a").await[0].used, 2);
+
+    // Counters expire when the month ends
+    let keys = keys.lock().unwrap();
+    let (key, (count, expires)) = keys.iter().next().unwrap();
+    assert!(key.starts_with("rate_limit:quota:") && key.ends_with(":tenant-a"));
+    assert_eq!(*count, 2);
+    assert!(expires.unwrap() <= Instant::now() + Duration::from_secs(31 * 86_400));
+}
+
+#[tokio::test]
+async fn test_local_calls_have_a_key_of_their_own() {
+    use tower::ServiceExt;
+
+    let addr = free_addr().await;
+    let keys = Keys::default();
+    spawn_store(addr, keys.clone()).await;
+    // Two replicas sharing the store, one serving a Unix socket and one HTTP
+    let config = ServerConfig {
+        rate_limit: RateLimitConfig {
+            max_requests: 2,
+            store: Some(store_config(addr)),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let builder = || ServerBuilder::new(config.clone()).with_default_services();
+
+    let socket = std::env::temp_dir().join(format!("{}.sock", uuid::Uuid::new_v4()));
+    let listener = UnixListener::bind(&socket).unwrap();
+    tokio::spawn(builder().serve_unix(listener, Framing::Lines));
+    let stream = UnixStream::connect(&socket).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    for id in 0..2 {
+        let call = json!({ "jsonrpc": "2.0", "id": id, "method": "my_rpc", "params": ["Alice"] });
+        writer
+            .write_all(format!("{}\n", call).as_bytes())
+            .await
+            .unwrap();
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert!(line.contains("Hello, Alice!"), "{}", line);
+    }
+    let _ = std::fs::remove_file(socket);
+
+    // Requests whose client address is unknown still have their whole budget
+    let app = builder().build();
+    let request = || {
+        axum::http::Request::post("/json_rpc")
+            .header("content-type", "application/json")
+            .body(axum::body::Body::from(
+                json!({ "jsonrpc": "2.0", "id": 1, "method": "my_rpc", "params": ["Bob"] })
+                    .to_string(),
+            ))
+            .unwrap()
+    };
+    let response = app.oneshot(request()).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["ratelimit-remaining"], "1");
+
+    let keys = keys.lock().unwrap();
+    assert_eq!(keys["rate_limit:default:local:unix"].0, 2);
+    assert_eq!(keys["rate_limit:default:unknown"].0, 1);
+}
@@ -0,0 +1,452 @@
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rust_http_template::config::{RateLimitAlgorithm, RateLimitConfig, ServerConfig};
+use rust_http_template::grpc::hello_world::helloworld::{
+    greeter_client::GreeterClient, HelloRequest,
+};
+use rust_http_template::rate_limiter::{
+    from_config, Algorithm, FixedWindow, Gcra, InMemoryRateLimiter, MockClock, RateLimiter,
+    RateLimiterMetrics, SlidingWindowCounter, SlidingWindowLog, TokenBucket,
+};
+use rust_http_template::ServerBuilder;
+
+mod common;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+fn secs(s: u64) -> Duration {
+    Duration::from_secs(s)
+}
+
+/// A limiter allowing 3 requests per minute on a clock that only moves when advanced.
+fn limiter<A: Algorithm>(algorithm: A) -> (InMemoryRateLimiter<A>, Arc<MockClock>) {
+    let clock = Arc::new(MockClock::default());
+    (
+        InMemoryRateLimiter::with_clock(algorithm, clock.clone()),
+        clock,
+    )
+}
+
+/// Sends `n` requests for `key` and returns the `remaining` of each.
+async fn remaining(limiter: &impl RateLimiter, key: &str, n: usize) -> Vec<u64> {
+    let mut remaining = Vec::new();
+    for _ in 0..n {
+        let decision = limiter.check(key).await;
+        assert!(decision.allowed, "request rejected: {:?}", decision);
+        remaining.push(decision.remaining);
+    }
+    remaining
+}
+
+async fn retry_after(limiter: &impl RateLimiter, key: &str) -> Duration {
+    let decision = limiter.check(key).await;
+    assert!(!decision.allowed, "request allowed: {:?}", decision);
+    assert_eq!(decision.remaining, 0);
+    decision.retry_after.unwrap()
+}
+
+#[tokio::test]
+async fn test_fixed_window() {
+    let (limiter, clock) = limiter(FixedWindow::new(3, WINDOW));
+
+    assert_eq!(remaining(&limiter, "a", 3).await, [2, 1, 0]);
+    assert_eq!(retry_after(&limiter, "a").await, secs(60));
+    // Keys are counted separately
+    assert_eq!(remaining(&limiter, "b", 1).await, [2]);
+
+    clock.advance(secs(59));
+    assert_eq!(retry_after(&limiter, "a").await, secs(1));
+    clock.advance(secs(1));
+    assert_eq!(remaining(&limiter, "a", 3).await, [2, 1, 0]);
+}
+
+#[tokio::test]
+async fn test_sliding_window_log() {
+    let (limiter, clock) = limiter(SlidingWindowLog::new(3, WINDOW));
+
+    assert_eq!(remaining(&limiter, "a", 1).await, [2]);
+    clock.advance(secs(30));
+    assert_eq!(remaining(&limiter, "a", 2).await, [1, 0]);
+    // The first request leaves the window 60s after it was made
+    assert_eq!(retry_after(&limiter, "a").await, secs(30));
+
+    clock.advance(secs(30));
+    assert_eq!(remaining(&limiter, "a", 1).await, [0]);
+    assert_eq!(retry_after(&limiter, "a").await, secs(30));
+}
+
+#[tokio::test]
+async fn test_sliding_window_counter() {
+    let (limiter, clock) = limiter(SlidingWindowCounter::new(3, WINDOW));
+
+    assert_eq!(remaining(&limiter, "a", 3).await, [2, 1, 0]);
+    // 20s into the next window the previous 3 requests weigh 3 * 40/60 = 2
+    assert_eq!(retry_after(&limiter, "a").await, secs(80));
+
+    // Unlike a fixed window, the new window doesn't start empty
+    clock.advance(secs(60));
+    assert_eq!(retry_after(&limiter, "a").await, secs(20));
+    clock.advance(secs(20));
+    assert_eq!(remaining(&limiter, "a", 1).await, [0]);
+    assert_eq!(retry_after(&limiter, "a").await, secs(20));
+
+    // Two windows later nothing is left
+    clock.advance(secs(120));
+    assert_eq!(remaining(&limiter, "a", 3).await, [2, 1, 0]);
+}
+
+#[tokio::test]
+async fn test_token_bucket() {
+    let (limiter, clock) = limiter(TokenBucket::new(3, WINDOW));
+
+    assert_eq!(remaining(&limiter, "a", 3).await, [2, 1, 0]);
+    // A token is refilled every 20s
+    assert_eq!(retry_after(&limiter, "a").await, secs(20));
+
+    clock.advance(secs(10));
+    assert_eq!(retry_after(&limiter, "a").await, secs(10));
+    clock.advance(secs(10));
+    assert_eq!(remaining(&limiter, "a", 1).await, [0]);
+
+    // The bucket never holds more than the limit
+    clock.advance(secs(600));
+    assert_eq!(remaining(&limiter, "a", 3).await, [2, 1, 0]);
+    assert_eq!(retry_after(&limiter, "a").await, secs(20));
+}
+
+#[tokio::test]
+async fn test_gcra() {
+    let (limiter, clock) = limiter(Gcra::new(3, WINDOW));
+
+    assert_eq!(remaining(&limiter, "a", 3).await, [2, 1, 0]);
+    assert_eq!(retry_after(&limiter, "a").await, secs(20));
+
+    clock.advance(secs(20));
+    assert_eq!(remaining(&limiter, "a", 1).await, [0]);
+    clock.advance(secs(40));
+    assert_eq!(remaining(&limiter, "a", 2).await, [1, 0]);
+
+    clock.advance(secs(600));
+    assert_eq!(remaining(&limiter, "a", 3).await, [2, 1, 0]);
+}
+
+#[tokio::test]
+async fn test_cost_weights() {
+    let algorithms: [(&str, Arc<dyn RateLimiter>); 5] = [
+        (
+            "fixed_window",
+            Arc::new(limiter(FixedWindow::new(6, WINDOW)).0),
+        ),
+        (
+            "sliding_log",
+            Arc::new(limiter(SlidingWindowLog::new(6, WINDOW)).0),
+        ),
+        (
+            "sliding_counter",
+            Arc::new(limiter(SlidingWindowCounter::new(6, WINDOW)).0),
+        ),
+        (
+            "token_bucket",
+            Arc::new(limiter(TokenBucket::new(6, WINDOW)).0),
+        ),
+        ("gcra", Arc::new(limiter(Gcra::new(6, WINDOW)).0)),
+    ];
+
+    for (name, limiter) in algorithms {
+        assert_eq!(limiter.check_cost("a", 4).await.remaining, 2, "{}", name);
+        // Doesn't fit in what is left, and doesn't use any of it up either
+        assert!(!limiter.check_cost("a", 3).await.allowed, "{}", name);
+        assert_eq!(limiter.check_cost("a", 2).await.remaining, 0, "{}", name);
+        assert!(!limiter.check("a").await.allowed, "{}", name);
+        // More than the whole budget is charged as the whole budget
+        let decision = limiter.check_cost("b", 7).await;
+        assert!(decision.allowed, "{}", name);
+        assert_eq!(decision.remaining, 0, "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn test_from_config_selects_algorithm() {
+    // How long the 4th request in a burst has to wait tells the algorithms apart
+    let cases = [
+        (RateLimitAlgorithm::FixedWindow, 60),
+        (RateLimitAlgorithm::SlidingWindowLog, 60),
+        (RateLimitAlgorithm::SlidingWindowCounter, 80),
+        (RateLimitAlgorithm::TokenBucket, 20),
+        (RateLimitAlgorithm::Gcra, 20),
+    ];
+
+    for (algorithm, expected) in cases {
+        let config = RateLimitConfig {
+            algorithm,
+            max_requests: 3,
+            window_secs: 60,
+            ..Default::default()
+        };
+        let limiter = from_config(&config, Arc::new(MockClock::default()));
+
+        for _ in 0..3 {
+            assert!(limiter.check("a").await.allowed);
+        }
+        let decision = limiter.check("a").await;
+        assert!(!decision.allowed);
+        assert_eq!(decision.limit, 3);
+        assert_eq!(
+            decision.retry_after,
+            Some(secs(expected)),
+            "{:?}",
+            algorithm
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_max_keys_evicts_least_recently_used() {
+    let (limiter, _clock) = limiter(FixedWindow::new(3, WINDOW));
+    // With a single shard the cap is an exact LRU
+    let limiter = limiter
+        .with_shards(NonZeroUsize::new(1).unwrap())
+        .with_max_keys(NonZeroUsize::new(2).unwrap());
+
+    remaining(&limiter, "a", 3).await;
+    remaining(&limiter, "b", 1).await;
+    retry_after(&limiter, "a").await; // touches "a", so "b" is now the oldest
+    remaining(&limiter, "c", 1).await;
+
+    assert_eq!(
+        limiter.metrics(),
+        RateLimiterMetrics {
+            tracked_keys: 2,
+            expired: 0,
+            evicted: 1,
+        }
+    );
+    // "a" is still limited, "b" starts over
+    retry_after(&limiter, "a").await;
+    assert_eq!(remaining(&limiter, "b", 1).await, [2]);
+    assert_eq!(limiter.metrics().evicted, 2);
+}
+
+#[tokio::test]
+async fn test_max_keys_holds_across_shards() {
+    for (shards, max_keys) in [(16, 3), (4, 10)] {
+        let (limiter, _clock) = limiter(FixedWindow::new(3, WINDOW));
+        let limiter = limiter
+            .with_shards(NonZeroUsize::new(shards).unwrap())
+            .with_max_keys(NonZeroUsize::new(max_keys).unwrap());
+
+        for i in 0..100 {
+            limiter.check(&format!("key-{}", i)).await;
+        }
+        let metrics = limiter.metrics();
+        assert!(metrics.tracked_keys <= max_keys, "{:?}", metrics);
+        assert_eq!(metrics.evicted, 100 - metrics.tracked_keys as u64);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_checks_across_shards() {
+    let (limiter, _clock) = limiter(FixedWindow::new(50, WINDOW));
+    let limiter = Arc::new(limiter);
+
+    let tasks: Vec<_> = (0..8)
+        .map(|_| {
+            let limiter = limiter.clone();
+            tokio::spawn(async move {
+                let mut allowed = 0;
+                for i in 0..1000 {
+                    if limiter.check(&format!("key-{}", i % 10)).await.allowed {
+                        allowed += 1;
+                    }
+                }
+                allowed
+            })
+        })
+        .collect();
+
+    let mut allowed = 0;
+    for task in tasks {
+        allowed += task.await.unwrap();
+    }
+    // Every key lets exactly its limit through, however the checks interleave
+    assert_eq!(allowed, 10 * 50);
+    assert_eq!(limiter.metrics().tracked_keys, 10);
+}
+
+#[tokio::test]
+async fn test_sweep_drops_expired_keys() {
+    for algorithm in [
+        RateLimitAlgorithm::FixedWindow,
+        RateLimitAlgorithm::SlidingWindowLog,
+        RateLimitAlgorithm::SlidingWindowCounter,
+        RateLimitAlgorithm::TokenBucket,
+        RateLimitAlgorithm::Gcra,
+    ] {
+        let config = RateLimitConfig {
+            algorithm,
+            max_requests: 3,
+            window_secs: 60,
+            ..Default::default()
+        };
+        let clock = Arc::new(MockClock::default());
+        let limiter = from_config(&config, clock.clone());
+
+        limiter.check("a").await;
+        limiter.check("b").await;
+        clock.advance(secs(10));
+        assert_eq!(limiter.sweep().await, 0, "{:?}", algorithm);
+
+        // Two windows after its last request every algorithm has fully reset a key
+        clock.advance(secs(110));
+        assert_eq!(limiter.sweep().await, 2, "{:?}", algorithm);
+        assert_eq!(limiter.sweep().await, 0, "{:?}", algorithm);
+
+        assert_eq!(
+            limiter.metrics(),
+            RateLimiterMetrics {
+                tracked_keys: 0,
+                expired: 2,
+                evicted: 0,
+            }
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint() {
+    let base =
+        common::spawn(ServerBuilder::new(ServerConfig::default()).with_default_services()).await;
+
+    let client = reqwest::Client::new();
+    client.get(format!("{}/stream", base)).send().await.unwrap();
+
+    let response = client
+        .get(format!("{}/metrics", base))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body = response.text().await.unwrap();
+    println!("{}", body);
+    assert!(body.contains("rate_limiter_tracked_keys{policy=\"default\"} 1\n"));
+    assert!(
+        body.contains("rate_limiter_evictions_total{policy=\"default\",reason=\"capacity\"} 0\n")
+    );
+}
+
+#[tokio::test]
+async fn test_rate_limit_headers() {
+    let config = ServerConfig {
+        rate_limit: RateLimitConfig {
+            max_requests: 2,
+            window_secs: 60,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let base = common::spawn(ServerBuilder::new(config).with_default_services()).await;
+    let url = format!("{}/stream", base);
+
+    let client = reqwest::Client::new();
+    for remaining in ["1", "0"] {
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let headers = response.headers();
+        assert_eq!(headers["ratelimit-limit"], "2");
+        assert_eq!(headers["ratelimit-remaining"], remaining);
+        assert_eq!(headers["ratelimit-reset"], "60");
+        assert!(headers.get("retry-after").is_none());
+    }
+
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.status(), 429);
+    let headers = response.headers();
+    assert_eq!(headers["ratelimit-limit"], "2");
+    assert_eq!(headers["ratelimit-remaining"], "0");
+    let retry_after: u64 = headers["retry-after"].to_str().unwrap().parse().unwrap();
+    assert!((59..=60).contains(&retry_after), "{}", retry_after);
+    assert_eq!(headers["content-type"], "application/problem+json");
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["status"], 429);
+    assert_eq!(
+        problem["detail"],
+        format!("Rate limit exceeded: try again in {} seconds", retry_after)
+    );
+}
+
+#[tokio::test]
+async fn test_route_and_method_policies() {
+    let config: ServerConfig = serde_json::from_value(serde_json::json!({
+        "rate_limit": {
+            "max_requests": 100,
+            "policies": {
+                "tight": { "max_requests": 2 },
+                "rpc": { "max_requests": 3 },
+            },
+            "routes": { "/stream": { "policy": "tight" } },
+            "grpc_methods": {
+                "/helloworld.Greeter/SayHello": { "policy": "tight", "cost": 2 },
+            },
+            "rpc_methods": { "greeting_rpc": { "policy": "rpc", "cost": 2 } },
+        },
+    }))
+    .unwrap();
+    let base = common::spawn(ServerBuilder::new(config).with_default_services()).await;
+    let client = reqwest::Client::new();
+
+    // `/stream` has a budget of its own, the other routes still use the default one
+    for expected in [200, 200, 429] {
+        let response = client.get(format!("{}/stream", base)).send().await.unwrap();
+        assert_eq!(response.status(), expected);
+        assert_eq!(response.headers()["ratelimit-limit"], "2");
+    }
+    let response = client.get(format!("{}/sse", base)).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["ratelimit-limit"], "100");
+
+    // SayHello shares the "tight" budget, which `/stream` already used up
+    let mut grpc = GreeterClient::connect(base.clone()).await.unwrap();
+    let hello = || HelloRequest {
+        name: "World".to_string(),
+    };
+    let status = grpc.say_hello(hello()).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+
+    // JSON-RPC calls pay for their method on top of the request to `/json_rpc`
+    let call = |method: &str, id: Option<u64>| {
+        let mut request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": { "name": "World", "language": "english" },
+        });
+        if let Some(id) = id {
+            request["id"] = id.into();
+        }
+        client
+            .post(format!("{}/json_rpc", base))
+            .json(&request)
+            .send()
+    };
+    let response = call("greeting_rpc", Some(1)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["ratelimit-limit"], "100");
+    // A rejected call still gets a JSON-RPC response, along with the headers
+    let response = call("greeting_rpc", Some(2)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["ratelimit-limit"], "3");
+    assert_eq!(response.headers()["ratelimit-remaining"], "0");
+    assert!(response.headers().contains_key("retry-after"));
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["jsonrpc"], "2.0");
+    assert_eq!(body["id"], 2);
+    assert_eq!(body["error"]["code"], -32029);
+    // and a rejected notification gets none
+    let response = call("greeting_rpc", None).await.unwrap();
+    assert_eq!(response.status(), 204);
+    assert_eq!(response.headers()["ratelimit-remaining"], "0");
+    assert!(response.text().await.unwrap().is_empty());
+    // Other methods aren't affected
+    assert_eq!(call("my_rpc", Some(3)).await.unwrap().status(), 200);
+}
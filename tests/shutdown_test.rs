@@ -0,0 +1,87 @@
+use std::time::{Duration, Instant};
+
+use axum::routing::get;
+use futures_util::stream::StreamExt;
+use reqwest::Client;
+use rust_http_template::config::ServerConfig;
+use rust_http_template::ServerBuilder;
+
+mod common;
+
+async fn slow() -> &'static str {
+    tokio::time::sleep(Duration::from_secs(30)).await;
+    "done"
+}
+
+#[tokio::test]
+async fn test_shutdown_drains_streams() {
+    let mut config = ServerConfig::default();
+    config.shutdown.drain_delay_secs = 1;
+    config.shutdown.deadline_secs = 5;
+
+    let builder = ServerBuilder::new(config).with_default_services();
+    let shutdown = builder.shutdown_handle();
+    let (addr, server) = common::serve(builder).await;
+    let base_url = format!("http://{}", addr);
+    let client = Client::new();
+
+    let response = client
+        .get(format!("{}/readyz", base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let sse = client
+        .get(format!("{}/sse", base_url))
+        .send()
+        .await
+        .unwrap();
+    shutdown.trigger();
+    assert_eq!(shutdown.in_flight(), vec!["SSE GET /sse"]);
+
+    // Still accepting during the drain delay, but no longer ready
+    let response = client
+        .get(format!("{}/readyz", base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 503);
+
+    // The stream is allowed to finish
+    let mut body = sse.bytes_stream();
+    let mut accumulated = String::new();
+    while let Some(chunk) = body.next().await {
+        accumulated.push_str(&String::from_utf8_lossy(&chunk.unwrap()));
+    }
+    assert!(accumulated.contains("data: !"));
+
+    server.await.unwrap().unwrap();
+    assert!(shutdown.in_flight().is_empty());
+}
+
+#[tokio::test]
+async fn test_shutdown_deadline_cuts_off_requests() {
+    let mut config = ServerConfig::default();
+    config.shutdown.deadline_secs = 1;
+
+    let builder = ServerBuilder::new(config).merge(axum::Router::new().route("/slow", get(slow)));
+    let shutdown = builder.shutdown_handle();
+    let (addr, server) = common::serve(builder).await;
+    let base_url = format!("http://{}", addr);
+
+    let request = tokio::spawn(Client::new().get(format!("{}/slow", base_url)).send());
+    while shutdown.in_flight().is_empty() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    assert_eq!(shutdown.in_flight(), vec!["HTTP GET /slow"]);
+
+    let started = Instant::now();
+    shutdown.trigger();
+    server.await.unwrap().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // The connection was force-closed rather than answered
+    assert!(request.await.unwrap().is_err());
+}
@@ -0,0 +1,205 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use axum::{extract::Request, routing::get};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
+use reqwest::{Certificate as ReqwestCertificate, Client, Identity};
+use rust_http_template::config::{ServerConfig, TlsConfig};
+use rust_http_template::tls::PeerIdentity;
+use rust_http_template::ServerBuilder;
+
+mod common;
+
+struct Ca {
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl Ca {
+    fn new() -> Self {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Test CA");
+        let cert = params.self_signed(&key).unwrap();
+        Self { cert, key }
+    }
+
+    /// Returns the cert and key PEMs for a leaf signed by this CA.
+    fn issue(&self, common_name: &str, usage: ExtendedKeyUsagePurpose) -> (String, String) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        params.extended_key_usages = vec![usage];
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+        (cert.pem(), key.serialize_pem())
+    }
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("{}-tls-{}", std::process::id(), name));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+async fn whoami(req: Request) -> String {
+    match req.extensions().get::<PeerIdentity>() {
+        Some(peer) => peer.common_name.clone().unwrap_or_default(),
+        None => "anonymous".to_string(),
+    }
+}
+
+/// Serves the default services plus `/whoami` over TLS, returning the HTTPS base URL.
+async fn spawn(tls: TlsConfig) -> String {
+    let config = ServerConfig {
+        tls: Some(tls),
+        ..Default::default()
+    };
+    let (addr, _) = common::serve(
+        ServerBuilder::new(config)
+            .with_default_services()
+            .merge(axum::Router::new().route("/whoami", get(whoami))),
+    )
+    .await;
+    format!("https://localhost:{}", addr.port())
+}
+
+fn client(ca: &Ca, identity: Option<(&str, &str)>) -> Client {
+    let mut builder = Client::builder()
+        .use_rustls_tls()
+        .tls_info(true)
+        .add_root_certificate(ReqwestCertificate::from_pem(ca.cert.pem().as_bytes()).unwrap());
+    if let Some((cert, key)) = identity {
+        builder =
+            builder.identity(Identity::from_pem(format!("{}{}", cert, key).as_bytes()).unwrap());
+    }
+    builder.build().unwrap()
+}
+
+fn peer_certificate(response: &reqwest::Response) -> Vec<u8> {
+    response
+        .extensions()
+        .get::<reqwest::tls::TlsInfo>()
+        .and_then(|info| info.peer_certificate())
+        .unwrap()
+        .to_vec()
+}
+
+#[tokio::test]
+async fn test_tls_with_alpn_and_reload() {
+    let ca = Ca::new();
+    let dir = temp_dir("reload");
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    let (cert, key) = ca.issue("server-1", ExtendedKeyUsagePurpose::ServerAuth);
+    std::fs::write(&cert_path, &cert).unwrap();
+    std::fs::write(&key_path, &key).unwrap();
+
+    let base_url = spawn(TlsConfig {
+        cert_path: cert_path.to_str().unwrap().to_string(),
+        key_path: key_path.to_str().unwrap().to_string(),
+        client_ca_path: None,
+        client_auth_optional: false,
+        reload_interval_secs: 1,
+    })
+    .await;
+
+    let response = client(&ca, None)
+        .post(format!("{}/echo/json", base_url))
+        .json(&serde_json::json!({ "hello": "tls" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.version(), reqwest::Version::HTTP_2); // negotiated through ALPN
+    let first_cert = peer_certificate(&response);
+
+    // Rotate the certificate on disk, new connections should get the new one
+    let (cert, key) = ca.issue("server-2", ExtendedKeyUsagePurpose::ServerAuth);
+    std::fs::write(&cert_path, &cert).unwrap();
+    std::fs::write(&key_path, &key).unwrap();
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+
+    let response = client(&ca, None)
+        .get(format!("{}/readyz", base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_ne!(peer_certificate(&response), first_cert);
+}
+
+#[tokio::test]
+async fn test_mtls_peer_identity() {
+    let ca = Ca::new();
+    let dir = temp_dir("mtls");
+    let (cert, key) = ca.issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+    std::fs::write(dir.join("cert.pem"), &cert).unwrap();
+    std::fs::write(dir.join("key.pem"), &key).unwrap();
+    std::fs::write(dir.join("ca.pem"), ca.cert.pem()).unwrap();
+
+    let base_url = spawn(TlsConfig {
+        cert_path: dir.join("cert.pem").to_str().unwrap().to_string(),
+        key_path: dir.join("key.pem").to_str().unwrap().to_string(),
+        client_ca_path: Some(dir.join("ca.pem").to_str().unwrap().to_string()),
+        client_auth_optional: false,
+        reload_interval_secs: 0,
+    })
+    .await;
+
+    // No client certificate, the handshake is rejected
+    assert!(client(&ca, None)
+        .get(format!("{}/whoami", base_url))
+        .send()
+        .await
+        .is_err());
+
+    // A certificate from some other CA is rejected too
+    let (other_cert, other_key) = Ca::new().issue("intruder", ExtendedKeyUsagePurpose::ClientAuth);
+    assert!(client(&ca, Some((&other_cert, &other_key)))
+        .get(format!("{}/whoami", base_url))
+        .send()
+        .await
+        .is_err());
+
+    let (client_cert, client_key) = ca.issue("alice", ExtendedKeyUsagePurpose::ClientAuth);
+    let response = client(&ca, Some((&client_cert, &client_key)))
+        .get(format!("{}/whoami", base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await.unwrap(), "alice");
+}
+
+#[tokio::test]
+async fn test_mtls_optional_client_auth() {
+    let ca = Ca::new();
+    let dir = temp_dir("optional");
+    let (cert, key) = ca.issue("server", ExtendedKeyUsagePurpose::ServerAuth);
+    std::fs::write(dir.join("cert.pem"), &cert).unwrap();
+    std::fs::write(dir.join("key.pem"), &key).unwrap();
+    std::fs::write(dir.join("ca.pem"), ca.cert.pem()).unwrap();
+
+    let base_url = spawn(TlsConfig {
+        cert_path: dir.join("cert.pem").to_str().unwrap().to_string(),
+        key_path: dir.join("key.pem").to_str().unwrap().to_string(),
+        client_ca_path: Some(dir.join("ca.pem").to_str().unwrap().to_string()),
+        client_auth_optional: true,
+        reload_interval_secs: 0,
+    })
+    .await;
+
+    let response = client(&ca, None)
+        .get(format!("{}/whoami", base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.text().await.unwrap(), "anonymous");
+}